@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Path, Request, State};
+use axum::extract::rejection::{JsonRejection, PathRejection};
+use axum::http::{self, HeaderMap, StatusCode};
+use axum::Json;
+use axum::middleware::Next;
+use axum::response::Response;
+use rand::Rng;
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::db::DbError;
+use crate::error::{AppError, ErrorBody};
+use crate::store::{blocking, SqliteStore};
+
+//Header checked for a key when there's no `Authorization: Bearer <key>`.
+pub const API_KEY_HEADER: &str = "x-api-key";
+//Every key starts with this, so they're easy to spot (and to grep for in leaked configs).
+const KEY_PREFIX: &str = "mm_";
+
+//What a key is allowed to do. Each scope includes the ones before it, so an admin key can also
+//write and read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(scope: &str) -> Result<Scope, DbError> {
+        match scope {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            x => Err(format!("Unknown scope \"{}\"", x).into()),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+//An issued key. Only a SHA-256 hash of the key itself is kept; keys are 32 random bytes, so there's
+//nothing to gain from a slow password hash. `prefix` is the start of the key, so people can tell
+//their keys apart in the list.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub scope: Scope,
+    pub prefix: String,
+    pub created: String,
+    pub revoked: Option<String>,
+}
+
+//Body of POST /keys.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NewApiKey {
+    #[schema(example = "ios app")]
+    pub name: String,
+    pub scope: Scope,
+}
+
+//Where keys are kept. SQLite keeps them next to the menu items, MemoryKeys goes with the in-memory store.
+pub trait KeyStore: Send + Sync {
+    fn add(&self, new: &NewApiKey, prefix: &str, hash: &str, created: &str) -> Result<ApiKey, DbError>;
+    fn list(&self) -> Result<Vec<ApiKey>, DbError>;
+    //The key with this hash, unless it's been revoked.
+    fn find(&self, hash: &str) -> Result<Option<ApiKey>, DbError>;
+    //Returns whether there was a live key to revoke.
+    fn revoke(&self, id: i64, at: &str) -> Result<bool, DbError>;
+}
+
+pub type SharedKeys = Arc<dyn KeyStore>;
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+//Makes a new key and stores its hash. The key itself is returned once, here, and never again.
+pub fn issue(keys: &dyn KeyStore, new: &NewApiKey) -> Result<(ApiKey, String), DbError> {
+    let key = format!("{}{}", KEY_PREFIX, hex::encode(rand::thread_rng().gen::<[u8; 32]>()));
+    let prefix = &key[..KEY_PREFIX.len() + 8];
+    let issued = keys.add(new, prefix, &hash_key(&key), &chrono::Utc::now().to_rfc3339())?;
+    Ok((issued, key))
+}
+
+fn key_from_row(row: &Row) -> Result<ApiKey, DbError> {
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scope: Scope::parse(&row.get::<_, String>(2)?)?,
+        prefix: row.get(3)?,
+        created: row.get(4)?,
+        revoked: row.get(5)?,
+    })
+}
+
+impl KeyStore for SqliteStore {
+    fn add(&self, new: &NewApiKey, prefix: &str, hash: &str, created: &str) -> Result<ApiKey, DbError> {
+        let connection = self.db().get()?;
+        connection.execute(
+            "INSERT INTO api_keys (name, scope, prefix, hash, created) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![new.name, new.scope.as_str(), prefix, hash, created],
+        )?;
+        Ok(ApiKey {
+            id: connection.last_insert_rowid(),
+            name: new.name.clone(),
+            scope: new.scope,
+            prefix: prefix.to_string(),
+            created: created.to_string(),
+            revoked: None,
+        })
+    }
+
+    fn list(&self) -> Result<Vec<ApiKey>, DbError> {
+        let connection = self.db().get()?;
+        let mut statement = connection.prepare(
+            "SELECT id, name, scope, prefix, created, revoked FROM api_keys ORDER BY id",
+        )?;
+        let mut rows = statement.query([])?;
+        let mut keys = Vec::new();
+        while let Some(row) = rows.next()? {
+            keys.push(key_from_row(row)?);
+        }
+        Ok(keys)
+    }
+
+    fn find(&self, hash: &str) -> Result<Option<ApiKey>, DbError> {
+        let connection = self.db().get()?;
+        let mut statement = connection.prepare(
+            "SELECT id, name, scope, prefix, created, revoked FROM api_keys WHERE hash = ?1 AND revoked IS NULL",
+        )?;
+        let row = statement.query_row([hash], |row| Ok(key_from_row(row))).optional()?;
+        row.transpose()
+    }
+
+    fn revoke(&self, id: i64, at: &str) -> Result<bool, DbError> {
+        let changed = self.db().get()?.execute(
+            "UPDATE api_keys SET revoked = ?2 WHERE id = ?1 AND revoked IS NULL",
+            params![id, at],
+        )?;
+        Ok(changed > 0)
+    }
+}
+
+//Keys for the in-memory store. Gone on restart, like everything else in it.
+#[derive(Default)]
+pub struct MemoryKeys {
+    inner: Mutex<BTreeMap<i64, (ApiKey, String)>>,
+}
+
+impl MemoryKeys {
+    pub fn new() -> Self {
+        MemoryKeys::default()
+    }
+
+    //See MemoryStore::lock.
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<i64, (ApiKey, String)>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl KeyStore for MemoryKeys {
+    fn add(&self, new: &NewApiKey, prefix: &str, hash: &str, created: &str) -> Result<ApiKey, DbError> {
+        let mut inner = self.lock();
+        let key = ApiKey {
+            id: inner.keys().next_back().map_or(1, |x| x + 1),
+            name: new.name.clone(),
+            scope: new.scope,
+            prefix: prefix.to_string(),
+            created: created.to_string(),
+            revoked: None,
+        };
+        inner.insert(key.id, (key.clone(), hash.to_string()));
+        Ok(key)
+    }
+
+    fn list(&self) -> Result<Vec<ApiKey>, DbError> {
+        Ok(self.lock().values().map(|x| x.0.clone()).collect())
+    }
+
+    fn find(&self, hash: &str) -> Result<Option<ApiKey>, DbError> {
+        Ok(self.lock().values().find(|x| x.1 == hash && x.0.revoked.is_none()).map(|x| x.0.clone()))
+    }
+
+    fn revoke(&self, id: i64, at: &str) -> Result<bool, DbError> {
+        match self.lock().get_mut(&id) {
+            Some((key, _)) if key.revoked.is_none() => {
+                key.revoked = Some(at.to_string());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+//What the auth middleware needs: where the keys are, and whether reading needs one at all.
+#[derive(Clone)]
+pub struct Auth {
+    keys: SharedKeys,
+    anonymous_read: bool,
+}
+
+impl Auth {
+    pub fn new(keys: SharedKeys, anonymous_read: bool) -> Self {
+        Auth { keys, anonymous_read }
+    }
+
+    //The key the request carries, if it's a live one. A key that's given but unknown is an error
+    //rather than being treated as anonymous, so typos don't fail silently.
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Option<ApiKey>, AppError> {
+        let bearer = headers.get(http::header::AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+        let given = bearer.or_else(|| headers.get(API_KEY_HEADER).and_then(|x| x.to_str().ok()));
+        let hash = match given {
+            Some(x) => hash_key(x.trim()),
+            None => return Ok(None),
+        };
+        match blocking(&self.keys, move |x| x.find(&hash)).await? {
+            Some(x) => Ok(Some(x)),
+            None => Err(AppError::Unauthorized("Unknown or revoked API key.".to_string())),
+        }
+    }
+
+    pub async fn check(&self, headers: &HeaderMap, needed: Scope) -> Result<Option<ApiKey>, AppError> {
+        let key = self.authenticate(headers).await?;
+        self.authorize(key, needed)
+    }
+
+    fn authorize(&self, key: Option<ApiKey>, needed: Scope) -> Result<Option<ApiKey>, AppError> {
+        match key {
+            Some(key) if key.scope >= needed => Ok(Some(key)),
+            Some(key) => Err(AppError::Forbidden(format!(
+                "This route needs a {} key, and \"{}\" is {}.", needed, key.name, key.scope
+            ))),
+            None if needed == Scope::Read && self.anonymous_read => Ok(None),
+            None => Err(AppError::Unauthorized(format!(
+                "This route needs a {} key, as `Authorization: Bearer <key>` or `{}: <key>`.", needed, API_KEY_HEADER
+            ))),
+        }
+    }
+}
+
+//Middleware for a group of routes that all need the same scope, e.g.
+//  .route_layer(middleware::from_fn_with_state((auth, Scope::Admin), auth::require))
+//The key (if any) is left in the request extensions for handlers that want to know who's asking.
+//limits::enforce may already have put it there, in which case it isn't looked up again.
+pub async fn require(
+    State((auth, scope)): State<(Auth, Scope)>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let key = match request.extensions().get::<ApiKey>() {
+        Some(x) => auth.authorize(Some(x.clone()), scope)?,
+        None => auth.check(request.headers(), scope).await?,
+    };
+    if let Some(key) = key {
+        request.extensions_mut().insert(key);
+    }
+    Ok(next.run(request).await)
+}
+
+//A new key along with the key itself, only returned by POST /keys.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct IssuedKey {
+    #[serde(flatten)]
+    info: ApiKey,
+    key: String,
+}
+
+//Handler for POST /keys.
+#[utoipa::path(
+    post,
+    path = "/keys",
+    tag = "keys",
+    summary = "Issue an API key",
+    description = "The key itself is only ever returned here; only a hash of it is kept.",
+    request_body = NewApiKey,
+    responses(
+        (status = 201, description = "Issued", body = IssuedKey),
+        (status = 400, description = "Malformed body", body = ErrorBody),
+        (status = 422, description = "Unknown scope or empty name", body = ErrorBody),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn issue_key(
+    State(keys): State<SharedKeys>,
+    body: Result<Json<NewApiKey>, JsonRejection>,
+) -> Result<(StatusCode, Json<IssuedKey>), AppError> {
+    let Json(new) = body?;
+    if new.name.trim().is_empty() {
+        return Err(AppError::Unprocessable("Keys need a name".to_string()));
+    }
+    let (info, key) = blocking(&keys, move |x| issue(x, &new)).await?;
+    Ok((StatusCode::CREATED, Json(IssuedKey { info, key })))
+}
+
+//Handler for GET /keys. Revoked keys are listed too, with when they were revoked.
+#[utoipa::path(
+    get,
+    path = "/keys",
+    tag = "keys",
+    summary = "List API keys",
+    responses(
+        (status = 200, description = "Every key issued, revoked ones included", body = Vec<ApiKey>),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn list_keys(State(keys): State<SharedKeys>) -> Result<Json<Vec<ApiKey>>, AppError> {
+    Ok(Json(blocking(&keys, |x| x.list()).await?))
+}
+
+//Handler for DELETE /keys/:id.
+#[utoipa::path(
+    delete,
+    path = "/keys/{id}",
+    tag = "keys",
+    summary = "Revoke an API key",
+    params(("id" = i64, Path)),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 400, description = "Bad id", body = ErrorBody),
+        (status = 404, description = "No live key with that id", body = ErrorBody),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn revoke_key(
+    id: Result<Path<i64>, PathRejection>,
+    State(keys): State<SharedKeys>,
+) -> Result<StatusCode, AppError> {
+    let Path(id) = id?;
+    let at = chrono::Utc::now().to_rfc3339();
+    if blocking(&keys, move |x| x.revoke(id, &at)).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("No live key {}", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::{self, HeaderMap, HeaderValue, StatusCode};
+
+    use crate::auth::{hash_key, issue, Auth, KeyStore, MemoryKeys, NewApiKey, Scope, API_KEY_HEADER};
+    use crate::store::{SqliteStore, TempDb};
+
+    fn new_key(name: &str, scope: Scope) -> NewApiKey {
+        NewApiKey { name: name.to_string(), scope }
+    }
+
+    //Runs the same checks against any key store.
+    fn exercise(keys: &dyn KeyStore) {
+        let (reader, reader_key) = issue(keys, &new_key("dashboard", Scope::Read)).unwrap();
+        let (admin, admin_key) = issue(keys, &new_key("ops", Scope::Admin)).unwrap();
+        assert_ne!(reader.id, admin.id);
+        assert!(reader_key.starts_with(&reader.prefix));
+
+        assert_eq!(keys.find(&hash_key(&reader_key)).unwrap(), Some(reader.clone()));
+        assert_eq!(keys.find(&hash_key(&admin_key)).unwrap(), Some(admin.clone()));
+        assert_eq!(keys.find(&hash_key("mm_nope")).unwrap(), None);
+
+        assert!(keys.revoke(reader.id, "2024-06-04T00:00:00Z").unwrap());
+        assert!(!keys.revoke(reader.id, "2024-06-04T00:00:00Z").unwrap());
+        assert_eq!(keys.find(&hash_key(&reader_key)).unwrap(), None);
+        let listed = keys.list().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].revoked.as_deref(), Some("2024-06-04T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_sqlite_keys() {
+        let temp = TempDb::new("keys");
+        exercise(&SqliteStore::open(&temp.path).unwrap());
+    }
+
+    #[test]
+    fn test_memory_keys() {
+        exercise(&MemoryKeys::new());
+    }
+
+    #[tokio::test]
+    async fn test_scopes() {
+        let keys = Arc::new(MemoryKeys::new());
+        let (_, writer) = issue(keys.as_ref(), &new_key("importer", Scope::Write)).unwrap();
+        let bearer = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(http::header::AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", key)).unwrap());
+            headers
+        };
+        let status = |res: Result<_, crate::error::AppError>| res.map(|_| ()).map_err(|e| e.status());
+
+        let open = Auth::new(keys.clone(), true);
+        assert_eq!(status(open.check(&HeaderMap::new(), Scope::Read).await), Ok(()));
+        assert_eq!(status(open.check(&HeaderMap::new(), Scope::Write).await), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(open.check(&bearer(&writer), Scope::Write).await), Ok(()));
+        assert_eq!(status(open.check(&bearer(&writer), Scope::Admin).await), Err(StatusCode::FORBIDDEN));
+        assert_eq!(status(open.check(&bearer("mm_typo"), Scope::Read).await), Err(StatusCode::UNAUTHORIZED));
+
+        let closed = Auth::new(keys.clone(), false);
+        assert_eq!(status(closed.check(&HeaderMap::new(), Scope::Read).await), Err(StatusCode::UNAUTHORIZED));
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, HeaderValue::from_str(&writer).unwrap());
+        assert_eq!(status(closed.check(&headers, Scope::Read).await), Ok(()));
+    }
+}
@@ -0,0 +1,136 @@
+use axum::http::{self, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use crate::error::AppError;
+
+//HTTP caching for responses served from the index.
+//Every write bumps the store's change counter, and each index remembers the counter it was built
+//at, so that's our catalog version. Responses are tagged with it as an ETag, and a client sending
+//it back in If-None-Match gets an empty 304 instead of the same results again.
+//Counters start over in every database, so the database's id goes in front of the counter; otherwise a
+//restored or replaced database could hand out a tag a client already has for different data.
+//The crate version is part of the tag too, since a new build can change the JSON for the same data,
+//and so is the format (JSON or NDJSON), since the same URL can give either depending on Accept.
+//Tags are weak: the compression layer may encode the body any number of ways, which changes the bytes
+//but not what they say, and weak tags promise only the latter. If-None-Match compares them weakly
+//anyway, and we don't serve ranges, which would need strong ones.
+#[derive(Debug, Clone, Copy)]
+pub struct CachePolicy {
+    max_age_secs: u64,
+    private: bool,
+}
+
+impl CachePolicy {
+    pub fn new(max_age_secs: u64) -> Self {
+        CachePolicy { max_age_secs, private: false }
+    }
+
+    //For responses that need an API key: only the client's own cache may keep them, never a
+    //shared one that could hand them to someone without a key.
+    pub fn private(mut self) -> Self {
+        self.private = true;
+        self
+    }
+
+    pub fn etag(database: i64, version: i64, format: &str) -> HeaderValue {
+        HeaderValue::from_str(&format!("W/\"{:016x}-{}-{}-{}\"", database as u64, version, format, env!("CARGO_PKG_VERSION")))
+            .expect("ETag should only contain ascii.")
+    }
+
+    //With no max age, clients still keep the response but have to revalidate it every time.
+    fn cache_control(&self) -> HeaderValue {
+        let visibility = if self.private { "private" } else { "public" };
+        if self.max_age_secs == 0 {
+            HeaderValue::from_str(&format!("{}, no-cache", visibility))
+        } else {
+            HeaderValue::from_str(&format!("{}, max-age={}", visibility, self.max_age_secs))
+        }.expect("Cache-Control should only contain ascii.")
+    }
+
+    //Answers 304 if the request already has the given tag, otherwise builds the response and tags
+    //it. Checked before `build` runs, so a revalidation skips the actual work.
+    //Error responses aren't tagged; they shouldn't be cached.
+    pub fn respond(
+        &self,
+        request: &HeaderMap,
+        etag: HeaderValue,
+        build: impl FnOnce() -> Result<Response, AppError>,
+    ) -> Result<Response, AppError> {
+        let mut response = if not_modified(request, &etag) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            build()?
+        };
+        if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+            let headers = response.headers_mut();
+            headers.insert(http::header::ETAG, etag);
+            headers.insert(http::header::CACHE_CONTROL, self.cache_control());
+        }
+        Ok(response)
+    }
+}
+
+//Whether any If-None-Match header matches the tag. Uses the weak comparison the spec asks for here,
+//so W/ prefixes on either side are ignored.
+fn not_modified(request: &HeaderMap, etag: &HeaderValue) -> bool {
+    let etag = etag.to_str().unwrap_or_default().trim_start_matches("W/");
+    request.get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .any(|x| x == "*" || x.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{self, HeaderMap, HeaderValue, StatusCode};
+    use axum::response::IntoResponse;
+
+    use crate::cache::{not_modified, CachePolicy};
+    use crate::error::AppError;
+
+    fn request(if_none_match: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::IF_NONE_MATCH, HeaderValue::from_str(if_none_match).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let etag = CachePolicy::etag(1, 3, "json");
+        let current = etag.to_str().unwrap();
+        assert!(!not_modified(&HeaderMap::new(), &etag));
+        assert!(not_modified(&request(current), &etag));
+        assert!(current.starts_with("W/"));
+        assert!(not_modified(&request(current.trim_start_matches("W/")), &etag));
+        assert!(not_modified(&request(&format!("\"old\", {}", current)), &etag));
+        assert!(not_modified(&request("*"), &etag));
+        assert!(!not_modified(&request(CachePolicy::etag(1, 2, "json").to_str().unwrap()), &etag));
+        assert!(!not_modified(&request(CachePolicy::etag(2, 3, "json").to_str().unwrap()), &etag));
+        assert!(!not_modified(&request(CachePolicy::etag(1, 3, "ndjson").to_str().unwrap()), &etag));
+    }
+
+    #[test]
+    fn test_respond() {
+        let policy = CachePolicy::new(60);
+        let current = CachePolicy::etag(1, 3, "json");
+
+        let res = policy.respond(&HeaderMap::new(), current.clone(), || Ok("body".into_response())).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[http::header::ETAG], current);
+        assert_eq!(res.headers()[http::header::CACHE_CONTROL], "public, max-age=60");
+
+        let res = policy.respond(&request(current.to_str().unwrap()), current.clone(), || panic!("Shouldn't be built.")).unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[http::header::ETAG], current);
+
+        let res = CachePolicy::new(0).respond(&HeaderMap::new(), current.clone(), || Ok("body".into_response())).unwrap();
+        assert_eq!(res.headers()[http::header::CACHE_CONTROL], "public, no-cache");
+        let res = CachePolicy::new(60).private().respond(&HeaderMap::new(), current.clone(), || Ok("body".into_response())).unwrap();
+        assert_eq!(res.headers()[http::header::CACHE_CONTROL], "private, max-age=60");
+
+        let res = policy.respond(&HeaderMap::new(), current.clone(), || Err(AppError::BadRequest(String::new())));
+        assert!(res.is_err());
+    }
+}
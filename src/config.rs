@@ -0,0 +1,536 @@
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+//Config file read when --config isn't given, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "menu_manager.toml";
+
+//Server settings. Layered, each overriding the last:
+//  1. the defaults below
+//  2. a TOML file (--config / MENU_MANAGER_CONFIG, or ./menu_manager.toml if present)
+//  3. environment variables (MENU_MANAGER_*, plus RUST_LOG for the log level)
+//  4. command line flags
+//See menu_manager.example.toml for the file format.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub db_path: String,
+    pub bind: SocketAddr,
+    pub log_dir: String,
+    pub log_format: LogFormat,
+    //Anything EnvFilter understands, e.g. "info" or "menu_manager=debug,tower_http=info".
+    pub log_level: String,
+    //Every *.json menu in here is added to the store on startup (existing items only pick up newer prices).
+    pub seed_dir: String,
+    //How long to wait for in-flight requests to finish after SIGINT/SIGTERM.
+    pub shutdown_timeout_secs: u64,
+    //How long clients may reuse a search response before revalidating it. 0 means always revalidate.
+    pub cache_max_age_secs: u64,
+    //How many of the latest changes /sync keeps in its log. Clients with older tokens start over.
+    //0 keeps every change.
+    pub sync_log_retention: u64,
+    pub features: Features,
+    pub limits: Limits,
+    pub monitor: Monitor,
+    pub cors: Cors,
+    pub webhooks: Webhooks,
+    pub tls: Tls,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            db_path: "res/menu_db.sqlite".to_string(),
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            log_dir: "res/".to_string(),
+            log_format: LogFormat::Text,
+            log_level: "info".to_string(),
+            seed_dir: "res/".to_string(),
+            shutdown_timeout_secs: 30,
+            cache_max_age_secs: 60,
+            sync_log_retention: 100_000,
+            features: Features::default(),
+            limits: Limits::default(),
+            monitor: Monitor::default(),
+            cors: Cors::default(),
+            webhooks: Webhooks::default(),
+            tls: Tls::default(),
+        }
+    }
+}
+
+//On/off switches for optional behaviour.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    //Load the menus in seed_dir on startup.
+    pub seed: bool,
+    //Use a throwaway in-memory store instead of db_path.
+    pub in_memory: bool,
+    //Save/load the built index next to the database instead of rebuilding it every start.
+    pub index_snapshot: bool,
+    //Allow search and the other read routes without an API key. Writes and admin always need one.
+    pub anonymous_read: bool,
+    //Turn away clients that request too many undefined routes for a while. See [monitor].
+    pub block_scanners: bool,
+    //gzip, br or zstd responses for clients that accept them.
+    pub compression: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            seed: true,
+            in_memory: false,
+            index_snapshot: true,
+            anonymous_read: true,
+            block_scanners: false,
+            compression: true,
+        }
+    }
+}
+
+//What a client can ask of the server. 0 turns any of these off.
+//Rates are token buckets: a client can send `burst` requests at once, then gets more back at the
+//per-minute rate. Requests with a valid API key count against the key, others against their address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub ip_requests_per_minute: u32,
+    pub ip_burst: u32,
+    pub key_requests_per_minute: u32,
+    pub key_burst: u32,
+    pub max_body_bytes: usize,
+    //Time until the response starts; streams like /events aren't cut off.
+    pub request_timeout_secs: u64,
+    //Requests over this are turned away with a 503 rather than queued.
+    pub max_concurrent_requests: usize,
+    //How deeply a GraphQL query can nest fields (see graphql.rs). The introspection query tools
+    //like GraphiQL send for the schema needs 13.
+    pub graphql_max_depth: usize,
+    //Every field a GraphQL query asks for costs 1, times `limit` for the paged lists. Queries
+    //costing more than this are rejected before they run.
+    pub graphql_max_complexity: usize,
+    //Reverse proxies in front of the server, as addresses or CIDR blocks. Requests from them are put
+    //down to the client in X-Forwarded-For instead (see limits::identify). Empty trusts nobody.
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            ip_requests_per_minute: 600,
+            ip_burst: 100,
+            key_requests_per_minute: 6000,
+            key_burst: 500,
+            max_body_bytes: 1024 * 1024,
+            request_timeout_secs: 30,
+            max_concurrent_requests: 512,
+            graphql_max_depth: 15,
+            graphql_max_complexity: 5000,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+//Watching requests to undefined routes (see monitor.rs). Every miss is counted per client; with
+//features.block_scanners on, `threshold` misses inside `window_secs` gets the client blocked for
+//`block_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Monitor {
+    pub threshold: u32,
+    pub window_secs: u64,
+    pub block_secs: u64,
+    //Paths containing any of these (ignoring case) are flagged as scanning.
+    pub scan_patterns: Vec<String>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Monitor {
+            threshold: 30,
+            window_secs: 60,
+            block_secs: 900,
+            scan_patterns: [
+                "wp-admin", "wp-login", "wp-content", "xmlrpc", ".php", ".env", ".git", ".aws",
+                "phpmyadmin", "cgi-bin", "actuator", "server-status", "etc/passwd", "../", "config.json",
+            ].iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+//Cross-origin access for browser front ends (see cors.rs). With no origins, CORS is off and
+//browsers only allow pages served from this server to call it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+    //Full origins, e.g. "https://menus.example.com", or "*" for any.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    //Request headers pages may send. Needs the API key headers for anything but anonymous reads.
+    pub allowed_headers: Vec<String>,
+    //How long browsers may cache a preflight answer.
+    pub max_age_secs: u64,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"].iter().map(|x| x.to_string()).collect(),
+            allowed_headers: ["authorization", "content-type", "if-none-match", "x-api-key", "x-request-id"]
+                .iter().map(|x| x.to_string()).collect(),
+            max_age_secs: 600,
+        }
+    }
+}
+
+//Outgoing webhook deliveries (see webhooks.rs).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webhooks {
+    //Lets webhooks point at loopback, private and link-local addresses. Off, since anyone with an
+    //admin key could otherwise make the server post to things only it can reach.
+    pub allow_private_targets: bool,
+    //Deliveries in flight at once; further events wait for a free slot. 0 means no limit.
+    pub max_concurrent_deliveries: usize,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Webhooks {
+            allow_private_targets: false,
+            max_concurrent_deliveries: 32,
+        }
+    }
+}
+
+//Serve HTTPS on `bind` instead of plain HTTP (see tls.rs). Both paths or neither; PEM files, with the
+//certificate file holding the full chain. Sending the process a SIGHUP reloads them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+}
+
+impl Tls {
+    pub fn paths(&self) -> Option<(&str, &str)> {
+        Some((self.cert_path.as_deref()?, self.key_path.as_deref()?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+//Command line flags. Every setting is optional here so we can tell "not given" apart from a default.
+//The toggles accept a bare flag as "true", so `--memory` works as well as `--memory false`.
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "Serves search over a small database of menu items.")]
+pub struct Cli {
+    /// TOML config file [default: ./menu_manager.toml, if it exists]
+    #[arg(short, long, env = "MENU_MANAGER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration, print it, and exit
+    #[arg(long)]
+    pub check_config: bool,
+    /// Issue an admin API key with this name, print it, and exit
+    #[arg(long, value_name = "NAME")]
+    pub create_admin_key: Option<String>,
+
+    /// SQLite database file
+    #[arg(long, env = "MENU_MANAGER_DB_PATH")]
+    pub db_path: Option<String>,
+    /// Address to listen on
+    #[arg(long, env = "MENU_MANAGER_BIND")]
+    pub bind: Option<SocketAddr>,
+    /// PEM certificate chain, to serve HTTPS (needs --tls-key)
+    #[arg(long, env = "MENU_MANAGER_TLS_CERT")]
+    pub tls_cert: Option<String>,
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "MENU_MANAGER_TLS_KEY")]
+    pub tls_key: Option<String>,
+    /// Directory for the rolling log files
+    #[arg(long, env = "MENU_MANAGER_LOG_DIR")]
+    pub log_dir: Option<String>,
+    #[arg(long, env = "MENU_MANAGER_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Log filter, e.g. "info" or "menu_manager=debug"
+    #[arg(long, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+    /// Directory of JSON menus to load on startup
+    #[arg(long, env = "MENU_MANAGER_SEED_DIR")]
+    pub seed_dir: Option<String>,
+    /// Seconds to wait for in-flight requests on shutdown
+    #[arg(long, env = "MENU_MANAGER_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+    /// Seconds clients may cache search responses before revalidating (0 = always revalidate)
+    #[arg(long, env = "MENU_MANAGER_CACHE_MAX_AGE")]
+    pub cache_max_age: Option<u64>,
+    /// Requests per minute from each address without an API key (0 = unlimited)
+    #[arg(long, env = "MENU_MANAGER_IP_RATE_LIMIT")]
+    pub ip_rate_limit: Option<u32>,
+    /// Requests per minute for each API key (0 = unlimited)
+    #[arg(long, env = "MENU_MANAGER_KEY_RATE_LIMIT")]
+    pub key_rate_limit: Option<u32>,
+    /// Largest request body accepted, in bytes (0 = unlimited)
+    #[arg(long, env = "MENU_MANAGER_MAX_BODY_BYTES")]
+    pub max_body_bytes: Option<usize>,
+    /// Seconds a request may take before it's answered with a 504 (0 = no limit)
+    #[arg(long, env = "MENU_MANAGER_REQUEST_TIMEOUT")]
+    pub request_timeout: Option<u64>,
+    /// Requests handled at once before new ones are turned away (0 = no limit)
+    #[arg(long, env = "MENU_MANAGER_MAX_CONCURRENT")]
+    pub max_concurrent: Option<usize>,
+    /// Reverse proxy address or CIDR block whose X-Forwarded-For is believed (repeatable, or comma separated)
+    #[arg(long = "trusted-proxy", env = "MENU_MANAGER_TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Option<Vec<String>>,
+
+    /// Load the menus in the seed directory on startup
+    #[arg(long, env = "MENU_MANAGER_SEED", num_args = 0..=1, default_missing_value = "true")]
+    pub seed: Option<bool>,
+    /// Use a throwaway in-memory store instead of the database file
+    #[arg(long = "memory", env = "MENU_MANAGER_IN_MEMORY", num_args = 0..=1, default_missing_value = "true")]
+    pub in_memory: Option<bool>,
+    /// Save and reuse the built index next to the database
+    #[arg(long, env = "MENU_MANAGER_INDEX_SNAPSHOT", num_args = 0..=1, default_missing_value = "true")]
+    pub index_snapshot: Option<bool>,
+    /// Allow search and the other read routes without an API key
+    #[arg(long, env = "MENU_MANAGER_ANONYMOUS_READ", num_args = 0..=1, default_missing_value = "true")]
+    pub anonymous_read: Option<bool>,
+    /// Block clients that request too many undefined routes for a while
+    #[arg(long, env = "MENU_MANAGER_BLOCK_SCANNERS", num_args = 0..=1, default_missing_value = "true")]
+    pub block_scanners: Option<bool>,
+    /// Compress responses for clients that accept gzip, br or zstd
+    #[arg(long, env = "MENU_MANAGER_COMPRESSION", num_args = 0..=1, default_missing_value = "true")]
+    pub compression: Option<bool>,
+
+    /// Origin allowed to call the API from a browser, e.g. https://menus.example.com (repeatable, or comma separated)
+    #[arg(long = "cors-origin", env = "MENU_MANAGER_CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Option<Vec<String>>,
+}
+
+impl Config {
+    //Builds the config from the defaults, the config file, and the (already env-merged) flags.
+    pub fn load(cli: &Cli) -> Result<Config, Box<dyn Error>> {
+        let mut config = match &cli.config {
+            Some(x) => Config::from_file(x)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+        config.apply(cli);
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, Box<dyn Error>> {
+        let raw = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read config file {}: {}", path.display(), e))?;
+        let config = toml::from_str(&raw)
+            .map_err(|e| format!("Couldn't parse config file {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, cli: &Cli) {
+        if let Some(x) = &cli.db_path {
+            self.db_path = x.clone();
+        }
+        if let Some(x) = cli.bind {
+            self.bind = x;
+        }
+        if let Some(x) = &cli.tls_cert {
+            self.tls.cert_path = Some(x.clone());
+        }
+        if let Some(x) = &cli.tls_key {
+            self.tls.key_path = Some(x.clone());
+        }
+        if let Some(x) = &cli.log_dir {
+            self.log_dir = x.clone();
+        }
+        if let Some(x) = cli.log_format {
+            self.log_format = x;
+        }
+        if let Some(x) = &cli.log_level {
+            self.log_level = x.clone();
+        }
+        if let Some(x) = &cli.seed_dir {
+            self.seed_dir = x.clone();
+        }
+        if let Some(x) = cli.shutdown_timeout {
+            self.shutdown_timeout_secs = x;
+        }
+        if let Some(x) = cli.cache_max_age {
+            self.cache_max_age_secs = x;
+        }
+        if let Some(x) = cli.ip_rate_limit {
+            self.limits.ip_requests_per_minute = x;
+        }
+        if let Some(x) = cli.key_rate_limit {
+            self.limits.key_requests_per_minute = x;
+        }
+        if let Some(x) = cli.max_body_bytes {
+            self.limits.max_body_bytes = x;
+        }
+        if let Some(x) = cli.request_timeout {
+            self.limits.request_timeout_secs = x;
+        }
+        if let Some(x) = cli.max_concurrent {
+            self.limits.max_concurrent_requests = x;
+        }
+        if let Some(x) = cli.seed {
+            self.features.seed = x;
+        }
+        if let Some(x) = cli.in_memory {
+            self.features.in_memory = x;
+        }
+        if let Some(x) = cli.index_snapshot {
+            self.features.index_snapshot = x;
+        }
+        if let Some(x) = cli.anonymous_read {
+            self.features.anonymous_read = x;
+        }
+        if let Some(x) = cli.block_scanners {
+            self.features.block_scanners = x;
+        }
+        if let Some(x) = cli.compression {
+            self.features.compression = x;
+        }
+        if let Some(x) = &cli.cors_origins {
+            self.cors.allowed_origins = x.clone();
+        }
+        if let Some(x) = &cli.trusted_proxies {
+            self.limits.trusted_proxies = x.clone();
+        }
+    }
+
+    //Checks everything that can be checked without starting the server. Returns every problem
+    //found rather than stopping at the first one.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        if let Err(e) = EnvFilter::try_new(&self.log_level) {
+            problems.push(format!("log_level \"{}\" isn't a valid filter: {}", self.log_level, e));
+        }
+        if !Path::new(&self.log_dir).is_dir() {
+            problems.push(format!("log_dir \"{}\" isn't a directory", self.log_dir));
+        }
+        if !self.features.in_memory {
+            let parent = Path::new(&self.db_path).parent()
+                .filter(|x| !x.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            if !parent.is_dir() {
+                problems.push(format!("db_path \"{}\" is in a directory that doesn't exist", self.db_path));
+            }
+        }
+        if self.features.seed && !Path::new(&self.seed_dir).is_dir() {
+            problems.push(format!("seed_dir \"{}\" isn't a directory", self.seed_dir));
+        }
+        if self.limits.ip_requests_per_minute > 0 && self.limits.ip_burst == 0 {
+            problems.push("limits.ip_burst has to be at least 1 when ip_requests_per_minute is set".to_string());
+        }
+        if self.limits.key_requests_per_minute > 0 && self.limits.key_burst == 0 {
+            problems.push("limits.key_burst has to be at least 1 when key_requests_per_minute is set".to_string());
+        }
+        if self.features.block_scanners && (self.monitor.threshold == 0 || self.monitor.window_secs == 0) {
+            problems.push("monitor.threshold and monitor.window_secs have to be set to block scanners".to_string());
+        }
+        for x in &self.limits.trusted_proxies {
+            if let Err(e) = crate::limits::Proxy::parse(x) {
+                problems.push(e);
+            }
+        }
+        if let Err(e) = crate::cors::layer(&self.cors) {
+            problems.push(e);
+        }
+        match (&self.tls.cert_path, &self.tls.key_path) {
+            (Some(_), None) | (None, Some(_)) => {
+                problems.push("tls.cert_path and tls.key_path have to be set together".to_string());
+            }
+            _ => {}
+        }
+        for path in [&self.tls.cert_path, &self.tls.key_path].into_iter().flatten() {
+            if !Path::new(path).is_file() {
+                problems.push(format!("TLS file \"{}\" doesn't exist", path));
+            }
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+
+    //The resolved config in the same format as the file.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Config should always serialize to TOML.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use clap::Parser;
+
+    use crate::config::{Cli, Config, LogFormat};
+
+    #[test]
+    fn test_layering() {
+        let file: Config = toml::from_str(r#"
+            bind = "127.0.0.1:8080"
+            log_format = "json"
+            log_level = "debug"
+
+            [features]
+            seed = false
+        "#).unwrap();
+        assert_eq!(file.bind, "127.0.0.1:8080".parse::<SocketAddr>().unwrap());
+        assert_eq!(file.log_format, LogFormat::Json);
+        //Anything the file leaves out keeps its default.
+        assert_eq!(file.db_path, Config::default().db_path);
+        assert!(file.features.index_snapshot);
+
+        let cli = Cli::try_parse_from([
+            "menu-manager", "--bind", "127.0.0.1:9000", "--seed", "true", "--memory",
+        ]).unwrap();
+        let mut config = file.clone();
+        config.apply(&cli);
+        assert_eq!(config.bind, "127.0.0.1:9000".parse::<SocketAddr>().unwrap());
+        assert!(config.features.seed);
+        assert!(config.features.in_memory);
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        let cli = Cli::try_parse_from(["menu-manager", "--memory", "false", "--cors-origin", "https://a.example,https://b.example"]).unwrap();
+        config.apply(&cli);
+        assert!(!config.features.in_memory);
+        assert_eq!(config.cors.allowed_origins, ["https://a.example", "https://b.example"]);
+    }
+
+    #[test]
+    fn test_round_trip_and_unknown_keys() {
+        let config = Config::default();
+        assert_eq!(toml::from_str::<Config>(&config.to_toml()).unwrap(), config);
+        assert!(toml::from_str::<Config>("dbpath = \"typo.sqlite\"").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config {
+            log_level: "this=is=not=a=filter".to_string(),
+            log_dir: "does/not/exist".to_string(),
+            seed_dir: "does/not/exist".to_string(),
+            db_path: "does/not/exist/menu.sqlite".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(config.validate().unwrap_err().len(), 4);
+    }
+}
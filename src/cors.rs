@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::Cors;
+use crate::request_id::REQUEST_ID_HEADER;
+use crate::versions::DEPRECATION_HEADER;
+
+//Response headers browsers are allowed to read on top of the always-safe ones (Content-Type, ...).
+//ETag for revalidating searches, Retry-After for backing off, the request id for bug reports, and
+//Deprecation and Link for noticing a client still uses the unversioned paths.
+const EXPOSED_HEADERS: [&str; 5] = ["etag", "retry-after", REQUEST_ID_HEADER, DEPRECATION_HEADER, "link"];
+
+//Builds the CORS layer for browser front ends on other origins. None with no origins configured,
+//in which case browsers keep their same-origin default.
+//Preflights (OPTIONS with Access-Control-Request-Method) are answered by the layer itself, before
+//auth or the rate limits see them, since browsers never send credentials on a preflight.
+//Credentials (cookies) aren't allowed; API keys go in a header, which has to be in `allowed_headers`.
+pub fn layer(config: &Cors) -> Result<Option<CorsLayer>, String> {
+    if config.allowed_origins.is_empty() {
+        return Ok(None);
+    }
+    let origins = if config.allowed_origins.iter().any(|x| x == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = config.allowed_origins.iter()
+            .map(|x| {
+                HeaderValue::from_str(x.trim_end_matches('/'))
+                    .map_err(|_| format!("cors.allowed_origins \"{}\" isn't a valid origin", x))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    let methods = config.allowed_methods.iter()
+        .map(|x| {
+            Method::from_bytes(x.to_uppercase().as_bytes())
+                .map_err(|_| format!("cors.allowed_methods \"{}\" isn't a valid method", x))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let headers = config.allowed_headers.iter()
+        .map(|x| {
+            HeaderName::from_bytes(x.to_lowercase().as_bytes())
+                .map_err(|_| format!("cors.allowed_headers \"{}\" isn't a valid header name", x))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .expose_headers(EXPOSED_HEADERS.map(HeaderName::from_static))
+            .max_age(Duration::from_secs(config.max_age_secs)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Cors;
+    use crate::cors::layer;
+
+    #[test]
+    fn test_layer() {
+        assert!(layer(&Cors::default()).unwrap().is_none());
+
+        let config = Cors { allowed_origins: vec!["https://menus.example.com/".to_string()], ..Cors::default() };
+        assert!(layer(&config).unwrap().is_some());
+        let config = Cors { allowed_origins: vec!["*".to_string()], ..Cors::default() };
+        assert!(layer(&config).unwrap().is_some());
+
+        let config = Cors {
+            allowed_origins: vec!["https://menus.example.com".to_string()],
+            allowed_methods: vec!["NOT A METHOD".to_string()],
+            ..Cors::default()
+        };
+        assert!(layer(&config).unwrap_err().contains("allowed_methods"));
+        let config = Cors { allowed_origins: vec!["https://bad\norigin".to_string()], ..Cors::default() };
+        assert!(layer(&config).unwrap_err().contains("allowed_origins"));
+    }
+}
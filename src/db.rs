@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::time::Duration;
+
+use r2d2_sqlite::SqliteConnectionManager;
+
+//Error type for anything that goes through the pool or a store. Needs to be Send so it can come
+//back out of spawn_blocking.
+pub type DbError = Box<dyn Error + Send + Sync>;
+
+//How long a connection waits on a lock held by another connection before giving up with SQLITE_BUSY.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const POOL_SIZE: u32 = 8;
+
+//Pool of SQLite connections to a single database file.
+//Every connection is switched to WAL mode, so readers don't block the (single) writer and vice versa,
+//and gets a busy timeout so concurrent writers wait their turn instead of failing immediately.
+//Cheap to clone; every clone shares the same pool.
+#[derive(Clone)]
+pub struct Db {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+    path: String,
+}
+
+impl Db {
+    pub fn open(path: &str) -> Result<Db, DbError> {
+        let manager = SqliteConnectionManager::file(path)
+            .with_init(|connection| {
+                connection.busy_timeout(BUSY_TIMEOUT)?;
+                //journal_mode returns the resulting mode as a row, so it can't go through execute.
+                connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+                connection.execute_batch("PRAGMA synchronous = NORMAL;")
+            });
+        let pool = r2d2::Pool::builder()
+            .max_size(POOL_SIZE)
+            .build(manager)?;
+
+        Ok(Db { pool, path: path.to_string() })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    //Checks a connection out of the pool. Blocks if they're all in use, so from async code only
+    //call this on a blocking thread (see store::with_store).
+    pub fn get(&self) -> Result<r2d2::PooledConnection<SqliteConnectionManager>, DbError> {
+        Ok(self.pool.get()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::store::TempDb;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pool_settings_and_concurrent_writes() {
+        let temp = TempDb::new("pool");
+        let db = Db::open(&temp.path).unwrap();
+
+        let connection = db.get().unwrap();
+        let mode: String = connection.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+        assert_eq!(mode, "wal");
+        connection.execute("CREATE TABLE counter (n INTEGER)", ()).unwrap();
+        drop(connection);
+
+        let mut tasks = Vec::new();
+        for n in 0..32 {
+            let db = db.clone();
+            tasks.push(tokio::task::spawn_blocking(move || {
+                db.get().unwrap().execute("INSERT INTO counter (n) VALUES (?1)", [n]).unwrap();
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let count: i64 = db.get().unwrap().query_row("SELECT COUNT(*) FROM counter", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 32);
+    }
+}
@@ -0,0 +1,251 @@
+use std::any::Any;
+
+use axum::{body::Body, http, Json};
+use axum::extract::Request;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::db::DbError;
+use crate::request_id;
+
+//The one error type for route handlers.
+//Handlers return Result<_, AppError> and use `?`; the response is always a JSON body like
+//  {"code": "not_found", "message": "No route /foo", "request_id": "..."}
+//The request id is filled in by render_errors, since the error itself doesn't know which request
+//it belongs to.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    //No API key, or one we don't know. Sent with a WWW-Authenticate header.
+    Unauthorized(String),
+    //A key, but not one allowed to do this.
+    Forbidden(String),
+    NotFound(String),
+    //Not produced by any route yet; here for the write endpoints.
+    #[allow(dead_code)]
+    Conflict(String),
+    Unprocessable(String),
+    PayloadTooLarge(String),
+    //Sent with a Retry-After header.
+    TooManyRequests { message: String, retry_after_secs: u64 },
+    //Too busy to take the request on right now. Sent with a Retry-After header.
+    Unavailable { message: String, retry_after_secs: u64 },
+    //Took too long, and was given up on.
+    Timeout(String),
+    //The message is logged, but never sent to the client.
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            AppError::BadRequest(_) => http::StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => http::StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => http::StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => http::StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => http::StatusCode::CONFLICT,
+            AppError::Unprocessable(_) => http::StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::PayloadTooLarge(_) => http::StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::TooManyRequests { .. } => http::StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable { .. } => http::StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Timeout(_) => http::StatusCode::GATEWAY_TIMEOUT,
+            AppError::Internal(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Unprocessable(_) => "unprocessable",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::Unavailable { .. } => "unavailable",
+            AppError::Timeout(_) => "timeout",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::BadRequest(x)
+            | AppError::Unauthorized(x)
+            | AppError::Forbidden(x)
+            | AppError::NotFound(x)
+            | AppError::Conflict(x)
+            | AppError::Unprocessable(x)
+            | AppError::PayloadTooLarge(x)
+            | AppError::TooManyRequests { message: x, .. }
+            | AppError::Unavailable { message: x, .. }
+            | AppError::Timeout(x) => x,
+            AppError::Internal(_) => "Something went wrong on our end.",
+        }
+    }
+}
+
+//Stashed in the response extensions so render_errors can write the body once it knows the request id.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ErrorBody {
+    #[schema(example = "not_found")]
+    pub code: &'static str,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if let AppError::Internal(x) = &self {
+            tracing::error!("Internal error: {}", x);
+        }
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.message().to_string(),
+            request_id: None,
+        };
+        let mut response = (self.status(), Json(body.clone())).into_response();
+        match &self {
+            AppError::Unauthorized(_) => {
+                response.headers_mut().insert(http::header::WWW_AUTHENTICATE, http::HeaderValue::from_static("Bearer"));
+            }
+            AppError::TooManyRequests { retry_after_secs, .. } | AppError::Unavailable { retry_after_secs, .. } => {
+                response.headers_mut().insert(http::header::RETRY_AFTER, http::HeaderValue::from(*retry_after_secs));
+            }
+            _ => {}
+        }
+        response.extensions_mut().insert(body);
+        response
+    }
+}
+
+impl From<DbError> for AppError {
+    fn from(e: DbError) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(e: PathRejection) -> Self {
+        AppError::BadRequest(e.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(e: QueryRejection) -> Self {
+        AppError::BadRequest(e.body_text())
+    }
+}
+
+//Well formed JSON that doesn't fit the expected shape is a 422, a body over the size limit a 413,
+//and anything else (bad syntax, wrong content type) is a 400.
+impl From<JsonRejection> for AppError {
+    fn from(e: JsonRejection) -> Self {
+        match e {
+            JsonRejection::JsonDataError(_) => AppError::Unprocessable(e.body_text()),
+            _ if e.status() == http::StatusCode::PAYLOAD_TOO_LARGE => AppError::PayloadTooLarge(e.body_text()),
+            _ => AppError::BadRequest(e.body_text()),
+        }
+    }
+}
+
+//Middleware that adds the request id to every error body. Needs to sit inside request_id::set.
+pub async fn render_errors(request: Request, next: Next) -> Response {
+    let request_id = request_id::of(request.headers()).map(|x| x.to_string());
+
+    let mut response = next.run(request).await;
+    if let Some(mut body) = response.extensions_mut().remove::<ErrorBody>() {
+        body.request_id = request_id;
+        let (mut parts, _) = response.into_parts();
+        parts.headers.remove(http::header::CONTENT_LENGTH);
+        response = Response::from_parts(parts, Body::from(serde_json::to_vec(&body).unwrap_or_default()));
+    }
+    response
+}
+
+//Turns a panic in a handler into a normal 500, for CatchPanicLayer.
+pub fn panic_response(err: Box<dyn Any + Send + 'static>) -> Response {
+    let detail = err.downcast_ref::<String>().map(|x| x.as_str())
+        .or_else(|| err.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    AppError::Internal(format!("Handler panicked: {}", detail)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http, middleware, Router, routing::get};
+    use axum::http::Request;
+    use tower::ServiceExt;
+    use tower_http::catch_panic::CatchPanicLayer;
+
+    use crate::error::{panic_response, render_errors, AppError};
+    use crate::request_id::REQUEST_ID_HEADER;
+
+    async fn body_json(response: axum::response::Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn explode() -> &'static str {
+        panic!("oops")
+    }
+
+    fn app() -> Router {
+        Router::new()
+            .route("/missing", get(|| async { Err::<(), _>(AppError::NotFound("Nothing here.".to_string())) }))
+            .route("/broken", get(|| async { Err::<(), _>(AppError::Internal("disk on fire".to_string())) }))
+            .route("/panic", get(explode))
+            .layer(CatchPanicLayer::custom(panic_response))
+            .layer(middleware::from_fn(render_errors))
+    }
+
+    #[tokio::test]
+    async fn test_error_body() {
+        let response = app()
+            .oneshot(Request::get("/missing").header(REQUEST_ID_HEADER, "abc-123").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "application/json");
+        assert_eq!(body_json(response).await, serde_json::json!({
+            "code": "not_found",
+            "message": "Nothing here.",
+            "request_id": "abc-123",
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_internal_details_stay_private() {
+        for path in ["/broken", "/panic"] {
+            let response = app().oneshot(Request::get(path).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(response.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+            let body = body_json(response).await;
+            assert_eq!(body["code"], "internal");
+            assert!(!body["message"].as_str().unwrap().contains("fire"));
+            assert!(body["request_id"].is_null());
+        }
+    }
+
+    #[test]
+    fn test_statuses() {
+        let cases = [
+            (AppError::BadRequest(String::new()), 400),
+            (AppError::Unauthorized(String::new()), 401),
+            (AppError::Forbidden(String::new()), 403),
+            (AppError::NotFound(String::new()), 404),
+            (AppError::Conflict(String::new()), 409),
+            (AppError::Unprocessable(String::new()), 422),
+            (AppError::PayloadTooLarge(String::new()), 413),
+            (AppError::TooManyRequests { message: String::new(), retry_after_secs: 1 }, 429),
+            (AppError::Unavailable { message: String::new(), retry_after_secs: 1 }, 503),
+            (AppError::Timeout(String::new()), 504),
+            (AppError::Internal(String::new()), 500),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status().as_u16(), status);
+        }
+    }
+}
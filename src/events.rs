@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use utoipa::ToSchema;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch};
+
+use crate::db::DbError;
+use crate::menu::{Item, MenuImport};
+use crate::store::{MenuStore, PricePoint, SharedStore};
+use crate::sync::{Changes, SyncToken};
+
+//How many events a slow subscriber can fall behind before it starts missing them.
+const EVENT_BUFFER: usize = 256;
+
+//Something that happened to the catalog, as sent on /events.
+//Ids are strings for the same reason as in /sync: they don't fit in a JavaScript number.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CatalogEvent {
+    ItemAdded { id: String, item: Item },
+    ItemUpdated { id: String, item: Item },
+    //The restaurant is only known if the item could be read before it was removed.
+    ItemRemoved { id: String, restaurant: Option<String> },
+    //A JSON menu file was imported. Only sent when it added something.
+    MenuImported { file: String, added: usize, restaurants: Vec<String> },
+    //Once per restaurant an import added items to, for anything that only cares about whole menus.
+    RestaurantChanged { restaurant: String },
+}
+
+impl CatalogEvent {
+    //Every value name() can return.
+    pub const NAMES: [&'static str; 5] = [
+        "item_added",
+        "item_updated",
+        "item_removed",
+        "menu_imported",
+        "restaurant_changed",
+    ];
+
+    //Matches the "type" field, and is used as the SSE event name so browsers can listen for one kind.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CatalogEvent::ItemAdded { .. } => "item_added",
+            CatalogEvent::ItemUpdated { .. } => "item_updated",
+            CatalogEvent::ItemRemoved { .. } => "item_removed",
+            CatalogEvent::MenuImported { .. } => "menu_imported",
+            CatalogEvent::RestaurantChanged { .. } => "restaurant_changed",
+        }
+    }
+
+    //The restaurants the event is about, as far as we know.
+    pub fn restaurants(&self) -> Vec<&str> {
+        match self {
+            CatalogEvent::ItemAdded { item, .. } | CatalogEvent::ItemUpdated { item, .. } => vec![item.restaurant()],
+            CatalogEvent::ItemRemoved { restaurant, .. } => restaurant.iter().map(|x| x.as_str()).collect(),
+            CatalogEvent::MenuImported { restaurants, .. } => restaurants.iter().map(|x| x.as_str()).collect(),
+            CatalogEvent::RestaurantChanged { restaurant } => vec![restaurant.as_str()],
+        }
+    }
+}
+
+//In-process broadcast channel for catalog events. Write paths publish, /events (and anything else
+//interested) subscribes. Publishing never waits: with nobody listening the event is just dropped, and
+//a subscriber that falls too far behind is told how many it missed.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<CatalogEvent>,
+    closed: Arc<watch::Sender<bool>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        let (closed, _) = watch::channel(false);
+        EventBus { sender, closed: Arc::new(closed) }
+    }
+
+    pub fn publish(&self, event: CatalogEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn publish_import(&self, import: &MenuImport) {
+        if import.added == 0 {
+            return;
+        }
+        self.publish(CatalogEvent::MenuImported {
+            file: import.file.clone(),
+            added: import.added,
+            restaurants: import.restaurants.iter().cloned().collect(),
+        });
+        for restaurant in &import.restaurants {
+            self.publish(CatalogEvent::RestaurantChanged { restaurant: restaurant.clone() });
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CatalogEvent> {
+        self.sender.subscribe()
+    }
+
+    //Ends every open stream, so shutdown doesn't have to wait out the drain deadline on them.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    pub fn closed(&self) -> impl Future<Output = ()> {
+        let mut closed = self.closed.subscribe();
+        async move {
+            let _ = closed.wait_for(|x| *x).await;
+        }
+    }
+}
+
+//Handler for GET /events, an SSE stream of CatalogEvents.
+//A client that falls behind gets a "lagged" event with how many it missed, and should catch up
+//through /sync.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "sync",
+    summary = "Stream of catalog changes",
+    description = "Server-sent events, named after the `type` of the CatalogEvent in their data. \
+        A `lagged` event means some were missed; catch up through /sync.",
+    responses(
+        (status = 200, description = "An SSE stream that stays open", body = CatalogEvent, content_type = "text/event-stream"),
+    ),
+    security((), ("bearer" = []), ("api_key" = [])),
+)]
+pub async fn events(State(events): State<EventBus>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = futures_util::stream::unfold(events.subscribe(), |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(x) => Event::default().event(x.name()).json_data(&x),
+            Err(RecvError::Lagged(missed)) => Event::default()
+                .event("lagged")
+                .json_data(serde_json::json!({ "type": "lagged", "missed": missed })),
+            Err(RecvError::Closed) => return None,
+        };
+        //Serializing our own types can't really fail, but if it does, skip the event rather than
+        //ending the stream.
+        let event = event.unwrap_or_else(|e| Event::default().comment(format!("dropped event: {}", e)));
+        Some((Ok(event), receiver))
+    });
+    Sse::new(stream.take_until(events.closed())).keep_alive(KeepAlive::default())
+}
+
+//Wraps a store to publish an event for every change made through it.
+//Upserts and deletes read the item first to tell added from updated and to name the restaurant;
+//another writer could slip in between, in which case the event is slightly off but the data isn't.
+pub struct EventStore {
+    inner: SharedStore,
+    events: EventBus,
+}
+
+impl EventStore {
+    pub fn new(inner: SharedStore, events: EventBus) -> Self {
+        EventStore { inner, events }
+    }
+}
+
+impl MenuStore for EventStore {
+    fn insert(&self, item: &Item) -> Result<bool, DbError> {
+        let added = self.inner.insert(item)?;
+        if added {
+            self.events.publish(CatalogEvent::ItemAdded { id: item.id().to_string(), item: item.clone() });
+        }
+        Ok(added)
+    }
+
+    fn upsert(&self, item: &Item) -> Result<(), DbError> {
+        let existed = self.inner.get(item.id())?.is_some();
+        self.inner.upsert(item)?;
+        let (id, item) = (item.id().to_string(), item.clone());
+        self.events.publish(if existed {
+            CatalogEvent::ItemUpdated { id, item }
+        } else {
+            CatalogEvent::ItemAdded { id, item }
+        });
+        Ok(())
+    }
+
+    fn delete(&self, id: i64) -> Result<bool, DbError> {
+        let restaurant = self.inner.get(id)?.map(|x| x.restaurant().to_string());
+        let removed = self.inner.delete(id)?;
+        if removed {
+            self.events.publish(CatalogEvent::ItemRemoved { id: id.to_string(), restaurant });
+        }
+        Ok(removed)
+    }
+
+    fn list(&self) -> Result<Vec<Item>, DbError> {
+        self.inner.list()
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Item>, DbError> {
+        self.inner.get(id)
+    }
+
+    fn change_counter(&self) -> Result<i64, DbError> {
+        self.inner.change_counter()
+    }
+
+    fn database_id(&self) -> Result<i64, DbError> {
+        self.inner.database_id()
+    }
+
+    fn changes_since(&self, since: Option<SyncToken>) -> Result<Changes, DbError> {
+        self.inner.changes_since(since)
+    }
+
+    fn trim_changes(&self, keep: i64) -> Result<usize, DbError> {
+        self.inner.trim_changes(keep)
+    }
+
+    fn price_histories(&self, ids: &[i64]) -> Result<BTreeMap<i64, Vec<PricePoint>>, DbError> {
+        self.inner.price_histories(ids)
+    }
+
+    fn snapshot_path(&self) -> Option<PathBuf> {
+        self.inner.snapshot_path()
+    }
+
+    fn checkpoint(&self) -> Result<(), DbError> {
+        self.inner.checkpoint()
+    }
+
+    fn check(&self) -> Result<(), DbError> {
+        self.inner.check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::{body::Body, Router, routing::get};
+    use axum::http::Request;
+    use futures_util::StreamExt;
+    use tower::ServiceExt;
+
+    use crate::events::{events, CatalogEvent, EventBus, EventStore};
+    use crate::menu::{add_json_to_db, Item, MenuImport};
+    use crate::store::{MemoryStore, MenuStore};
+
+    #[test]
+    fn test_event_store() {
+        let bus = EventBus::new();
+        let mut receiver = bus.subscribe();
+        let store = EventStore::new(Arc::new(MemoryStore::new()), bus.clone());
+
+        let import = add_json_to_db(&store, "res/lark_06-03.json").unwrap();
+        let item = store.list().unwrap().remove(0);
+        store.upsert(&item).unwrap();
+        store.delete(item.id()).unwrap();
+        bus.publish_import(&import);
+        //Repricing goes out as item_updated from the store; on its own it isn't an import.
+        bus.publish_import(&MenuImport { repriced: 1, ..MenuImport::default() });
+
+        let mut names = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            names.push(event.name());
+        }
+        assert_eq!(names.iter().filter(|x| **x == "item_added").count(), import.added);
+        assert_eq!(&names[import.added..], [
+            "item_updated",
+            "item_removed",
+            "menu_imported",
+            "restaurant_changed",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let bus = EventBus::new();
+        let app = Router::new().route("/events", get(events)).with_state(bus.clone());
+        let response = app.oneshot(Request::get("/events").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body().into_data_stream();
+
+        bus.publish(CatalogEvent::ItemAdded { id: "1".to_string(), item: Item::default() });
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap().unwrap().unwrap();
+        let text = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(text.starts_with("event: item_added\ndata: {\"type\":\"item_added\",\"id\":\"1\""));
+
+        bus.close();
+        let end = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap();
+        assert!(end.is_none());
+    }
+}
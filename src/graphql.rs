@@ -0,0 +1,751 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Error, ID, Lookahead, Object, Schema, SimpleObject};
+use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::Json;
+use axum::response::Html;
+
+use crate::config::Limits;
+use crate::error::{AppError, ErrorBody};
+use crate::index::{Index, SharedIndex};
+use crate::menu::Item;
+use crate::search::{self, parse_price, Highlights, SearchRequest};
+use crate::store::{with_store, PricePoint, SharedStore};
+
+//GraphQL over the catalog, for clients that want a different slice of it than the REST routes give:
+//restaurants with their menus, items with their price history, ingredients with what uses them.
+//Read only. Everything except price history comes from one snapshot of the index, taken when the
+//query starts, so a rebuild partway through can't mix two versions of the catalog.
+//Restaurants and ingredients are looked up in a Catalog built once per snapshot, and price history
+//is fetched a page of items at a time, so no field costs a pass over the catalog or a store call
+//per item.
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+pub type CatalogSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+//Built once at startup. Queries nested deeper or costing more than the limits are turned away
+//before anything runs; 0 turns either off.
+pub fn schema(store: SharedStore, limits: &Limits) -> CatalogSchema {
+    let mut builder = Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(store)
+        .data(Catalogs::default());
+    if limits.graphql_max_depth > 0 {
+        builder = builder.limit_depth(limits.graphql_max_depth);
+    }
+    if limits.graphql_max_complexity > 0 {
+        builder = builder.limit_complexity(limits.graphql_max_complexity);
+    }
+    builder.finish()
+}
+
+//Lookups over one index snapshot, for the fields that would otherwise go through every item.
+struct Catalog {
+    index: Arc<Index>,
+    //Item ids by restaurant, keyed by the name in lowercase, along with the name as written.
+    restaurants: BTreeMap<String, (String, Vec<u32>)>,
+    //Item ids by ingredient, keyed by ingredient_key.
+    ingredients: BTreeMap<String, Vec<u32>>,
+    //Index ids by store id.
+    ids: HashMap<i64, u32>,
+}
+
+impl Catalog {
+    fn build(index: Arc<Index>) -> Self {
+        let mut restaurants: BTreeMap<String, (String, Vec<u32>)> = BTreeMap::new();
+        let mut ingredients: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        let mut ids = HashMap::with_capacity(index.item_count());
+        for id in 0..index.item_count() as u32 {
+            let item = index.item(id);
+            restaurants.entry(item.restaurant().to_lowercase())
+                .or_insert_with(|| (item.restaurant().to_string(), Vec::new()))
+                .1.push(id);
+            for ingredient in item.ingredients() {
+                let key = ingredient_key(ingredient);
+                if key.is_empty() {
+                    continue;
+                }
+                //Ids only increase, so the tail is enough to skip an ingredient listed twice.
+                let list = ingredients.entry(key).or_default();
+                if list.last() != Some(&id) {
+                    list.push(id);
+                }
+            }
+            ids.insert(item.id(), id);
+        }
+        Catalog { index, restaurants, ingredients, ids }
+    }
+
+    fn restaurant(&self, name: &str) -> Option<&(String, Vec<u32>)> {
+        self.restaurants.get(&name.trim().to_lowercase())
+    }
+
+    fn items<'a>(&'a self, ids: &'a [u32]) -> impl Iterator<Item = &'a Item> {
+        ids.iter().map(|&x| self.index.item(x))
+    }
+}
+
+//The Catalog for the latest snapshot a query ran against. The first query on a new snapshot builds
+//it (holding the lock, so it's only built once), the rest share it.
+#[derive(Default)]
+struct Catalogs(Mutex<Option<Arc<Catalog>>>);
+
+impl Catalogs {
+    fn get(&self, index: &Arc<Index>) -> Arc<Catalog> {
+        let mut cached = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match &*cached {
+            Some(x) if Arc::ptr_eq(&x.index, index) => Arc::clone(x),
+            _ => {
+                let catalog = Arc::new(Catalog::build(Arc::clone(index)));
+                *cached = Some(Arc::clone(&catalog));
+                catalog
+            }
+        }
+    }
+}
+
+//The Catalog for the snapshot the handler added to the request.
+fn catalog(ctx: &Context<'_>) -> Arc<Catalog> {
+    ctx.data_unchecked::<Catalogs>().get(ctx.data_unchecked::<Arc<Index>>())
+}
+
+//Paged lists take an offset and a limit like POST /search does. Every list is paged, and costs
+//`limit` times what's asked of each entry, so the complexity limit catches queries that go round in
+//circles (item -> restaurant -> menu -> items -> ...) as well as plain big ones.
+fn page<T>(all: impl IntoIterator<Item = T>, offset: usize, limit: usize) -> Result<Vec<T>, Error> {
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(Error::new(format!("limit has to be between 1 and {}", MAX_LIMIT)));
+    }
+    Ok(all.into_iter().skip(offset).take(limit).collect())
+}
+
+//Ingredients are matched ignoring case and surrounding whitespace; this is the form they're named by.
+fn ingredient_key(ingredient: &str) -> String {
+    ingredient.trim().to_lowercase()
+}
+
+async fn price_histories(ctx: &Context<'_>, ids: Vec<i64>) -> Result<BTreeMap<i64, Vec<PricePoint>>, Error> {
+    with_store(ctx.data_unchecked::<SharedStore>(), move |x| x.price_histories(&ids))
+        .await
+        .map_err(|e| {
+            tracing::error!("Couldn't read price history: {}", e);
+            Error::new("Couldn't read the price history")
+        })
+}
+
+//Turns a page of items into Items for the response. `fields` is what's asked of each of them; if
+//that includes their price history, it's read here for the whole page in one store call.
+async fn menu_items(ctx: &Context<'_>, fields: Lookahead<'_>, items: Vec<Item>) -> Result<Vec<MenuItem>, Error> {
+    if !fields.field("priceHistory").exists() {
+        return Ok(items.into_iter().map(|item| MenuItem { item, prices: None }).collect());
+    }
+    let mut prices = price_histories(ctx, items.iter().map(Item::id).collect()).await?;
+    Ok(items.into_iter()
+        .map(|item| {
+            let history = prices.remove(&item.id()).unwrap_or_default();
+            MenuItem { item, prices: Some(history) }
+        })
+        .collect())
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    //Every restaurant, by name.
+    #[graphql(complexity = "limit * child_complexity")]
+    async fn restaurants(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize,
+    ) -> Result<Vec<Restaurant>, Error> {
+        let catalog = catalog(ctx);
+        page(catalog.restaurants.values().map(|x| Restaurant { name: x.0.clone() }), offset, limit)
+    }
+
+    //The restaurant with this name, ignoring case.
+    async fn restaurant(&self, ctx: &Context<'_>, name: String) -> Option<Restaurant> {
+        catalog(ctx).restaurant(&name).map(|x| Restaurant { name: x.0.clone() })
+    }
+
+    //Items in index order, optionally only one restaurant's.
+    #[graphql(complexity = "limit * child_complexity")]
+    async fn items(
+        &self,
+        ctx: &Context<'_>,
+        restaurant: Option<String>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize,
+    ) -> Result<Vec<MenuItem>, Error> {
+        let catalog = catalog(ctx);
+        let matching: Vec<&Item> = match restaurant {
+            Some(x) => page(catalog.items(catalog.restaurant(&x).map(|x| x.1.as_slice()).unwrap_or_default()), offset, limit)?,
+            None => page((0..catalog.index.item_count() as u32).map(|x| catalog.index.item(x)), offset, limit)?,
+        };
+        menu_items(ctx, ctx.look_ahead(), matching.into_iter().cloned().collect()).await
+    }
+
+    //The item with this id, the same one /sync and /events use.
+    async fn item(&self, ctx: &Context<'_>, id: ID) -> Option<MenuItem> {
+        let id: i64 = id.parse().ok()?;
+        let catalog = catalog(ctx);
+        let item = catalog.index.item(*catalog.ids.get(&id)?).clone();
+        Some(MenuItem { item, prices: None })
+    }
+
+    //Every ingredient, by name.
+    #[graphql(complexity = "limit * child_complexity")]
+    async fn ingredients(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize,
+    ) -> Result<Vec<Ingredient>, Error> {
+        let catalog = catalog(ctx);
+        page(catalog.ingredients.keys().map(|x| Ingredient { name: x.clone() }), offset, limit)
+    }
+
+    //The ingredient with this name, ignoring case.
+    async fn ingredient(&self, ctx: &Context<'_>, name: String) -> Option<Ingredient> {
+        let name = ingredient_key(&name);
+        catalog(ctx).ingredients.contains_key(&name).then_some(Ingredient { name })
+    }
+
+    //Same search as POST /v1/search, with the same arguments in camelCase.
+    #[graphql(complexity = "request.limit() * child_complexity")]
+    async fn search(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] request: SearchRequest,
+    ) -> Result<SearchResult, Error> {
+        request.validate().map_err(|e| Error::new(e.message()))?;
+        let catalog = catalog(ctx);
+        let results = search::run(&catalog.index, &request);
+        let items = results.results.iter().map(|x| x.item.clone()).collect();
+        let items = menu_items(ctx, ctx.look_ahead().field("hits").field("item"), items).await?;
+        Ok(SearchResult {
+            total: results.total,
+            offset: results.offset,
+            limit: results.limit,
+            terms: results.terms.into_iter().map(|(term, matches)| SearchTerm { term, matches }).collect(),
+            hits: results.results.into_iter()
+                .zip(items)
+                .map(|(x, item)| SearchHit { score: x.score, item, highlights: x.highlights })
+                .collect(),
+        })
+    }
+}
+
+pub struct Restaurant {
+    name: String,
+}
+
+#[Object]
+impl Restaurant {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    //Everything the restaurant has in the catalog.
+    async fn menu(&self, ctx: &Context<'_>) -> Menu {
+        let items = catalog(ctx).restaurant(&self.name).map(|x| x.1.clone()).unwrap_or_default();
+        Menu { restaurant: self.name.clone(), items }
+    }
+}
+
+//A restaurant's current menu: every item of theirs we have, each at its latest price.
+pub struct Menu {
+    restaurant: String,
+    items: Vec<u32>,
+}
+
+#[Object]
+impl Menu {
+    async fn restaurant(&self) -> Restaurant {
+        Restaurant { name: self.restaurant.clone() }
+    }
+
+    //The most recent `updated` date of any item on it.
+    async fn updated(&self, ctx: &Context<'_>) -> Option<String> {
+        catalog(ctx).items(&self.items).map(|x| x.updated()).max().map(str::to_string)
+    }
+
+    //The whole menu unless asked for less; menus are rarely over a hundred items.
+    #[graphql(complexity = "limit * child_complexity")]
+    async fn items(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default_with = "MAX_LIMIT")] limit: usize,
+    ) -> Result<Vec<MenuItem>, Error> {
+        let items = page(catalog(ctx).items(&self.items).cloned(), offset, limit)?;
+        menu_items(ctx, ctx.look_ahead(), items).await
+    }
+}
+
+//Named Item in the schema; `Item` is already the stored type.
+//`prices` is the item's price history when it was read along with the rest of the page.
+pub struct MenuItem {
+    item: Item,
+    prices: Option<Vec<PricePoint>>,
+}
+
+#[Object(name = "Item")]
+impl MenuItem {
+    async fn id(&self) -> ID {
+        ID(self.item.id().to_string())
+    }
+
+    async fn name(&self) -> &str {
+        self.item.name()
+    }
+
+    #[graphql(complexity = "limit * child_complexity")]
+    async fn ingredients(
+        &self,
+        #[graphql(default)] offset: usize,
+        #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize,
+    ) -> Result<Vec<Ingredient>, Error> {
+        let names = self.item.ingredients().iter()
+            .map(|x| ingredient_key(x))
+            .filter(|x| !x.is_empty())
+            .map(|name| Ingredient { name });
+        page(names, offset, limit)
+    }
+
+    //As written on the menu, e.g. "16" or "mp".
+    async fn price(&self) -> &str {
+        self.item.price()
+    }
+
+    //The price as a number, if it is one.
+    async fn price_value(&self) -> Option<f64> {
+        parse_price(self.item.price())
+    }
+
+    async fn updated(&self) -> &str {
+        self.item.updated()
+    }
+
+    async fn restaurant(&self) -> Restaurant {
+        Restaurant { name: self.item.restaurant().to_string() }
+    }
+
+    //Every price the item has had, oldest first, ending with the current one.
+    //Comes from the store rather than the index snapshot, so can be a change ahead of the rest.
+    async fn price_history(&self, ctx: &Context<'_>) -> Result<Vec<Price>, Error> {
+        let history = match &self.prices {
+            Some(x) => x.clone(),
+            None => {
+                let id = self.item.id();
+                price_histories(ctx, vec![id]).await?.remove(&id).unwrap_or_default()
+            }
+        };
+        Ok(history.into_iter().map(Price::from).collect())
+    }
+}
+
+//A price the item had, with the `updated` date of the item when it was set.
+//Named PricePoint in the schema, after the store's type it's made from.
+#[derive(SimpleObject)]
+#[graphql(name = "PricePoint")]
+pub struct Price {
+    price: String,
+    updated: String,
+}
+
+impl From<PricePoint> for Price {
+    fn from(point: PricePoint) -> Self {
+        Price { price: point.price, updated: point.updated }
+    }
+}
+
+pub struct Ingredient {
+    name: String,
+}
+
+#[Object]
+impl Ingredient {
+    //Lowercase, however the menus wrote it.
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    //Items listing it.
+    #[graphql(complexity = "limit * child_complexity")]
+    async fn items(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize,
+    ) -> Result<Vec<MenuItem>, Error> {
+        let catalog = catalog(ctx);
+        let listing = catalog.ingredients.get(&self.name).map(Vec::as_slice).unwrap_or_default();
+        let items = page(catalog.items(listing).cloned(), offset, limit)?;
+        menu_items(ctx, ctx.look_ahead(), items).await
+    }
+
+    //Restaurants with an item listing it, by name.
+    #[graphql(complexity = "limit * child_complexity")]
+    async fn restaurants(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize,
+    ) -> Result<Vec<Restaurant>, Error> {
+        let catalog = catalog(ctx);
+        let listing = catalog.ingredients.get(&self.name).map(Vec::as_slice).unwrap_or_default();
+        let mut names: Vec<&str> = catalog.items(listing).map(|x| x.restaurant()).collect();
+        names.sort_unstable();
+        names.dedup();
+        page(names.into_iter().map(|x| Restaurant { name: x.to_string() }), offset, limit)
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct SearchResult {
+    //Items matching the search and filters; `hits` is one page of them.
+    total: usize,
+    offset: usize,
+    limit: usize,
+    terms: Vec<SearchTerm>,
+    hits: Vec<SearchHit>,
+}
+
+//A word searched for, with the indexed words it matched.
+#[derive(SimpleObject)]
+pub struct SearchTerm {
+    term: String,
+    matches: Vec<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct SearchHit {
+    //Only comparable within one response. 0 without search text.
+    score: f64,
+    item: MenuItem,
+    //Only with `options: {highlight: true}`.
+    highlights: Option<Highlights>,
+}
+
+//Handler for POST /graphql.
+//GraphQL errors (bad queries, limits exceeded, bad search arguments) come back as `errors` in a
+//200 response like GraphQL clients expect. Only a body that isn't a GraphQL request at all is an
+//AppError.
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    tag = "search",
+    summary = "GraphQL over restaurants, menus, items and ingredients",
+    description = "Takes `{\"query\": ..., \"variables\": ..., \"operationName\": ...}`. The schema is \
+        browsable at GET /v1/graphql. Queries over the depth or complexity limits are rejected.",
+    request_body = Object,
+    responses(
+        (status = 200, description = "`data`, and `errors` if anything went wrong", body = Object),
+        (status = 400, description = "Malformed body", body = ErrorBody),
+        (status = 413, description = "Body over the size limit", body = ErrorBody),
+        (status = 422, description = "Not a GraphQL request", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = [])),
+)]
+pub async fn graphql(
+    State(schema): State<CatalogSchema>,
+    State(index): State<SharedIndex>,
+    body: Result<Json<async_graphql::Request>, JsonRejection>,
+) -> Result<Json<async_graphql::Response>, AppError> {
+    let Json(request) = body?;
+    Ok(Json(schema.execute(request.data(index.snapshot())).await))
+}
+
+//A small query page for POST /graphql: an editor for the query and variables, the response, and the
+//schema's types down the side (from an introspection query). Written here rather than GraphiQL,
+//which would have to come from a CDN or be vendored, for a page that only needs to send a request.
+//Everything it shows from the server goes in with textContent, never as HTML.
+const EXPLORER_PAGE: &str = r##"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>Menu Manager GraphQL</title>
+  <style>
+    body { margin: 0; font: 14px system-ui, sans-serif; display: grid; grid-template-columns: 1fr 1fr 18em; height: 100vh; }
+    section { display: flex; flex-direction: column; padding: 8px; gap: 6px; min-height: 0; border-right: 1px solid #ddd; }
+    textarea, pre { font: 13px ui-monospace, monospace; margin: 0; }
+    textarea { flex: 1; resize: none; }
+    #variables { flex: 0 0 6em; }
+    pre { flex: 1; overflow: auto; background: #f6f6f6; padding: 6px; }
+    #schema { overflow: auto; font-size: 13px; }
+    #schema h3 { margin: 10px 0 2px; font-size: 13px; }
+    #schema div { padding-left: 1em; font-family: ui-monospace, monospace; }
+  </style>
+</head>
+<body>
+  <section>
+    <label>Query (Ctrl+Enter runs it)</label>
+    <textarea id="query" spellcheck="false">{
+  restaurants {
+    name
+    menu { updated items(limit: 3) { name price } }
+  }
+}</textarea>
+    <label>Variables (JSON)</label>
+    <textarea id="variables" spellcheck="false">{}</textarea>
+    <label>API key, if the server needs one <input id="key" type="password" autocomplete="off"></label>
+    <button id="run">Run</button>
+  </section>
+  <section><pre id="result"></pre></section>
+  <section id="schema"></section>
+  <script>
+    const $ = (id) => document.getElementById(id);
+    async function send(query, variables) {
+      const headers = { "content-type": "application/json" };
+      if ($("key").value) headers.authorization = "Bearer " + $("key").value;
+      const response = await fetch("graphql", { method: "POST", headers, body: JSON.stringify({ query, variables }) });
+      return response.json();
+    }
+    async function run() {
+      let variables;
+      try { variables = JSON.parse($("variables").value || "{}"); }
+      catch (e) { $("result").textContent = "Variables aren't valid JSON: " + e.message; return; }
+      $("result").textContent = "...";
+      try { $("result").textContent = JSON.stringify(await send($("query").value, variables), null, 2); }
+      catch (e) { $("result").textContent = "Request failed: " + e.message; }
+    }
+    function typeName(t) {
+      if (t.kind === "NON_NULL") return typeName(t.ofType) + "!";
+      if (t.kind === "LIST") return "[" + typeName(t.ofType) + "]";
+      return t.name;
+    }
+    async function schema() {
+      const type = "name kind ofType { name kind ofType { name kind ofType { name kind } } }";
+      const res = await send("{ __schema { types { name kind fields { name type { " + type + " } } } } }", {});
+      if (!res.data) return;
+      for (const t of res.data.__schema.types) {
+        if (t.name.startsWith("__") || !t.fields) continue;
+        const h = document.createElement("h3");
+        h.textContent = t.name;
+        $("schema").append(h);
+        for (const f of t.fields) {
+          const d = document.createElement("div");
+          d.textContent = f.name + ": " + typeName(f.type);
+          $("schema").append(d);
+        }
+      }
+    }
+    $("run").onclick = run;
+    $("query").onkeydown = (e) => { if (e.key === "Enter" && e.ctrlKey) run(); };
+    schema();
+  </script>
+</body>
+</html>
+"##;
+
+//Handler for GET /graphql, the query page above.
+#[utoipa::path(
+    get,
+    path = "/graphql",
+    tag = "search",
+    summary = "A page for trying out queries against the GraphQL endpoint",
+    responses(
+        (status = 200, description = "The query page", body = String, content_type = "text/html"),
+    ),
+    security((), ("bearer" = []), ("api_key" = [])),
+)]
+pub async fn explorer() -> Html<&'static str> {
+    Html(EXPLORER_PAGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_graphql::Request;
+    use axum::body::Body;
+    use axum::http;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::config::Limits;
+    use crate::graphql::{schema, Catalogs};
+    use crate::menu::{get_index, Item};
+    use crate::router;
+    use crate::tests::{send, test_state};
+    use crate::AppState;
+
+    //Runs the query against the state's schema and index, returning `data` and the error messages.
+    async fn execute(state: &AppState, query: &str, variables: Value) -> (Value, Vec<String>) {
+        let request = Request::new(query)
+            .variables(async_graphql::Variables::from_json(variables))
+            .data(state.index.snapshot());
+        let response = state.graphql.execute(request).await;
+        let errors = response.errors.iter().map(|x| x.message.clone()).collect();
+        (response.data.into_json().unwrap(), errors)
+    }
+
+    #[tokio::test]
+    async fn test_catalog() {
+        let state = test_state();
+        let (data, errors) = execute(&state, "{ restaurants { name menu { updated items { name } } } }", json!({})).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        let restaurants = data["restaurants"].as_array().unwrap();
+        let names: Vec<&str> = restaurants.iter().map(|x| x["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["Bateau", "Canlis", "Lark", "Westward"]);
+        let items: usize = restaurants.iter().map(|x| x["menu"]["items"].as_array().unwrap().len()).sum();
+        assert_eq!(items, state.store.list().unwrap().len());
+
+        //Every item comes back to the restaurant it's on, and lists its ingredients.
+        let query = r#"query($name: String!) {
+            restaurant(name: $name) { name menu { items(limit: 5) { id restaurant { name } ingredients { name } } } }
+        }"#;
+        let (data, _) = execute(&state, query, json!({ "name": "lark" })).await;
+        assert_eq!(data["restaurant"]["name"], "Lark");
+        let items = data["restaurant"]["menu"]["items"].as_array().unwrap();
+        assert_eq!(items.len(), 5);
+        assert!(items.iter().all(|x| x["restaurant"]["name"] == "Lark"));
+        let (data, _) = execute(&state, "{ restaurant(name: \"Nowhere\") { name } }", json!({})).await;
+        assert_eq!(data["restaurant"], Value::Null);
+
+        //An ingredient knows what uses it, ignoring case.
+        let ingredient = items.iter().flat_map(|x| x["ingredients"].as_array().unwrap()).next().unwrap()["name"].clone();
+        let query = "query($name: String!) { ingredient(name: $name) { name restaurants { name } items { restaurant { name } } } }";
+        let (data, _) = execute(&state, query, json!({ "name": ingredient.as_str().unwrap().to_uppercase() })).await;
+        assert_eq!(data["ingredient"]["name"], ingredient);
+        assert!(data["ingredient"]["restaurants"].as_array().unwrap().contains(&json!({ "name": "Lark" })));
+        assert!(!data["ingredient"]["items"].as_array().unwrap().is_empty());
+
+        //Every list is paged.
+        let (data, _) = execute(&state, "{ restaurants(offset: 1, limit: 2) { name } }", json!({})).await;
+        assert_eq!(data["restaurants"], json!([{ "name": "Canlis" }, { "name": "Lark" }]));
+        let (data, _) = execute(&state, "{ items(limit: 1) { ingredients(limit: 1) { restaurants(limit: 1) { name } } } }", json!({})).await;
+        assert_eq!(data["items"][0]["ingredients"].as_array().unwrap().len(), 1);
+        assert_eq!(data["items"][0]["ingredients"][0]["restaurants"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_catalog_per_snapshot() {
+        let state = test_state();
+        let catalogs = Catalogs::default();
+        let index = state.index.snapshot();
+        let first = catalogs.get(&index);
+        assert!(Arc::ptr_eq(&first, &catalogs.get(&index)));
+        assert_eq!(first.restaurants.len(), 4);
+        assert_eq!(first.ids.len(), index.item_count());
+        let listed: usize = first.restaurants.values().map(|x| x.1.len()).sum();
+        assert_eq!(listed, index.item_count());
+
+        //A new snapshot gets a new one.
+        state.index.publish(get_index(state.store.as_ref()).unwrap());
+        assert!(!Arc::ptr_eq(&first, &catalogs.get(&state.index.snapshot())));
+    }
+
+    #[tokio::test]
+    async fn test_price_history() {
+        let state = test_state();
+        let stored = state.store.list().unwrap().remove(0);
+        let mut repriced: Value = serde_json::to_value(&stored).unwrap();
+        repriced["price"] = json!("99.5");
+        let repriced: Item = serde_json::from_value(repriced).unwrap();
+        state.store.upsert(&repriced).unwrap();
+
+        let query = "query($id: ID!) { item(id: $id) { name price priceValue priceHistory { price updated } } }";
+        let (data, errors) = execute(&state, query, json!({ "id": stored.id().to_string() })).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        //The index hasn't been rebuilt, so the item is still at its old price, but the history is
+        //already up to date.
+        assert_eq!(data["item"]["price"], stored.price());
+        let history: Vec<&Value> = data["item"]["priceHistory"].as_array().unwrap().iter().map(|x| &x["price"]).collect();
+        assert_eq!(history, [stored.price(), "99.5"]);
+        let (data, _) = execute(&state, "{ item(id: \"not a number\") { name } }", json!({})).await;
+        assert_eq!(data["item"], Value::Null);
+
+        //Read for the whole page at once, and still each item's own.
+        let query = "{ restaurants(limit: 4) { menu { items { id priceHistory { price } } } } }";
+        let (data, errors) = execute(&state, query, json!({})).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        let items: Vec<&Value> = data["restaurants"].as_array().unwrap().iter()
+            .flat_map(|x| x["menu"]["items"].as_array().unwrap())
+            .collect();
+        assert_eq!(items.len(), state.store.list().unwrap().len());
+        let repriced_id = json!(stored.id().to_string());
+        for item in items {
+            let expected = if item["id"] == repriced_id { 2 } else { 1 };
+            assert_eq!(item["priceHistory"].as_array().unwrap().len(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let state = test_state();
+        let query = r#"{ search(request: { text: "oystr", options: { fuzzy: true, highlight: true }, limit: 3 }) {
+            total limit terms { term matches } hits { score item { name } highlights { itemName } }
+        } }"#;
+        let (data, errors) = execute(&state, query, json!({})).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        let search = &data["search"];
+        assert!(search["total"].as_u64().unwrap() > 3);
+        assert_eq!(search["hits"].as_array().unwrap().len(), 3);
+        assert_eq!(search["terms"][0], json!({ "term": "oystr", "matches": ["oyster"] }));
+        assert!(search["hits"][0]["highlights"]["itemName"].as_str().unwrap().contains("<mark>"));
+
+        //Same validation as POST /v1/search.
+        let (_, errors) = execute(&state, r#"{ search(request: { filters: { updatedFrom: "June" } }) { total } }"#, json!({})).await;
+        assert_eq!(errors, ["filters.updated_from \"June\" isn't a YYYY-MM-DD date"]);
+        let (_, errors) = execute(&state, "{ items(limit: 0) { name } }", json!({})).await;
+        assert_eq!(errors, ["limit has to be between 1 and 100"]);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let mut state = test_state();
+        //Round in circles, three menus deep.
+        let circular = "{ restaurants { menu { items { restaurant { menu { items { restaurant { menu { items { name } } } } } } } } } }";
+        let (_, errors) = execute(&state, circular, json!({})).await;
+        assert_eq!(errors, ["Query is too complex."]);
+        let (_, errors) = execute(&state, "{ items(limit: 100) { ingredients { items(limit: 100) { name } } } }", json!({})).await;
+        assert_eq!(errors, ["Query is too complex."]);
+
+        state.graphql = schema(state.store.clone(), &Limits { graphql_max_depth: 3, ..Limits::default() });
+        let (data, errors) = execute(&state, "{ restaurants { menu { updated } } }", json!({})).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(data["restaurants"].is_array());
+        let (_, errors) = execute(&state, "{ restaurants { menu { items { name } } } }", json!({})).await;
+        assert_eq!(errors, ["Query is nested too deep."]);
+
+        //0 turns the limits off.
+        state.graphql = schema(state.store.clone(), &Limits { graphql_max_depth: 0, graphql_max_complexity: 0, ..Limits::default() });
+        let (_, errors) = execute(&state, "{ items(limit: 100) { ingredients { items(limit: 100) { name } } } }", json!({})).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[tokio::test]
+    async fn test_served() {
+        let app = router(test_state());
+        let (status, body) = send(&app, "POST", "/v1/graphql", None, r#"{"query": "{ restaurants { name } }"}"#).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["data"]["restaurants"].as_array().unwrap().len(), 4);
+
+        //Query errors are still a 200, as GraphQL clients expect; bodies that aren't a request aren't.
+        let (status, body) = send(&app, "POST", "/v1/graphql", None, r#"{"query": "{ nope }"}"#).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert!(body["errors"].is_array());
+        let (status, body) = send(&app, "POST", "/v1/graphql", None, r#"{"query": 5}"#).await;
+        assert_eq!(status, http::StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "unprocessable");
+
+        let response = app.clone().oneshot(http::Request::get("/v1/graphql").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let page = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8_lossy(&page).contains("https://"), "The page shouldn't load anything from elsewhere");
+        //The schema listing the page asks for fits in the default limits.
+        let listing = r#"{"query": "{ __schema { types { name kind fields { name type { name kind ofType { name kind ofType { name kind ofType { name kind } } } } } } } }"}"#;
+        let (status, body) = send(&app, "POST", "/v1/graphql", None, listing).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert!(body.get("errors").is_none(), "{}", body);
+        let (status, _) = send(&app, "POST", "/graphql", None, r#"{"query": "{ restaurants { name } }"}"#).await;
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+    }
+}
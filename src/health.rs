@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::{json, Value};
+
+use crate::index::SharedIndex;
+use crate::store::{with_store, SharedStore};
+
+//Startup (or maintenance) work that should keep /readyz failing while it runs, by name.
+//A name can be held more than once; it's only cleared when every guard for it is dropped.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    pending: Arc<Mutex<BTreeMap<&'static str, usize>>>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Readiness::default()
+    }
+
+    //Marks the work as running until the returned guard is dropped.
+    pub fn begin(&self, name: &'static str) -> PendingGuard {
+        *self.lock().entry(name).or_insert(0) += 1;
+        PendingGuard { readiness: self.clone(), name }
+    }
+
+    pub fn pending(&self) -> Vec<&'static str> {
+        self.lock().keys().copied().collect()
+    }
+
+    //Nothing in here can be left half updated, so a poisoned lock is still fine to use.
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<&'static str, usize>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct PendingGuard {
+    readiness: Readiness,
+    name: &'static str,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut pending = self.readiness.lock();
+        if let Some(count) = pending.get_mut(self.name) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(self.name);
+            }
+        }
+    }
+}
+
+//Liveness: if this answers, the process is up and the runtime isn't wedged.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "ops",
+    summary = "Liveness",
+    responses(
+        (status = 200, description = "The process is up", body = Value, example = json!({ "status": "ok" })),
+    ),
+)]
+pub async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+//Readiness: the database opens and has the schema this build expects, the index has been built and
+//isn't being rebuilt, and no startup work is still running. 503 with the details otherwise.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "ops",
+    summary = "Readiness",
+    responses(
+        (status = 200, description = "Ready for traffic", body = Value, example = json!({
+            "status": "ready",
+            "checks": { "database": "ok", "index": "ok", "pending": [] },
+        })),
+        (status = 503, description = "Not ready, with the checks that failed", body = Value, example = json!({
+            "status": "not_ready",
+            "checks": { "database": "ok", "index": "building", "pending": ["seed"] },
+        })),
+    ),
+)]
+pub async fn readyz(
+    State(store): State<SharedStore>,
+    State(index): State<SharedIndex>,
+    State(readiness): State<Readiness>,
+) -> (StatusCode, Json<Value>) {
+    let database = match with_store(&store, |store| store.check()).await {
+        Ok(()) => "ok".to_string(),
+        Err(e) => e.to_string(),
+    };
+    let index_status = if !index.is_built() {
+        "building"
+    } else if index.is_rebuilding() {
+        "rebuilding"
+    } else {
+        "ok"
+    };
+    let pending = readiness.pending();
+
+    let ready = database == "ok" && index_status == "ok" && pending.is_empty();
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "checks": {
+            "database": database,
+            "index": index_status,
+            "pending": pending,
+        },
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::Readiness;
+
+    #[test]
+    fn test_readiness_guards() {
+        let readiness = Readiness::new();
+        let seed = readiness.begin("seed");
+        let first = readiness.begin("migration");
+        let second = readiness.begin("migration");
+        assert_eq!(readiness.pending(), vec!["migration", "seed"]);
+
+        drop(first);
+        assert_eq!(readiness.pending(), vec!["migration", "seed"]);
+        drop(second);
+        drop(seed);
+        assert!(readiness.pending().is_empty());
+    }
+}
@@ -0,0 +1,614 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::db::DbError;
+use crate::menu::{load_index, Item};
+use crate::store::{with_store, SharedStore};
+
+//Snapshot file layout, all integers little-endian:
+//  4 bytes     magic, "MMIX"
+//  u32         format version, bumped whenever Index (or Item) changes shape
+//  i64         id of the database the index was built from
+//  i64         database change counter the index was built at
+//  ...         the Index itself, bincode encoded
+//Anything that doesn't match is treated as missing and rebuilt, never as an error for the caller.
+//That includes a body that decodes but doesn't hang together (see Index::is_consistent), since the
+//header alone can't vouch for a file someone edited or a disk flipped bits in.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"MMIX";
+const SNAPSHOT_VERSION: u32 = 2;
+
+//The snapshot lives next to the database, e.g. res/menu_db.sqlite.index
+pub fn snapshot_path(db_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.index", db_path))
+}
+
+//Inverted index over the menu items.
+//Items live once in `items`, and everything else refers to them by their position (a u32 id).
+//Each distinct word is interned once into `terms`, which maps it to a term id, and `postings` holds
+//the sorted ids of the items containing that word. Compared to the old HashMap<String, HashSet<Arc<Item>>>
+//this is a lot smaller (4 bytes per posting instead of a pointer plus hash table overhead), and
+//sorted lists mean multi-word queries can merge instead of hashing every item.
+//`version` is the store's change counter the index was built at, and `database` the id of the
+//database it was built from. They're kept in the snapshot header rather than the encoded Index, so
+//they're skipped here.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    items: Vec<Item>,
+    terms: HashMap<Box<str>, u32>,
+    postings: Vec<Vec<u32>>,
+    #[serde(skip)]
+    version: i64,
+    #[serde(skip)]
+    database: i64,
+}
+
+impl Index {
+    //Ids are assigned in the order the items are passed in.
+    pub fn build(mut items: Vec<Item>) -> Self {
+        items.shrink_to_fit();
+
+        let mut terms: HashMap<Box<str>, u32> = HashMap::new();
+        let mut postings: Vec<Vec<u32>> = Vec::new();
+
+        for (id, item) in items.iter().enumerate() {
+            let id = id as u32;
+            for word in item.terms() {
+                let term = match terms.get(word.as_str()) {
+                    Some(&x) => x,
+                    None => {
+                        let x = postings.len() as u32;
+                        terms.insert(word.into_boxed_str(), x);
+                        postings.push(Vec::new());
+                        x
+                    }
+                };
+                //Ids only ever increase, so checking the tail is enough to keep the list sorted and
+                //free of duplicates (an item can use the same word more than once).
+                let list = &mut postings[term as usize];
+                if list.last() != Some(&id) {
+                    list.push(id);
+                }
+            }
+        }
+
+        for list in postings.iter_mut() {
+            list.shrink_to_fit();
+        }
+
+        Index { items, terms, postings, version: 0, database: 0 }
+    }
+
+    //Tags the index with the database it was built from and the change counter it was built at.
+    pub fn with_version(mut self, database: i64, version: i64) -> Self {
+        self.database = database;
+        self.version = version;
+        self
+    }
+
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+
+    pub fn database(&self) -> i64 {
+        self.database
+    }
+
+    pub fn item(&self, id: u32) -> &Item {
+        &self.items[id as usize]
+    }
+
+    pub fn item_count(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn term_count(&self) -> usize {
+        self.terms.len()
+    }
+
+    //Every distinct word in the index, in no particular order. For matching that can't be a lookup
+    //(fuzzy, stemmed); see search.rs.
+    pub fn terms(&self) -> impl Iterator<Item = &str> {
+        self.terms.keys().map(|x| x.as_ref())
+    }
+
+    //Sorted ids of the items containing the term. Unknown terms have an empty list.
+    pub fn postings(&self, term: &str) -> &[u32] {
+        match self.terms.get(term) {
+            Some(&x) => &self.postings[x as usize],
+            None => &[],
+        }
+    }
+
+    //Ids of the items matching any of the terms.
+    pub fn search_any<'a>(&self, terms: impl IntoIterator<Item = &'a str>) -> Vec<u32> {
+        terms.into_iter()
+            .map(|x| self.postings(x))
+            .fold(Vec::new(), |acc, list| union(&acc, list))
+    }
+
+    //Ids of the items matching every one of the terms.
+    //Starts from the shortest list so every later step is at most that long.
+    pub fn search_all<'a>(&self, terms: impl IntoIterator<Item = &'a str>) -> Vec<u32> {
+        let mut lists: Vec<&[u32]> = terms.into_iter().map(|x| self.postings(x)).collect();
+        lists.sort_by_key(|x| x.len());
+
+        let mut lists = lists.into_iter();
+        let mut res = match lists.next() {
+            Some(x) => x.to_vec(),
+            None => return Vec::new(),
+        };
+        for list in lists {
+            if res.is_empty() {
+                break;
+            }
+            res = intersect(&res, list);
+        }
+        res
+    }
+
+    //Whether every id in the postings points at an item and every list is sorted without duplicates,
+    //and every term points at a postings list. Always true for a built index; checked on snapshots
+    //before anything indexes into `items` with them.
+    fn is_consistent(&self) -> bool {
+        let items = self.items.len() as u64;
+        self.terms.len() == self.postings.len()
+            && self.terms.values().all(|&x| (x as usize) < self.postings.len())
+            && self.postings.iter().all(|list| {
+                list.windows(2).all(|x| x[0] < x[1]) && list.last().is_none_or(|&x| (x as u64) < items)
+            })
+    }
+
+    //Writes the index to a snapshot file, tagged with the database and change counter it was built at.
+    //Writes to a temporary file first and renames it, so a crash never leaves a half written snapshot.
+    pub fn save(&self, file: &Path, database: i64, change_counter: i64) -> Result<(), Box<dyn Error>> {
+        let tmp = file.with_extension("index.tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            writer.write_all(SNAPSHOT_MAGIC)?;
+            writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+            writer.write_all(&database.to_le_bytes())?;
+            writer.write_all(&change_counter.to_le_bytes())?;
+            bincode::serialize_into(&mut writer, self)?;
+            writer.flush()?;
+        }
+        fs::rename(&tmp, file)?;
+        Ok(())
+    }
+
+    //Reads a snapshot file if it exists, was written by this format version, matches the given
+    //database and change counter, and is consistent. Returns None otherwise.
+    pub fn load(file: &Path, database: i64, change_counter: i64) -> Result<Option<Index>, Box<dyn Error>> {
+        let mut reader = match File::open(file) {
+            Ok(x) => BufReader::new(x),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut header = [0u8; 24];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if &header[0..4] != SNAPSHOT_MAGIC
+            || header[4..8] != SNAPSHOT_VERSION.to_le_bytes()
+            || header[8..16] != database.to_le_bytes()
+            || header[16..24] != change_counter.to_le_bytes() {
+            return Ok(None);
+        }
+
+        let index: Index = bincode::deserialize_from(reader)?;
+        if !index.is_consistent() {
+            return Ok(None);
+        }
+        Ok(Some(index.with_version(database, change_counter)))
+    }
+}
+
+//Merges two sorted lists into their sorted union.
+pub fn union(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => {
+                res.push(a[i]);
+                i += 1;
+            }
+            Ordering::Greater => {
+                res.push(b[j]);
+                j += 1;
+            }
+            Ordering::Equal => {
+                res.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    res.extend_from_slice(&a[i..]);
+    res.extend_from_slice(&b[j..]);
+    res
+}
+
+//Intersects two sorted lists.
+//When one list is much shorter, each of its ids is found in the longer one by galloping (doubling
+//the step until we pass it, then binary searching that window), otherwise it's a plain merge.
+pub fn intersect(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    let mut res = Vec::with_capacity(short.len());
+
+    if short.len() * 8 < long.len() {
+        let mut base = 0;
+        for &x in short {
+            let mut step = 1;
+            while base + step < long.len() && long[base + step] < x {
+                step *= 2;
+            }
+            let end = (base + step + 1).min(long.len());
+            match long[base..end].binary_search(&x) {
+                Ok(pos) => {
+                    res.push(x);
+                    base += pos + 1;
+                }
+                Err(pos) => base += pos,
+            }
+            if base >= long.len() {
+                break;
+            }
+        }
+    } else {
+        let (mut i, mut j) = (0, 0);
+        while i < short.len() && j < long.len() {
+            match short[i].cmp(&long[j]) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    res.push(short[i]);
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+    }
+    res
+}
+
+//Holds the current index as an immutable snapshot.
+//Readers grab an Arc to whatever snapshot is current and keep using it for the rest of the request,
+//so a rebuild never blocks them. Writers build a whole new index off to the side and swap it in.
+//Since nothing is ever locked, a panic while building a new index just means the old one stays put,
+//instead of poisoning search for everyone (which was the problem with the RwLock).
+//Also tracks whether a real index has been published yet and whether a rebuild is running, for /readyz.
+#[derive(Clone)]
+pub struct SharedIndex {
+    current: Arc<ArcSwap<Index>>,
+    status: Arc<IndexStatus>,
+}
+
+#[derive(Default)]
+struct IndexStatus {
+    built: AtomicBool,
+    rebuilding: AtomicUsize,
+}
+
+//Marks a rebuild as running for as long as it's alive, including if the rebuild fails or is dropped.
+struct RebuildGuard<'a>(&'a IndexStatus);
+
+impl Drop for RebuildGuard<'_> {
+    fn drop(&mut self) {
+        self.0.rebuilding.fetch_sub(1, AtomicOrdering::SeqCst);
+    }
+}
+
+impl SharedIndex {
+    pub fn new(index: Index) -> Self {
+        let shared = SharedIndex::empty();
+        shared.publish(index);
+        shared
+    }
+
+    //Starts with an empty index that doesn't count as built until the first publish.
+    pub fn empty() -> Self {
+        SharedIndex {
+            current: Arc::new(ArcSwap::from_pointee(Index::default())),
+            status: Arc::new(IndexStatus::default()),
+        }
+    }
+
+    //Returns the snapshot that's current right now. Later publishes don't affect it.
+    pub fn snapshot(&self) -> Arc<Index> {
+        self.current.load_full()
+    }
+
+    //Atomically replaces the current snapshot. Readers holding the old one finish with it, and it's
+    //dropped once the last of them is done.
+    pub fn publish(&self, index: Index) {
+        debug!("Publishing new index snapshot with {} terms and {} items.", index.term_count(), index.item_count());
+        self.current.store(Arc::new(index));
+        self.status.built.store(true, AtomicOrdering::SeqCst);
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.status.built.load(AtomicOrdering::SeqCst)
+    }
+
+    pub fn is_rebuilding(&self) -> bool {
+        self.status.rebuilding.load(AtomicOrdering::SeqCst) > 0
+    }
+
+    //Rebuilds the index from the database (or its saved snapshot, if that's still current) and
+    //publishes it.
+    //The (slow) rebuild happens on a blocking thread; readers keep using the old snapshot until
+    //it's done. If the rebuild fails or panics, the old snapshot is left in place.
+    pub async fn rebuild(&self, store: &SharedStore) -> Result<(), DbError> {
+        self.status.rebuilding.fetch_add(1, AtomicOrdering::SeqCst);
+        let _guard = RebuildGuard(&self.status);
+        let index = with_store(store, load_index).await?;
+        self.publish(index);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::mem::size_of;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use crate::index::{intersect, union, Index, SharedIndex};
+    use crate::menu::{make_map, Item};
+    use crate::store::{MemoryStore, SharedStore};
+
+    fn item(name: &str, ingredients: &[&str]) -> Item {
+        serde_json::from_value(serde_json::json!({
+            "item_name": name,
+            "ingredients": ingredients,
+            "updated": "2024-06-04",
+            "price": "12",
+            "restaurant": "Lark",
+        })).unwrap()
+    }
+
+    fn sample() -> Index {
+        Index::build(vec![
+            item("Oyster stew", &["cream", "leek"]),
+            item("Grilled oyster", &["butter"]),
+            item("Leek soup", &["cream"]),
+        ])
+    }
+
+    //Every item from the sample menus in res/, repeated with a suffix so the names don't collide.
+    fn catalog(copies: usize) -> Vec<Item> {
+        let mut items = Vec::new();
+        for file in ["res/bateau_04-11.json", "res/canlis_06-03.json", "res/lark_06-03.json", "res/westward_05-16.json"] {
+            let raw = std::fs::read_to_string(file).unwrap();
+            let json: Vec<serde_json::Value> = serde_json::from_str(&raw).unwrap();
+            for n in 0..copies {
+                for x in &json {
+                    let mut x = x.clone();
+                    let name = format!("{} v{}", x["item_name"].as_str().unwrap(), n);
+                    x["item_name"] = name.into();
+                    items.push(serde_json::from_value(x).unwrap());
+                }
+            }
+        }
+        items
+    }
+
+    #[test]
+    fn test_postings_sorted_and_deduplicated() {
+        let index = Index::build(vec![item("Leek leek", &["leek"]), item("Leek", &[])]);
+        assert_eq!(index.postings("leek"), &[0, 1]);
+        assert_eq!(index.postings("missing"), &[] as &[u32]);
+        assert_eq!(index.term_count(), 1);
+        assert_eq!(index.item_count(), 2);
+    }
+
+    #[test]
+    fn test_search_any_and_all() {
+        let index = sample();
+        assert_eq!(index.search_any(["oyster", "leek"]), vec![0, 1, 2]);
+        assert_eq!(index.search_all(["oyster", "leek"]), vec![0]);
+        assert_eq!(index.search_all(["cream", "leek"]), vec![0, 2]);
+        assert_eq!(index.search_all(["oyster", "missing"]), Vec::<u32>::new());
+        assert_eq!(index.search_any(Vec::<&str>::new()), Vec::<u32>::new());
+        assert_eq!(index.item(1).terms(), vec!["grilled", "oyster", "butter"]);
+    }
+
+    #[test]
+    fn test_merge_and_gallop_agree() {
+        let long: Vec<u32> = (0..1000).filter(|x| x % 3 == 0).collect();
+        let short: Vec<u32> = vec![0, 4, 9, 500, 501, 999, 2000];
+        let mid: Vec<u32> = (0..1000).filter(|x| x % 2 == 0).collect();
+
+        let expected = |a: &[u32], b: &[u32]| -> Vec<u32> { a.iter().copied().filter(|x| b.contains(x)).collect() };
+
+        //Galloping path.
+        assert_eq!(intersect(&short, &long), expected(&short, &long));
+        assert_eq!(intersect(&long, &short), expected(&short, &long));
+        //Merge path.
+        assert_eq!(intersect(&mid, &long), expected(&mid, &long));
+
+        let mut all: Vec<u32> = short.iter().chain(long.iter()).copied().collect();
+        all.sort();
+        all.dedup();
+        assert_eq!(union(&short, &long), all);
+    }
+
+    #[test]
+    fn test_snapshot_file_round_trip() {
+        let file = std::env::temp_dir().join(format!("menu_manager_test_{}.index", std::process::id()));
+        let index = sample();
+        index.save(&file, 1, 7).unwrap();
+
+        let loaded = Index::load(&file, 1, 7).unwrap().expect("Snapshot should match its own counter.");
+        assert_eq!(loaded.postings("oyster"), &[0, 1]);
+        assert_eq!(loaded.item(2), index.item(2));
+        assert_eq!(loaded.term_count(), index.term_count());
+        assert_eq!(loaded.version(), 7);
+
+        //Stale counter, another database, unknown version, and truncated files are all just "no snapshot".
+        assert!(Index::load(&file, 1, 8).unwrap().is_none());
+        assert!(Index::load(&file, 2, 7).unwrap().is_none());
+        let mut raw = std::fs::read(&file).unwrap();
+        raw[4] = raw[4].wrapping_add(1);
+        std::fs::write(&file, &raw).unwrap();
+        assert!(Index::load(&file, 1, 7).unwrap().is_none());
+        std::fs::write(&file, &raw[..10]).unwrap();
+        assert!(Index::load(&file, 1, 7).unwrap().is_none());
+
+        std::fs::remove_file(&file).unwrap();
+        assert!(Index::load(&file, 1, 7).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_inconsistent_snapshot() {
+        let file = std::env::temp_dir().join(format!("menu_manager_test_{}_bad.index", std::process::id()));
+        assert!(sample().is_consistent());
+
+        //A posting past the last item would panic in `item` if it were ever loaded.
+        let mut index = sample();
+        index.postings[0].push(3);
+        index.save(&file, 1, 7).unwrap();
+        assert!(Index::load(&file, 1, 7).unwrap().is_none());
+
+        let mut index = sample();
+        index.postings[0].reverse();
+        assert!(!index.is_consistent());
+        let mut index = sample();
+        index.postings.pop();
+        assert!(!index.is_consistent());
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_survives_publish() {
+        let index = SharedIndex::new(sample());
+        let old = index.snapshot();
+
+        index.publish(Index::build(vec![item("Clams", &[])]));
+
+        assert_eq!(old.postings("oyster"), &[0, 1]);
+        assert!(index.snapshot().postings("oyster").is_empty());
+        assert_eq!(index.snapshot().postings("clams"), &[0]);
+    }
+
+    #[tokio::test]
+    async fn test_build_status() {
+        let index = SharedIndex::empty();
+        assert!(!index.is_built());
+        assert!(!index.is_rebuilding());
+
+        let store: SharedStore = Arc::new(MemoryStore::new());
+        index.rebuild(&store).await.unwrap();
+        assert!(index.is_built());
+        assert!(!index.is_rebuilding());
+        assert!(SharedIndex::new(sample()).is_built());
+    }
+
+    #[test]
+    fn test_panicking_writer_keeps_old_snapshot() {
+        let index = SharedIndex::new(sample());
+        let writer = index.clone();
+
+        let build = || -> Index { panic!("Simulated failure while building a new index.") };
+        let res = std::thread::spawn(move || writer.publish(build())).join();
+
+        assert!(res.is_err());
+        assert_eq!(index.snapshot().postings("oyster"), &[0, 1]);
+    }
+
+    //Rough comparison of the old map and the new index. Heap sizes are estimates (hashbrown's
+    //tables are approximated as capacity * (entry + 1 control byte)), but it's the same estimate
+    //for both, so the ratio is what matters.
+    //Run with `cargo test --release bench_index_vs_map -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_index_vs_map() {
+        let items = catalog(200);
+        let queries = ["oyster", "lemon butter", "cream leek potato", "smoked salmon roe", "nothing here"];
+        let rounds = 200;
+
+        fn item_heap(item: &Item) -> usize {
+            serde_json::to_string(item).unwrap().len()
+        }
+
+        let start = Instant::now();
+        let map = make_map(items.clone());
+        let map_build = start.elapsed();
+        let mut map_bytes = map.capacity() * (size_of::<(String, HashSet<Arc<Item>>)>() + 1);
+        let mut map_items = 0;
+        let mut seen: HashSet<*const Item> = HashSet::new();
+        for (key, set) in &map {
+            map_bytes += key.capacity();
+            map_bytes += set.capacity() * (size_of::<Arc<Item>>() + 1);
+            for x in set {
+                if seen.insert(Arc::as_ptr(x)) {
+                    //Arc allocations carry two reference counts in front of the item.
+                    map_items += size_of::<Item>() + 16 + item_heap(x);
+                }
+            }
+        }
+
+        let start = Instant::now();
+        let index = Index::build(items);
+        let index_build = start.elapsed();
+        let index_items = index.items.capacity() * size_of::<Item>() + index.items.iter().map(item_heap).sum::<usize>();
+        let mut index_bytes = index.terms.capacity() * (size_of::<(Box<str>, u32)>() + 1);
+        index_bytes += index.terms.keys().map(|x| x.len()).sum::<usize>();
+        index_bytes += index.postings.capacity() * size_of::<Vec<u32>>();
+        index_bytes += index.postings.iter().map(|x| x.capacity() * size_of::<u32>()).sum::<usize>();
+
+        //What the query handler used to do: look up every word and clone the items into a set.
+        let start = Instant::now();
+        let mut map_hits = 0;
+        for _ in 0..rounds {
+            for query in queries {
+                let mut res: HashSet<Item> = HashSet::new();
+                for word in query.split(char::is_whitespace) {
+                    if let Some(x) = map.get(word) {
+                        for item in x {
+                            res.insert((**item).clone());
+                        }
+                    }
+                }
+                map_hits += serde_json::to_vec(&res).unwrap().len();
+            }
+        }
+        let map_query = start.elapsed();
+
+        let start = Instant::now();
+        let mut index_hits = 0;
+        for _ in 0..rounds {
+            for query in queries {
+                let ids = index.search_any(query.split(char::is_whitespace));
+                let res: Vec<&Item> = ids.iter().map(|&x| index.item(x)).collect();
+                index_hits += serde_json::to_vec(&res).unwrap().len();
+            }
+        }
+        let index_query = start.elapsed();
+
+        //Same results, just (possibly) in a different order.
+        assert_eq!(map_hits, index_hits);
+
+        let per_query = (rounds * queries.len()) as u32;
+        println!("{} items, {} terms", index.item_count(), index.term_count());
+        println!("map:   ~{} KiB lookup structure + ~{} KiB items, built in {:?}, {:?} per query",
+                 map_bytes / 1024, map_items / 1024, map_build, map_query / per_query);
+        println!("index: ~{} KiB lookup structure + ~{} KiB items, built in {:?}, {:?} per query",
+                 index_bytes / 1024, index_items / 1024, index_build, index_query / per_query);
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http;
+use axum::middleware::Next;
+use axum::response::Response;
+use tokio::sync::Semaphore;
+
+use crate::auth::{ApiKey, Auth};
+use crate::config::Limits;
+use crate::error::AppError;
+
+//Routes that are never rate limited: whatever polls them (orchestrators, scrapers) does so from one
+//address on a schedule, and shouldn't be locked out by someone else sharing it.
+pub const UNLIMITED_ROUTES: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+//Keeps the bucket map from growing forever with one entry per address ever seen. Once it passes
+//this many, buckets that have refilled completely are dropped; they'd start full again anyway.
+const PRUNE_AT: usize = 10_000;
+
+//How long a client turned away by the concurrency cap is told to wait. Slots free up as soon as any
+//request in flight is answered, so there's no better estimate than "shortly".
+const BUSY_RETRY_AFTER_SECS: u64 = 1;
+
+//A token bucket per client. Each request takes a token, and tokens come back at `per_minute`, up to
+//`burst` saved up. A client out of tokens is told how long until the next one.
+pub struct RateLimiter {
+    per_sec: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    prune_at: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    //None if the limit is turned off (0 per minute).
+    pub fn new(per_minute: u32, burst: u32) -> Option<Self> {
+        (per_minute > 0).then(|| RateLimiter {
+            per_sec: per_minute as f64 / 60.0,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(Buckets { map: HashMap::new(), prune_at: PRUNE_AT }),
+        })
+    }
+
+    //Takes a token for `client`, or returns how long until one is available.
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.map.len() >= buckets.prune_at {
+            buckets.map.retain(|_, x| self.refill(*x, now).tokens < self.burst);
+            buckets.prune_at = (buckets.map.len() * 2).max(PRUNE_AT);
+        }
+        let bucket = buckets.map.entry(client.to_string())
+            .or_insert(Bucket { tokens: self.burst, updated: now });
+        *bucket = self.refill(*bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_sec))
+        }
+    }
+
+    fn refill(&self, bucket: Bucket, now: Instant) -> Bucket {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        Bucket {
+            tokens: (bucket.tokens + elapsed * self.per_sec).min(self.burst),
+            updated: now,
+        }
+    }
+}
+
+//A network given in limits.trusted_proxies: an address, or a CIDR block like "10.0.0.0/8".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Proxy {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Proxy {
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        let bad = || format!("limits.trusted_proxies entry \"{}\" isn't an address or CIDR block", entry);
+        let (address, prefix) = match entry.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u8>().map_err(|_| bad())?)),
+            None => (entry, None),
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| bad())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return Err(bad());
+        }
+        Ok(Proxy { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        //Dual stack listeners see IPv4 peers as ::ffff:a.b.c.d.
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+//The client a request is put down to, as clients are told apart here and in monitor.rs. Set by
+//identify.
+#[derive(Debug, Clone)]
+pub struct Client(pub String);
+
+//The client a request was put down to by identify. Tests (and anything else calling the router
+//directly) have no address, so they all share one.
+pub fn client_of(extensions: &http::Extensions) -> String {
+    extensions.get::<Client>().map(|x| x.0.clone()).unwrap_or_else(|| "unknown".to_string())
+}
+
+//Middleware working out who a request is from, for the rate limits and the scan monitor. That's
+//the address it came from, unless that's one of the trusted proxies: then it's the last address in
+//X-Forwarded-For that isn't one. Entries further left than that were written by the client itself,
+//so can't be believed.
+pub async fn identify(State(limiter): State<Arc<Limiter>>, mut request: Request, next: Next) -> Response {
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let client = limiter.client(peer.ip(), request.headers());
+        request.extensions_mut().insert(Client(client.to_string()));
+    }
+    next.run(request).await
+}
+
+//Everything that limits what a client can ask of the server, built from the [limits] config.
+//Anything set to 0 there is off.
+pub struct Limiter {
+    per_ip: Option<RateLimiter>,
+    per_key: Option<RateLimiter>,
+    concurrency: Option<Semaphore>,
+    timeout: Option<Duration>,
+    max_body_bytes: usize,
+    trusted_proxies: Vec<Proxy>,
+}
+
+impl Limiter {
+    pub fn new(limits: &Limits) -> Self {
+        Limiter {
+            per_ip: RateLimiter::new(limits.ip_requests_per_minute, limits.ip_burst),
+            per_key: RateLimiter::new(limits.key_requests_per_minute, limits.key_burst),
+            concurrency: (limits.max_concurrent_requests > 0).then(|| Semaphore::new(limits.max_concurrent_requests)),
+            timeout: (limits.request_timeout_secs > 0).then(|| Duration::from_secs(limits.request_timeout_secs)),
+            max_body_bytes: limits.max_body_bytes,
+            trusted_proxies: limits.trusted_proxies.iter()
+                .map(|x| Proxy::parse(x).expect("Trusted proxies should have been checked by Config::validate."))
+                .collect(),
+        }
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|x| x.contains(ip))
+    }
+
+    //See identify.
+    fn client(&self, peer: IpAddr, headers: &http::HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        if !self.trusts(client) {
+            return client;
+        }
+        //Every X-Forwarded-For header, in order, as one list.
+        let forwarded: Vec<&str> = headers.get_all("x-forwarded-for").iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .map(str::trim)
+            .collect();
+        for entry in forwarded.into_iter().rev() {
+            match entry.parse::<IpAddr>() {
+                Ok(x) => client = x.to_canonical(),
+                Err(_) => break,
+            }
+            if !self.trusts(client) {
+                break;
+            }
+        }
+        client
+    }
+
+    //Also applied to the body extractors (see router in main), for bodies sent without a length.
+    pub fn max_body_bytes(&self) -> Option<usize> {
+        (self.max_body_bytes > 0).then_some(self.max_body_bytes)
+    }
+
+    //Requests with a valid API key are counted against the key, everything else against the address
+    //it came from. A key that doesn't check out counts against the address too, so making up keys
+    //doesn't get anyone a fresh bucket.
+    fn rate_limit(&self, key: Option<&ApiKey>, client: &str) -> Result<(), AppError> {
+        let now = Instant::now();
+        let res = match (key, &self.per_key, &self.per_ip) {
+            (Some(key), Some(limiter), _) => limiter.check(&format!("key:{}", key.id), now),
+            (Some(_), None, _) => Ok(()),
+            (None, _, Some(limiter)) => limiter.check(&format!("ip:{}", client), now),
+            (None, _, None) => Ok(()),
+        };
+        res.map_err(|wait| AppError::TooManyRequests {
+            message: "Too many requests, slow down.".to_string(),
+            retry_after_secs: wait.as_secs_f64().ceil().max(1.0) as u64,
+        })
+    }
+}
+
+//Middleware applying every limit: body size (by Content-Length), rate, concurrency, then the timeout.
+//The key (if any) is looked up here and left in the request extensions, so auth::require doesn't
+//have to look it up again.
+//Only the time until the response starts counts against the timeout and the concurrency cap, so
+//long streams like /events aren't cut off and don't hold a slot.
+pub async fn enforce(
+    State((limiter, auth)): State<(Arc<Limiter>, Auth)>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if let Some(max) = limiter.max_body_bytes() {
+        let length = request.headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse::<u64>().ok());
+        if length.is_some_and(|x| x > max as u64) {
+            return Err(AppError::PayloadTooLarge(format!("Request bodies can be at most {} bytes.", max)));
+        }
+    }
+
+    let exempt = request.extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|x| UNLIMITED_ROUTES.contains(&x.as_str()));
+    if !exempt {
+        let key = auth.authenticate(request.headers()).await.ok().flatten();
+        let client = client_of(request.extensions());
+        limiter.rate_limit(key.as_ref(), &client)?;
+        if let Some(key) = key {
+            request.extensions_mut().insert(key);
+        }
+    }
+
+    let _permit = match &limiter.concurrency {
+        Some(x) => Some(x.try_acquire().map_err(|_| AppError::Unavailable {
+            message: "The server is handling too many requests, try again shortly.".to_string(),
+            retry_after_secs: BUSY_RETRY_AFTER_SECS,
+        })?),
+        None => None,
+    };
+    match limiter.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, next.run(request)).await
+            .map_err(|_| AppError::Timeout(format!("The request took longer than {}s.", timeout.as_secs()))),
+        None => Ok(next.run(request).await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use axum::{body::Body, http, middleware, Router, routing::{get, post}};
+    use axum::http::Request;
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    use crate::auth::{Auth, MemoryKeys};
+    use crate::config::Limits;
+    use crate::limits::{enforce, Limiter, Proxy, RateLimiter};
+
+    #[test]
+    fn test_token_bucket() {
+        //One a second, three saved up.
+        let limiter = RateLimiter::new(60, 3).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check("a", start).is_ok());
+        }
+        let wait = limiter.check("a", start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        //Other clients have their own bucket.
+        assert!(limiter.check("b", start).is_ok());
+
+        assert!(limiter.check("a", start + Duration::from_millis(500)).is_err());
+        assert!(limiter.check("a", start + Duration::from_millis(1000)).is_ok());
+        //Never refills past the burst.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check("a", later).is_ok());
+        }
+        assert!(limiter.check("a", later).is_err());
+
+        assert!(RateLimiter::new(0, 3).is_none());
+    }
+
+    //`/wait` only answers once `release` is notified.
+    fn app(limits: Limits, release: Arc<Notify>) -> Router {
+        let auth = Auth::new(Arc::new(MemoryKeys::new()), true);
+        Router::new()
+            .route("/fast", get(|| async { "done" }))
+            .route("/wait", get(move || async move {
+                release.notified().await;
+                "done"
+            }))
+            .route("/upload", post(|body: String| async move { body.len().to_string() }))
+            .route("/healthz", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state((Arc::new(Limiter::new(&limits)), auth), enforce))
+    }
+
+    fn off() -> Limits {
+        Limits {
+            ip_requests_per_minute: 0,
+            key_requests_per_minute: 0,
+            max_concurrent_requests: 0,
+            request_timeout_secs: 0,
+            max_body_bytes: 0,
+            ..Limits::default()
+        }
+    }
+
+    fn get_request(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    async fn status(app: &Router, request: Request<Body>) -> http::StatusCode {
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let app = app(Limits { ip_requests_per_minute: 1, ip_burst: 2, ..off() }, Arc::new(Notify::new()));
+        for _ in 0..2 {
+            assert_eq!(status(&app, get_request("/fast")).await, http::StatusCode::OK);
+        }
+        let response = app.clone().oneshot(get_request("/fast")).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "60");
+        //Health checks aren't counted.
+        assert_eq!(status(&app, get_request("/healthz")).await, http::StatusCode::OK);
+        //Neither is a made up key a way around the limit.
+        let request = Request::get("/fast").header(http::header::AUTHORIZATION, "Bearer mm_nope").body(Body::empty()).unwrap();
+        assert_eq!(status(&app, request).await, http::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let app = app(Limits { max_body_bytes: 8, ..off() }, Arc::new(Notify::new()));
+        let request = Request::post("/upload").body(Body::from("short")).unwrap();
+        assert_eq!(status(&app, request).await, http::StatusCode::OK);
+        let request = Request::post("/upload").header(http::header::CONTENT_LENGTH, "9").body(Body::from("too long!")).unwrap();
+        assert_eq!(status(&app, request).await, http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_cap() {
+        let release = Arc::new(Notify::new());
+        let app = app(Limits { max_concurrent_requests: 1, ..off() }, Arc::clone(&release));
+        let waiting = tokio::spawn(app.clone().oneshot(get_request("/wait")));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = app.clone().oneshot(get_request("/fast")).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "1");
+
+        release.notify_one();
+        assert_eq!(waiting.await.unwrap().unwrap().status(), http::StatusCode::OK);
+        assert_eq!(status(&app, get_request("/fast")).await, http::StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout() {
+        let app = app(Limits { request_timeout_secs: 1, ..off() }, Arc::new(Notify::new()));
+        let response = app.oneshot(get_request("/wait")).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let limiter = Limiter::new(&Limits {
+            trusted_proxies: vec!["10.0.0.0/8".to_string(), "::1".to_string()],
+            ..off()
+        });
+        let client = |peer: &str, forwarded: &[&str]| {
+            let mut headers = http::HeaderMap::new();
+            for x in forwarded {
+                headers.append("x-forwarded-for", x.parse().unwrap());
+            }
+            limiter.client(peer.parse().unwrap(), &headers).to_string()
+        };
+
+        //Anyone else can claim to forward for whoever they like.
+        assert_eq!(client("203.0.113.9", &["198.51.100.1"]), "203.0.113.9");
+        assert_eq!(client("10.1.2.3", &[]), "10.1.2.3");
+        assert_eq!(client("10.1.2.3", &["198.51.100.1"]), "198.51.100.1");
+        assert_eq!(client("::ffff:10.1.2.3", &["198.51.100.1"]), "198.51.100.1");
+        //Only the entries our own proxies added count, not whatever the client sent along.
+        assert_eq!(client("::1", &["192.0.2.7, 198.51.100.1", "10.0.0.2"]), "198.51.100.1");
+        assert_eq!(client("10.1.2.3", &["10.0.0.2, 10.0.0.3"]), "10.0.0.2");
+        assert_eq!(client("10.1.2.3", &["198.51.100.1, junk"]), "10.1.2.3");
+
+        assert!(Proxy::parse("10.0.0.0/33").is_err());
+        assert!(Proxy::parse("fd00::/8").is_ok());
+        assert!(Proxy::parse("proxy.internal").is_err());
+    }
+}
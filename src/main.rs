@@ -1,46 +1,91 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-
-use axum::{extract::{Path, State}, extract, http, response, Router, routing::get};
-use axum::extract::MatchedPath;
-use axum::http::{Request};
-use serde_json::{json, Value};
-use tower_http::{classify::ServerErrorsFailureClass, trace::TraceLayer};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{extract::{Path, Query, State}, http, middleware, response, Router, routing::{delete, get, post, MethodRouter}};
+use axum::extract::{DefaultBodyLimit, FromRef, MatchedPath};
+use axum::extract::rejection::{PathRejection, QueryRejection};
+use axum::http::Request;
+use clap::Parser;
+use serde::Deserialize;
+use tower_http::{catch_panic::CatchPanicLayer, classify::ServerErrorsFailureClass, cors::CorsLayer, trace::TraceLayer};
+use tower_http::compression::CompressionLayer;
 use tracing::{info_span, Span};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_panic::panic_hook;
-use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{EnvFilter, fmt, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa::IntoParams;
 
-use crate::menu::{add_json_to_db, get_map, Item};
+use crate::auth::{Auth, MemoryKeys, NewApiKey, Scope, SharedKeys};
+use crate::cache::CachePolicy;
+use crate::config::{Cli, Config, LogFormat};
+use crate::events::{EventBus, EventStore};
+use crate::graphql::CatalogSchema;
+use crate::error::{AppError, ErrorBody};
+use crate::health::Readiness;
+use crate::index::SharedIndex;
+use crate::limits::Limiter;
+use crate::menu::{seed_from_dir, Item};
+use crate::metrics::{MeteredStore, Metrics};
+use crate::monitor::ScanMonitor;
+use crate::webhooks::{Dispatcher, MemoryWebhooks, RetryPolicy, SharedWebhooks, TargetPolicy};
+use crate::store::{with_store, MemoryStore, SharedStore, SqliteStore};
+use crate::stream::Format;
 
+mod auth;
+mod cache;
+mod config;
+mod cors;
+mod db;
+mod error;
+mod events;
+mod graphql;
+mod health;
+mod index;
+mod limits;
 mod menu;
-
-//Declaring where the database is, instead of determining and passing along like in Android/Crux,
-//is so much easier it feels wrong. ;_;
-static PATH: &str = "res/menu_db.sqlite";
+mod metrics;
+mod monitor;
+mod openapi;
+mod request_id;
+mod search;
+mod shutdown;
+mod store;
+mod stream;
+mod sync;
+mod tls;
+mod versions;
+mod webhooks;
 
 //App itself should just read the json responses; allows adding fields on this (server) side without
-//needing to update the app. However, that could complicate caching responses.
+//needing to update the app. However, that could complicate caching responses, which is why the ETags
+//include the server version (see cache.rs).
 
 //State struct to have shared state across router functions.
 //Allows local (app) access to the HashMap.
-//Wrapped in an atomic reference counted read-write lock to allow async/multithreaded access.
-//There are a couple aof points of uncertainty regarding how/when references are added/dropped, so
-//I'm slightly concerned this may break if running and being used for a while... it's probably fine.
+//The map used to sit behind an Arc<RwLock<>>, which meant a rebuild blocked every reader and a panic
+//while holding the lock poisoned search for everyone. It's now an immutable snapshot that gets
+//swapped out atomically; see index.rs.
 //Using the default example name because names are hard.
 //Can't say this is my favorite pattern.
-#[derive(Clone)]
+//FromRef lets handlers extract just the part they need, e.g. State<SharedIndex>.
+#[derive(Clone, FromRef)]
 struct AppState {
-    map: Arc<RwLock<HashMap<String, HashSet<Arc<Item>>>>>,
-}
-
-//Initial setup, could/should implement something to avoid this going forward.
-fn _db_load() {
-    add_json_to_db(PATH, "res/bateau_04-11.json").unwrap();
-    add_json_to_db(PATH, "res/canlis_06-03.json").unwrap();
-    add_json_to_db(PATH, "res/lark_06-03.json").unwrap();
-    add_json_to_db(PATH, "res/westward_05-16.json").unwrap();
+    index: SharedIndex,
+    metrics: Arc<Metrics>,
+    store: SharedStore,
+    readiness: Readiness,
+    cache: CachePolicy,
+    events: EventBus,
+    webhooks: SharedWebhooks,
+    webhook_targets: TargetPolicy,
+    auth: Auth,
+    keys: SharedKeys,
+    limits: Arc<Limiter>,
+    monitor: Arc<ScanMonitor>,
+    //None when no origins are configured.
+    cors: Option<CorsLayer>,
+    compression: bool,
+    graphql: CatalogSchema,
 }
 
 // Ultra basic server setup (give or take the Arc<> stuff); we don't really need much beyond a basic
@@ -49,27 +94,75 @@ fn _db_load() {
 //TODO: add a post end point to upload JSON formatted menus or individual items
 #[tokio::main]
 async fn main() {
+    //Declaring where the database is used to be a static, which was so much easier than determining
+    //and passing it along like in Android/Crux that it felt wrong. Now it all comes from config.rs.
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Err(problems) = config.validate() {
+        for problem in problems {
+            eprintln!("Invalid configuration: {}", problem);
+        }
+        std::process::exit(2);
+    }
+    if cli.check_config {
+        print!("{}", config.to_toml());
+        println!("# Configuration is valid.");
+        return;
+    }
+    //Bootstrapping: the key admin routes need an admin key, so the first one has to come from here.
+    if let Some(name) = &cli.create_admin_key {
+        if config.features.in_memory {
+            eprintln!("--create-admin-key needs a database; keys in memory are gone once this exits.");
+            std::process::exit(2);
+        }
+        let store = open_database(&config.db_path);
+        match auth::issue(&store, &NewApiKey { name: name.clone(), scope: Scope::Admin }) {
+            Ok((key, secret)) => {
+                eprintln!("Created admin key {} (\"{}\"). It won't be shown again:", key.id, key.name);
+                println!("{}", secret);
+            }
+            Err(e) => {
+                eprintln!("Couldn't create a key: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     //Sets up a rolling log file.
     //There's a *lot* of components to the tracing logger, and they all had their own documentation,
     //but almost no clear examples as to how they fit together.
     let file_appender = RollingFileAppender::builder()
         .rotation(Rotation::DAILY)
         .filename_prefix("menu_manager.log")
-        .build("res/")
+        .build(&config.log_dir)
         .expect("Log file should have been created. Check file paths.");
 
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
 
     //Set up the logging format layer.
     //By default, ANSI escape characters are included that are illegible in a text reader.
-    let fmt_layer = fmt::layer()
-        // .pretty()
-        .with_ansi(false)
-        .with_writer(non_blocking);
-    //Set up the filter layer. Attempts to use the RUST_LOG env level, if it exists.
-    let filter_layer = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("trace"))
-        .unwrap();
+    //The JSON and text layers are different types, hence the boxing.
+    let fmt_layer = match config.log_format {
+        LogFormat::Text => fmt::layer()
+            // .pretty()
+            .with_ansi(false)
+            .with_writer(non_blocking)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_writer(non_blocking)
+            .boxed(),
+    };
+    //Set up the filter layer. RUST_LOG is picked up as part of the config.
+    let filter_layer = EnvFilter::try_new(&config.log_level)
+        .expect("Log level should have been checked by Config::validate.");
 
     tracing_subscriber::registry()
         .with(filter_layer)
@@ -79,13 +172,172 @@ async fn main() {
     //Logs panics
     std::panic::set_hook(Box::new(panic_hook));
 
-    menu::ensure_db(PATH).expect("Database should have been created. Check for permissions.");
-    _db_load();
+    let metrics = Arc::new(Metrics::new());
+
+    //The in-memory store is handy for demos and poking at the API without touching the database.
+    //Webhooks and API keys are kept in the same database as the items.
+    let (store, webhooks, keys): (SharedStore, SharedWebhooks, SharedKeys) = if config.features.in_memory {
+        tracing::info!("Using an in-memory store; nothing will be saved.");
+        (Arc::new(MemoryStore::new()), Arc::new(MemoryWebhooks::new()), Arc::new(MemoryKeys::new()))
+    } else {
+        let store = open_database(&config.db_path);
+        let store = Arc::new(if config.features.index_snapshot { store } else { store.without_snapshot() });
+        (store.clone(), store.clone(), store)
+    };
+    //Every store operation is timed for /metrics.
+    let store: SharedStore = Arc::new(MeteredStore::new(store, Arc::clone(&metrics)));
+    //And every change made through it is published for /events.
+    let events = EventBus::new();
+    let store: SharedStore = Arc::new(EventStore::new(store, events.clone()));
 
+    //The GraphQL schema reads price history through the same wrapped store.
+    let graphql = graphql::schema(Arc::clone(&store), &config.limits);
+
+    //Seeding and building the index happen after we start listening, so /healthz and /readyz can
+    //answer (with "not ready") while they run. The schema itself is set up in SqliteStore::open,
+    //before we listen at all, so there's nothing to report during that.
     let state = AppState {
-        map: Arc::new(RwLock::new(get_map(PATH))),
+        index: SharedIndex::empty(),
+        metrics,
+        store,
+        readiness: Readiness::new(),
+        //Responses that need a key shouldn't sit in shared caches.
+        cache: if config.features.anonymous_read {
+            CachePolicy::new(config.cache_max_age_secs)
+        } else {
+            CachePolicy::new(config.cache_max_age_secs).private()
+        },
+        events,
+        webhooks,
+        webhook_targets: TargetPolicy::new(&config.webhooks),
+        auth: Auth::new(Arc::clone(&keys), config.features.anonymous_read),
+        keys,
+        limits: Arc::new(Limiter::new(&config.limits)),
+        monitor: Arc::new(ScanMonitor::new(config.monitor.clone(), config.features.block_scanners)),
+        cors: cors::layer(&config.cors).expect("CORS settings should have been checked by Config::validate."),
+        compression: config.features.compression,
+        graphql,
     };
+    //Subscribed before startup runs, so webhooks hear about the seed imports too.
+    Dispatcher::new(Arc::clone(&state.webhooks), RetryPolicy::default(), &config.webhooks).spawn(&state.events);
+    tokio::spawn(startup(state.clone(), config.clone()));
+    sync::spawn_log_trimmer(Arc::clone(&state.store), config.sync_log_retention);
+
+    let app = router(state.clone());
+
+    //HTTPS if a certificate is configured, plain HTTP otherwise.
+    let tls = match config.tls.paths() {
+        Some((cert, key)) => match tls::load(cert, key).await {
+            Ok(x) => {
+                tls::reload_on_sighup(x.clone(), cert.to_string(), key.to_string());
+                Some(x)
+            }
+            Err(e) => {
+                tracing::error!("Couldn't load the TLS certificate: {}", e);
+                eprintln!("Couldn't load the TLS certificate from {} and {}: {}", cert, key, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Run our application as a hyper server on the configured address (http://localhost:3000 by default).
+    //On SIGINT/SIGTERM it stops accepting connections and gives in-flight requests a while to finish.
+    let listener = tokio::net::TcpListener::bind(config.bind).await.unwrap();
+    tracing::debug!("listening on {} ({})", listener.local_addr().unwrap(), if tls.is_some() { "https" } else { "http" });
+    let deadline = Duration::from_secs(config.shutdown_timeout_secs);
+    //Open /events streams never finish on their own, so they're closed as soon as shutdown starts.
+    let events = state.events.clone();
+    let shutdown = async move {
+        shutdown::signal().await;
+        events.close();
+    };
+    let served = match tls {
+        Some(tls) => shutdown::serve_tls(listener, app, tls, shutdown, deadline).await,
+        None => shutdown::serve(listener, app, shutdown, deadline).await,
+    };
+    if let Err(e) = served {
+        tracing::error!("Server error: {}", e);
+    }
+
+    if let Err(e) = with_store(&state.store, |store| store.checkpoint()).await {
+        tracing::error!("Couldn't checkpoint the database: {}", e);
+    }
+    tracing::info!("Shutdown complete.");
+    //Dropping the guard flushes whatever the non-blocking writer still has buffered to the log file.
+    drop(guard);
+}
+
+//Opens (creating or migrating as needed) the database, or exits if it can't be used: a file we can't
+//create, a migration that failed, or a schema from a newer build.
+fn open_database(path: &str) -> SqliteStore {
+    match SqliteStore::open(path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("Couldn't open the database at {}: {}", path, e);
+            eprintln!("Couldn't open the database at {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+//Loads the seed menus, then builds the index from the store (through the same path a reload uses).
+//If either fails the server stays up but never reports ready, so whatever supervises it can tell.
+async fn startup(state: AppState, config: Config) {
+    let _pending = state.readiness.begin("startup");
+
+    //Initial setup, could/should implement something to avoid this going forward.
+    if config.features.seed {
+        let dir = config.seed_dir.clone();
+        match with_store(&state.store, move |store| seed_from_dir(store, &dir)).await {
+            Ok(imports) => {
+                state.metrics.add_menu_imports(imports.len());
+                for import in &imports {
+                    state.events.publish_import(import);
+                }
+                tracing::info!("Loaded {} seed menus from {}.", imports.len(), config.seed_dir);
+            }
+            Err(e) => {
+                tracing::error!("Couldn't load seed menus from {}: {}", config.seed_dir, e);
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    if let Err(e) = state.index.rebuild(&state.store).await {
+        tracing::error!("Couldn't build the index: {}", e);
+        std::future::pending::<()>().await;
+    }
+    tracing::info!("Startup complete, ready for traffic.");
+}
 
+//Builds the router with all of its routes and middleware.
+//Split out of main so tests can drive it without binding a socket.
+//Routes are grouped by the API key scope they need (see auth.rs). route_layer only runs for requests
+//that matched one of the group's routes, so unknown paths still get the fallback's 404.
+//The groups make up the versioned API, mounted under /v1 and, deprecated, at the old unversioned
+//paths (see versions.rs). Metrics, health checks and docs aren't versioned.
+fn router(state: AppState) -> Router {
+    let read = add_routes(Router::new(), read_routes())
+        .route_layer(middleware::from_fn_with_state((state.auth.clone(), Scope::Read), auth::require));
+    let read_v1 = add_routes(Router::new(), read_v1_routes())
+        .route_layer(middleware::from_fn_with_state((state.auth.clone(), Scope::Read), auth::require));
+    let admin = add_routes(Router::new(), admin_routes())
+        .route_layer(middleware::from_fn_with_state((state.auth.clone(), Scope::Admin), auth::require));
+
+    //The routes from before /v1, which also stay at their old unversioned paths.
+    let unversioned = Router::new()
+        .merge(read)
+        .merge(admin);
+    let v1 = unversioned.clone()
+        .merge(read_v1);
+
+    let cors = state.cors.clone();
+    //Negotiated from Accept-Encoding. Small bodies, images and /events aren't compressed.
+    let compression = CompressionLayer::new()
+        .gzip(state.compression)
+        .br(state.compression)
+        .zstd(state.compression);
     let app = Router::new()
         .fallback(
             fallback
@@ -93,29 +345,56 @@ async fn main() {
         // .route("/",
         //        get(|| async { "Hello, World!" }),
         // )
-        .route("/query/:input",
-               get(query),
-        )
+        .nest(versions::CURRENT, v1)
+        .merge(unversioned.layer(middleware::from_fn(versions::deprecated)));
+    let app = add_routes(app, open_routes())
+        //Layers wrap everything added before them, so the last one here sees the request first.
+        //Rate, size, time and concurrency limits; see limits.rs. Inside the metrics and error layers
+        //so rejections are counted and get a request id like any other error.
+        .layer(match state.limits.max_body_bytes() {
+            Some(x) => DefaultBodyLimit::max(x),
+            None => DefaultBodyLimit::disable(),
+        })
+        .layer(middleware::from_fn_with_state((Arc::clone(&state.limits), state.auth.clone()), limits::enforce))
+        //Blocked scanners are turned away before anything else is spent on them.
+        .layer(middleware::from_fn_with_state((Arc::clone(&state.monitor), state.auth.clone()), monitor::reject_blocked))
+        //Works out who the request is from, for the two above.
+        .layer(middleware::from_fn_with_state(Arc::clone(&state.limits), limits::identify))
+        //Panics become plain 500s instead of dropping the connection.
+        .layer(CatchPanicLayer::custom(error::panic_response))
+        .layer(middleware::from_fn_with_state(Arc::clone(&state.metrics), metrics::track_requests))
         .layer(
             TraceLayer::new_for_http()
+                //Everything about a request ends up on this span, so the one "finished" line tells
+                //the whole story. Handlers fill in the search fields (see query).
                 .make_span_with(|request: &Request<_>| {
                     let matched_path = request
                         .extensions()
                         .get::<MatchedPath>()
                         .map(MatchedPath::as_str);
+                    let request_id = request_id::of(request.headers());
 
                     info_span!(
                         "http_request",
                         method = ?request.method(),
                         matched_path,
-                        some_other_field = tracing::field::Empty,
+                        path = request.uri().path(),
+                        request_id,
+                        status = tracing::field::Empty,
+                        latency_ms = tracing::field::Empty,
+                        terms = tracing::field::Empty,
+                        results = tracing::field::Empty,
                     )
                 })
                 .on_request(|request: &Request<_>, _span: &Span| {
                     tracing::debug!("started {} {}", request.method(), request.uri().path())
                 })
+                .on_response(|response: &response::Response, latency: Duration, span: &Span| {
+                    span.record("status", response.status().as_u16());
+                    span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+                    tracing::info!("finished")
+                })
                 //Not currently concerned with these options, but they exist.
-                .on_response(())
                 .on_body_chunk(())
                 .on_eos(())
                 //
@@ -123,42 +402,668 @@ async fn main() {
                     tracing::error!("something went wrong: {}", error)
                 }),
         )
+        //Copies the request id onto the response, so clients can quote it back to us.
+        .layer(request_id::propagate())
+        //Fills in the request id on error bodies.
+        .layer(middleware::from_fn(error::render_errors))
+        //Keeps the client's x-request-id if it sent one, otherwise generates a UUID.
+        .layer(request_id::set())
+        //Outside render_errors, which rewrites error bodies and would undo it.
+        .layer(compression)
         .with_state(state);
+    //Outermost, so preflights are answered before anything else runs and every response, errors
+    //included, carries the CORS headers a browser needs to read it.
+    match cors {
+        Some(x) => app.layer(x),
+        None => app,
+    }
+}
+
+//The routes in each of router()'s groups, as path and handlers. Lists rather than .route() chains,
+//so the OpenAPI test can check every one of them is documented.
+type Routes = Vec<(&'static str, MethodRouter<AppState>)>;
+
+fn add_routes(router: Router<AppState>, routes: Routes) -> Router<AppState> {
+    routes.into_iter().fold(router, |router, (path, handlers)| router.route(path, handlers))
+}
+
+fn read_routes() -> Routes {
+    vec![
+        ("/query/:input", get(query)),
+        ("/events", get(events::events)),
+        ("/sync", get(sync::sync)),
+        ("/export", get(stream::export)),
+    ]
+}
+
+//Added with /v1, so they never had unversioned paths.
+fn read_v1_routes() -> Routes {
+    vec![
+        ("/search", post(search::search)),
+        ("/graphql", get(graphql::explorer).post(graphql::graphql)),
+    ]
+}
+
+fn admin_routes() -> Routes {
+    vec![
+        ("/webhooks", get(webhooks::list_webhooks).post(webhooks::create_webhook)),
+        ("/webhooks/:id", delete(webhooks::delete_webhook)),
+        ("/webhooks/:id/deliveries", get(webhooks::webhook_deliveries)),
+        ("/keys", get(auth::list_keys).post(auth::issue_key)),
+        ("/keys/:id", delete(auth::revoke_key)),
+        ("/monitor", get(monitor::monitor_summary)),
+        ("/monitor/blocks/:client", delete(monitor::unblock_client)),
+    ]
+}
 
-    // Run our application as a hyper server on http://localhost:3000.
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+//Unversioned and without an API key. Metrics and the health checks stay open for scrapers and
+//orchestrators; the API description and docs are public, like the API itself is by default.
+fn open_routes() -> Routes {
+    vec![
+        ("/metrics", get(metrics::serve_metrics)),
+        ("/healthz", get(health::healthz)),
+        ("/readyz", get(health::readyz)),
+        ("/openapi.json", get(openapi::openapi_json)),
+        ("/docs", get(openapi::docs)),
+        ("/docs/:file", get(openapi::docs_asset)),
+    ]
+}
+
+//Optional query string parameters for the query route.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct QueryOptions {
+    //Only items with every term, rather than any of them.
+    #[serde(default)]
+    all: bool,
+    format: Option<Format>,
 }
 
 //Simple handle that takes an input string, splits it into tokens by whitespace, and returns a JSON
-//array of all the items found that include the passed tokens.
-//Slightly worried about accessing the item via pointer, then cloning, and if that impacts the Arc.
+//array of all the items found that include any of the passed tokens.
+//Passing `?all=true` only returns items that include every token instead.
+//`?format=ndjson` (or `Accept: application/x-ndjson`) streams the items a line each instead of one
+//array, for big result sets; see stream.rs.
+//Grabs the current index snapshot once, so a rebuild mid-request can't mix two versions.
+//The response is serialized straight from the snapshot's item storage rather than cloning items.
+//Extractor rejections are taken as Results so bad input comes back as our JSON error, not axum's text.
+//Tagged with the snapshot's version, so a client revalidating an unchanged catalog gets a 304 without
+//us searching at all (see cache.rs).
+#[utoipa::path(
+    get,
+    path = "/query/{input}",
+    tag = "search",
+    summary = "Search the catalog",
+    description = "Items whose name or ingredients include any of the whitespace separated terms \
+        (or all of them, with `all=true`). Streamed as NDJSON with `format=ndjson` or \
+        `Accept: application/x-ndjson`.",
+    params(("input" = String, Path, description = "Search terms, separated by whitespace"), QueryOptions),
+    responses(
+        (status = 200, description = "The matching items", content(
+            (Vec<Item> = "application/json"),
+            (Item = "application/x-ndjson"),
+        ), headers(("etag" = String))),
+        (status = 304, description = "Unchanged since the ETag in If-None-Match"),
+        (status = 400, description = "Bad query string", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = [])),
+)]
 async fn query(
-    Path(mut input): Path<String>,
+    input: Result<Path<String>, PathRejection>,
+    options: Result<Query<QueryOptions>, QueryRejection>,
+    headers: http::HeaderMap,
     State(state): State<AppState>,
-) -> extract::Json<Value> {
-    let mut res: HashSet<Item> = HashSet::new();
-
-    input.retain(|x| x.is_alphabetic() || x.is_whitespace());
-    for i in input.split(char::is_whitespace) {
-        if let Some(x) = state.map.read()
-            .expect("State HashMap should be available at this point.")
-            .get(i) {
-            for item in x {
-                res.insert((**item).clone());
-            }
-        }
+) -> Result<response::Response, AppError> {
+    let Path(mut input) = input?;
+    let Query(options) = options?;
+    let index = state.index.snapshot();
+    let format = Format::negotiate(options.format, &headers);
+    let etag = CachePolicy::etag(index.database(), index.version(), format.as_str());
+
+    let mut response = state.cache.respond(&headers, etag, || {
+        input.retain(|x| x.is_alphabetic() || x.is_whitespace());
+        //split_whitespace, so punctuation dropped between spaces doesn't leave an empty term behind
+        //(which matches nothing, and so would empty every ?all=true search). Normalized like the
+        //indexed words, so "Oyster" finds "oyster".
+        let terms: Vec<String> = input.split_whitespace().map(menu::normalize).collect();
+        let terms = terms.iter().map(String::as_str);
+        let ids = if options.all { index.search_all(terms.clone()) } else { index.search_any(terms.clone()) };
+
+        //Recorded on the http_request span from the TraceLayer.
+        let span = Span::current();
+        span.record("terms", terms.count());
+        span.record("results", ids.len());
+        state.metrics.observe_search(ids.len());
+
+        Ok(format.respond(Arc::clone(&index), ids))
+    })?;
+    //Without ?format= the Accept header picked it, so caches have to key on that too.
+    if options.format.is_none() {
+        response.headers_mut().append(http::header::VARY, http::HeaderValue::from_static("accept"));
     }
-    json!(res).into()
+    Ok(response)
 }
 
 //Handler for calls to undefined routes.
-//In real world situations, I feel like this would be important to monitor for security reasons.
+//Every one is recorded by the scan monitor (see monitor.rs). Plain misses are usually typos or stale
+//clients, so only the ones that look like scanning are logged at warn.
 async fn fallback(
-    uri: http::Uri
-) -> impl response::IntoResponse {
-    //Is warn too high?
-    tracing::warn!("Undefined route called: {}", uri);
-    (http::StatusCode::NOT_FOUND, format!("No route {}", uri))
-}
\ No newline at end of file
+    uri: http::Uri,
+    extensions: http::Extensions,
+    State(monitor): State<Arc<ScanMonitor>>,
+) -> AppError {
+    let client = limits::client_of(&extensions);
+    let verdict = monitor.record(&client, uri.path(), Instant::now());
+    match &verdict.pattern {
+        Some(pattern) => tracing::warn!("Undefined route called by {}, looks like a scan ({}): {}", client, pattern, uri),
+        None => tracing::info!("Undefined route called by {}: {}", client, uri),
+    }
+    if verdict.blocked {
+        tracing::warn!("Blocking {} for too many undefined routes.", client);
+    }
+    AppError::NotFound(format!("No route {}", uri))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{body::Body, http};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use crate::{graphql, router, AppState};
+    use crate::cache::CachePolicy;
+    use crate::auth::{issue, Auth, MemoryKeys, NewApiKey, Scope, SharedKeys};
+    use crate::events::EventBus;
+    use crate::webhooks::{MemoryWebhooks, TargetPolicy};
+    use crate::health::Readiness;
+    use crate::config::{Cors, Limits, Monitor};
+    use crate::index::{Index, SharedIndex};
+    use crate::limits::Limiter;
+    use crate::menu::seed_from_dir;
+    use crate::metrics::Metrics;
+    use crate::monitor::ScanMonitor;
+    use crate::store::{MemoryStore, MenuStore, SharedStore};
+    use crate::versions::{DEPRECATED_SINCE, DEPRECATION_HEADER};
+
+    //State over the sample menus in res/, backed by in-memory stores. Search is open to anonymous
+    //clients.
+    pub(crate) fn test_state() -> AppState {
+        let store = MemoryStore::new();
+        seed_from_dir(&store, "res/").unwrap();
+        let index = SharedIndex::new(Index::build(store.list().unwrap()));
+        let keys: SharedKeys = Arc::new(MemoryKeys::new());
+        let store: SharedStore = Arc::new(store);
+        AppState {
+            index,
+            metrics: Arc::new(Metrics::new()),
+            store: Arc::clone(&store),
+            readiness: Readiness::new(),
+            cache: CachePolicy::new(60),
+            events: EventBus::new(),
+            webhooks: Arc::new(MemoryWebhooks::new()),
+            webhook_targets: TargetPolicy::default(),
+            auth: Auth::new(Arc::clone(&keys), true),
+            keys,
+            limits: Arc::new(Limiter::new(&Limits::default())),
+            monitor: Arc::new(ScanMonitor::new(Monitor::default(), false)),
+            cors: None,
+            compression: true,
+            graphql: graphql::schema(store, &Limits::default()),
+        }
+    }
+
+    pub(crate) fn test_router() -> axum::Router {
+        router(test_state())
+    }
+
+    //Issues a key with the scope straight into the state's key store.
+    pub(crate) fn test_key(state: &AppState, scope: Scope) -> String {
+        issue(state.keys.as_ref(), &NewApiKey { name: "test".to_string(), scope }).unwrap().1
+    }
+
+    //Sends a request with an optional API key and JSON body. Bodies that aren't JSON come back as null.
+    pub(crate) async fn send(
+        app: &axum::Router,
+        method: &str,
+        path: &str,
+        key: Option<&str>,
+        body: &str,
+    ) -> (http::StatusCode, serde_json::Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(http::header::CONTENT_TYPE, "application/json");
+        if let Some(key) = key {
+            request = request.header(http::header::AUTHORIZATION, format!("Bearer {}", key));
+        }
+        let response = app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or_default())
+    }
+
+    async fn get(path: &str) -> (http::StatusCode, http::HeaderMap, serde_json::Value) {
+        let response = test_router().oneshot(Request::get(path).body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let (status, headers, body) = get("/v1/query/aioli").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert!(!body.as_array().unwrap().is_empty());
+        assert!(!headers.contains_key(DEPRECATION_HEADER));
+
+        //Stray spaces and punctuation don't count as terms.
+        let (_, _, all) = get("/v1/query/aioli?all=true").await;
+        assert!(!all.as_array().unwrap().is_empty());
+        assert_eq!(get("/v1/query/%20aioli%20-%20?all=true").await.2, all);
+        //Matched like the index's words, whatever the case.
+        assert_eq!(get("/v1/query/AIOLI?all=true").await.2, all);
+        assert_eq!(get("/v1/query/Aioli").await.2, body);
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let app = test_router();
+        let body = r#"{"text": "aioli / \"house\"", "sort": "relevance", "limit": 5, "options": {"fuzzy": true, "highlight": true}}"#;
+        let (status, json) = send(&app, "POST", "/v1/search", None, body).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert!(json["total"].as_u64().unwrap() > 0);
+        assert_eq!(json["limit"], 5);
+        assert!(json["results"].as_array().unwrap().len() <= 5);
+        assert!(json["results"][0]["score"].as_f64().unwrap() > 0.0);
+        assert!(json["results"][0]["highlights"]["item_name"].is_string());
+
+        let (status, json) = send(&app, "POST", "/v1/search", None, r#"{"limit": 1000}"#).await;
+        assert_eq!(status, http::StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(json["message"].as_str().unwrap().contains("limit"));
+        assert_eq!(send(&app, "POST", "/v1/search", None, "{").await.0, http::StatusCode::BAD_REQUEST);
+        //New in /v1, so there's no unversioned alias.
+        assert_eq!(send(&app, "POST", "/search", None, "{}").await.0, http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_unversioned_aliases() {
+        let (status, headers, body) = get("/query/aioli?all=true").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body, get("/v1/query/aioli?all=true").await.2);
+        assert_eq!(headers[DEPRECATION_HEADER], DEPRECATED_SINCE);
+        assert_eq!(headers[http::header::LINK], "</v1/query/aioli?all=true>; rel=\"successor-version\"");
+
+        //Errors from the aliased routes are marked too, but not the unversioned ops routes or misses.
+        let (status, headers, _) = get("/webhooks").await;
+        assert_eq!(status, http::StatusCode::UNAUTHORIZED);
+        assert!(headers.contains_key(DEPRECATION_HEADER));
+        assert!(!get("/healthz").await.1.contains_key(DEPRECATION_HEADER));
+        assert!(!get("/v1/healthz").await.1.contains_key(DEPRECATION_HEADER));
+        assert_eq!(get("/v1/healthz").await.0, http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_query_revalidation() {
+        let app = test_router();
+        let response = app.clone().oneshot(Request::get("/query/aioli").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CACHE_CONTROL], "public, max-age=60");
+        let etag = response.headers()[http::header::ETAG].clone();
+
+        let response = app.clone()
+            .oneshot(Request::get("/query/aioli").header(http::header::IF_NONE_MATCH, &etag).body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[http::header::ETAG], etag);
+        assert!(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().is_empty());
+
+        let response = app
+            .oneshot(Request::get("/query/aioli").header(http::header::IF_NONE_MATCH, "\"stale\"").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let (status, _, body) = get("/sync").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["reset"], true);
+        assert!(!body["added"].as_array().unwrap().is_empty());
+        assert!(body["added"][0]["id"].is_string());
+        assert!(body["added"][0]["item"]["item_name"].is_string());
+
+        //`get` builds a new state (and database) every time, so its token is from somewhere else.
+        let token = body["token"].as_str().unwrap();
+        let app = test_router();
+        let (status, body) = send(&app, "GET", &format!("/sync?since={}", token), None, "").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["reset"], true);
+
+        let token = body["token"].as_str().unwrap();
+        let (status, body) = send(&app, "GET", &format!("/sync?since={}", token), None, "").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["reset"], false);
+        assert_eq!(body["token"], token);
+        assert!(body["added"].as_array().unwrap().is_empty());
+
+        let (status, _, _) = get("/sync?since=yesterday").await;
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_routes() {
+        let state = test_state();
+        let admin = test_key(&state, Scope::Admin);
+        let app = router(state);
+
+        let (status, body) = send(&app, "POST", "/webhooks", Some(&admin), r#"{"url": "http://93.184.215.14:9/hook", "restaurants": ["Lark"]}"#).await;
+        assert_eq!(status, http::StatusCode::CREATED);
+        assert_eq!(body["secret"].as_str().unwrap().len(), 64);
+        let id = body["id"].as_i64().unwrap();
+
+        let (status, body) = send(&app, "GET", "/webhooks", Some(&admin), "").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body[0]["restaurants"][0], "Lark");
+        assert!(body[0].get("secret").is_none());
+
+        let (status, body) = send(&app, "GET", &format!("/webhooks/{}/deliveries", id), Some(&admin), "").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert!(body.as_array().unwrap().is_empty());
+
+        let (status, body) = send(&app, "POST", "/webhooks", Some(&admin), r#"{"url": "http://93.184.215.14:9/hook", "events": ["lunch"]}"#).await;
+        assert_eq!(status, http::StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "unprocessable");
+        let (status, _) = send(&app, "POST", "/webhooks", Some(&admin), r#"{"url": "ftp://localhost/hook"}"#).await;
+        assert_eq!(status, http::StatusCode::UNPROCESSABLE_ENTITY);
+        //Nothing the server can reach that the rest of the world can't.
+        for url in ["http://localhost:9/hook", "http://127.0.0.1/", "http://10.0.0.1/", "http://169.254.169.254/latest", "http://[::1]/"] {
+            let (status, body) = send(&app, "POST", "/webhooks", Some(&admin), &format!(r#"{{"url": "{}"}}"#, url)).await;
+            assert_eq!(status, http::StatusCode::UNPROCESSABLE_ENTITY, "{}", url);
+            assert!(body["message"].as_str().unwrap().contains("private"), "{}", url);
+        }
+        let (status, _) = send(&app, "POST", "/webhooks", Some(&admin), "{").await;
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(&app, "DELETE", &format!("/webhooks/{}", id), Some(&admin), "").await;
+        assert_eq!(status, http::StatusCode::NO_CONTENT);
+        let (status, _) = send(&app, "DELETE", &format!("/webhooks/{}", id), Some(&admin), "").await;
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+        let (status, _) = send(&app, "GET", &format!("/webhooks/{}/deliveries", id), Some(&admin), "").await;
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_scopes_enforced() {
+        let state = test_state();
+        let reader = test_key(&state, Scope::Read);
+        let admin = test_key(&state, Scope::Admin);
+        let app = router(state.clone());
+
+        assert_eq!(send(&app, "GET", "/query/aioli", None, "").await.0, http::StatusCode::OK);
+        let (status, body) = send(&app, "GET", "/webhooks", None, "").await;
+        assert_eq!(status, http::StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "unauthorized");
+        assert_eq!(send(&app, "GET", "/webhooks", Some(&reader), "").await.0, http::StatusCode::FORBIDDEN);
+        assert_eq!(send(&app, "GET", "/webhooks", Some(&admin), "").await.0, http::StatusCode::OK);
+        //Operational routes never need a key.
+        assert_eq!(send(&app, "GET", "/healthz", None, "").await.0, http::StatusCode::OK);
+
+        //Admins can hand out and take back keys.
+        let (status, body) = send(&app, "POST", "/keys", Some(&admin), r#"{"name": "importer", "scope": "write"}"#).await;
+        assert_eq!(status, http::StatusCode::CREATED);
+        let writer = body["key"].as_str().unwrap().to_string();
+        let id = body["id"].as_i64().unwrap();
+        assert_eq!(send(&app, "GET", "/query/aioli", Some(&writer), "").await.0, http::StatusCode::OK);
+        let (status, body) = send(&app, "GET", "/keys", Some(&admin), "").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert!(body.as_array().unwrap().iter().all(|x| x.get("key").is_none() && x.get("hash").is_none()));
+        assert_eq!(send(&app, "DELETE", &format!("/keys/{}", id), Some(&admin), "").await.0, http::StatusCode::NO_CONTENT);
+        assert_eq!(send(&app, "GET", "/query/aioli", Some(&writer), "").await.0, http::StatusCode::UNAUTHORIZED);
+
+        //With anonymous reads off, search needs a key too.
+        let closed = router(AppState { auth: Auth::new(Arc::clone(&state.keys), false), ..state });
+        assert_eq!(send(&closed, "GET", "/query/aioli", None, "").await.0, http::StatusCode::UNAUTHORIZED);
+        assert_eq!(send(&closed, "GET", "/query/aioli", Some(&reader), "").await.0, http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let state = AppState {
+            limits: Arc::new(Limiter::new(&Limits { max_body_bytes: 64, ip_requests_per_minute: 1, ip_burst: 2, ..Limits::default() })),
+            ..test_state()
+        };
+        let admin = test_key(&state, Scope::Admin);
+        let app = router(state);
+
+        //Bodies without a Content-Length are cut off by the extractor instead.
+        let long = format!(r#"{{"url": "http://localhost:9/{}"}}"#, "a".repeat(64));
+        let (status, body) = send(&app, "POST", "/webhooks", Some(&admin), &long).await;
+        assert_eq!(status, http::StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["code"], "payload_too_large");
+
+        //The admin key has its own bucket, anonymous clients share the one for their address.
+        for _ in 0..2 {
+            assert_eq!(send(&app, "GET", "/query/aioli", None, "").await.0, http::StatusCode::OK);
+        }
+        let (status, body) = send(&app, "GET", "/query/aioli", None, "").await;
+        assert_eq!(status, http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(body["request_id"].is_string());
+        assert_eq!(send(&app, "GET", "/query/aioli", Some(&admin), "").await.0, http::StatusCode::OK);
+        assert_eq!(send(&app, "GET", "/readyz", None, "").await.0, http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let config = Cors { allowed_origins: vec!["https://menus.example.com".to_string()], ..Cors::default() };
+        let app = router(AppState { cors: crate::cors::layer(&config).unwrap(), ..test_state() });
+        let preflight = |origin: &str, method: &str, headers: &str| {
+            Request::builder()
+                .method("OPTIONS")
+                .uri("/webhooks")
+                .header(http::header::ORIGIN, origin)
+                .header(http::header::ACCESS_CONTROL_REQUEST_METHOD, method)
+                .header(http::header::ACCESS_CONTROL_REQUEST_HEADERS, headers)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        //Answered without a key, even though the route itself needs an admin one.
+        let response = app.clone().oneshot(preflight("https://menus.example.com", "POST", "authorization,content-type")).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[http::header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://menus.example.com");
+        assert!(headers[http::header::ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap().contains("POST"));
+        assert!(headers[http::header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap().contains("authorization"));
+        assert_eq!(headers[http::header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let response = app.clone().oneshot(preflight("https://elsewhere.example.com", "POST", "authorization")).await.unwrap();
+        assert!(response.headers().get(http::header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        let response = app.clone()
+            .oneshot(Request::get("/query/aioli").header(http::header::ORIGIN, "https://menus.example.com").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers()[http::header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://menus.example.com");
+        assert!(response.headers()[http::header::ACCESS_CONTROL_EXPOSE_HEADERS].to_str().unwrap().contains("etag"));
+
+        //Errors are readable too.
+        let response = app
+            .oneshot(Request::get("/webhooks").header(http::header::ORIGIN, "https://menus.example.com").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[http::header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://menus.example.com");
+
+        //Off by default.
+        let response = test_router()
+            .oneshot(Request::get("/query/aioli").header(http::header::ORIGIN, "https://menus.example.com").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert!(response.headers().get(http::header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_and_export() {
+        let app = test_router();
+        let (_, json_headers, json) = get("/query/aioli").await;
+        let response = app.clone()
+            .oneshot(Request::get("/query/aioli").header(http::header::ACCEPT, "application/x-ndjson").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "application/x-ndjson");
+        //Same URL, different body, so a different tag, and caches are told Accept matters.
+        assert_ne!(response.headers()[http::header::ETAG], json_headers[http::header::ETAG]);
+        assert!(response.headers()[http::header::ETAG].to_str().unwrap().starts_with("W/"));
+        let varies_on_accept = |headers: &http::HeaderMap| headers.get_all(http::header::VARY).iter().any(|x| x == "accept");
+        assert!(varies_on_accept(response.headers()));
+        assert!(varies_on_accept(&json_headers));
+        assert!(!varies_on_accept(&get("/query/aioli?format=json").await.1));
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(bytes.to_vec()).unwrap()
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(serde_json::Value::Array(lines), json);
+
+        let state = test_state();
+        let count = state.index.snapshot().item_count();
+        let response = router(state).oneshot(Request::get("/export").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(String::from_utf8(bytes.to_vec()).unwrap().lines().count(), count);
+        let (status, _, body) = get("/export?format=json").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), count);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let app = test_router();
+        for encoding in ["gzip", "br", "zstd"] {
+            let response = app.clone()
+                .oneshot(Request::get("/export").header(http::header::ACCEPT_ENCODING, encoding).body(Body::empty()).unwrap())
+                .await.unwrap();
+            assert_eq!(response.headers()[http::header::CONTENT_ENCODING], encoding);
+            assert!(response.headers()[http::header::VARY].to_str().unwrap().contains("accept-encoding"));
+        }
+        //Error bodies too, without render_errors mangling them.
+        let response = app.clone()
+            .oneshot(Request::get(format!("/{}", "missing".repeat(10))).header(http::header::ACCEPT_ENCODING, "gzip").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[http::header::CONTENT_ENCODING], "gzip");
+
+        let response = app.clone().oneshot(Request::get("/export").body(Body::empty()).unwrap()).await.unwrap();
+        assert!(response.headers().get(http::header::CONTENT_ENCODING).is_none());
+        let off = router(AppState { compression: false, ..test_state() });
+        let response = off
+            .oneshot(Request::get("/export").header(http::header::ACCEPT_ENCODING, "gzip").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert!(response.headers().get(http::header::CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bad_query_string() {
+        let (status, _, body) = get("/query/aioli?all=maybe").await;
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "bad_request");
+        assert!(body["request_id"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_request_id_echoed() {
+        let response = test_router()
+            .oneshot(Request::get("/query/aioli").header("x-request-id", "from-client").body(Body::empty()).unwrap())
+            .await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "from-client");
+
+        //Generated when the client doesn't send one, and matches the one in error bodies.
+        let (_, headers, body) = get("/nope").await;
+        let generated = headers["x-request-id"].to_str().unwrap();
+        assert_eq!(generated.len(), 36);
+        assert_eq!(body["request_id"], generated);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let app = test_router();
+        app.clone().oneshot(Request::get("/query/aioli").body(Body::empty()).unwrap()).await.unwrap();
+        app.clone().oneshot(Request::get("/wp-admin").body(Body::empty()).unwrap()).await.unwrap();
+
+        let response = app.oneshot(Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains(r#"menu_manager_http_requests_total{method="GET",route="/query/:input",status="200"} 1"#));
+        assert!(text.contains(r#"menu_manager_http_requests_total{method="GET",route="unmatched",status="404"} 1"#));
+        assert!(text.contains("menu_manager_search_results_count 1"));
+        assert!(!text.contains("wp-admin"));
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let (status, _, body) = get("/healthz").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+
+        let (status, _, body) = get("/readyz").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_until_started() {
+        let state = AppState {
+            index: SharedIndex::empty(),
+            store: Arc::new(MemoryStore::new()),
+            ..test_state()
+        };
+        let app = router(state.clone());
+        let ready = |app: axum::Router| async move {
+            app.oneshot(Request::get("/readyz").body(Body::empty()).unwrap()).await.unwrap().status()
+        };
+
+        assert_eq!(ready(app.clone()).await, http::StatusCode::SERVICE_UNAVAILABLE);
+        let pending = state.readiness.begin("startup");
+        state.index.publish(Index::default());
+        assert_eq!(ready(app.clone()).await, http::StatusCode::SERVICE_UNAVAILABLE);
+        drop(pending);
+        assert_eq!(ready(app).await, http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let (status, _, body) = get("/nope").await;
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "No route /nope");
+    }
+
+    #[tokio::test]
+    async fn test_scanners_blocked() {
+        let state = AppState {
+            monitor: Arc::new(ScanMonitor::new(Monitor { threshold: 2, ..Monitor::default() }, true)),
+            ..test_state()
+        };
+        let admin = test_key(&state, Scope::Admin);
+        let app = router(state);
+
+        assert_eq!(send(&app, "GET", "/.env", None, "").await.0, http::StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, "GET", "/wp-admin/", None, "").await.0, http::StatusCode::NOT_FOUND);
+        let (status, body) = send(&app, "GET", "/query/aioli", None, "").await;
+        assert_eq!(status, http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], "too_many_requests");
+        assert_eq!(send(&app, "GET", "/healthz", None, "").await.0, http::StatusCode::OK);
+
+        //Requests sent straight to the router have no address, so the admin shares the blocked
+        //client here. A real key still gets through, a made up one doesn't.
+        assert_eq!(send(&app, "GET", "/monitor", Some("mm_made_up"), "").await.0, http::StatusCode::TOO_MANY_REQUESTS);
+        let (status, body) = send(&app, "GET", "/monitor", Some(&admin), "").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["clients"][0]["client"], "unknown");
+        assert_eq!(body["clients"][0]["suspicious"], 2);
+        assert_eq!(body["patterns"]["wp-admin"], 1);
+        assert_eq!(send(&app, "DELETE", "/monitor/blocks/unknown", Some(&admin), "").await.0, http::StatusCode::NO_CONTENT);
+        assert_eq!(send(&app, "GET", "/query/aioli", None, "").await.0, http::StatusCode::OK);
+        assert_eq!(send(&app, "DELETE", "/monitor/blocks/unknown", Some(&admin), "").await.0, http::StatusCode::NOT_FOUND);
+    }
+}
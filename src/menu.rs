@@ -1,13 +1,21 @@
 use std::{fmt, fs};
+use std::collections::BTreeSet;
+#[cfg(test)]
 use std::collections::{HashMap, HashSet};
-use std::error::Error;
 use std::fmt::Formatter;
 use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+#[cfg(test)]
 use std::sync::Arc;
 
-use rusqlite::{Connection, params, Result};
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
-use tracing::{trace, warn};
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+use crate::db::DbError;
+use crate::index::Index;
+use crate::store::MenuStore;
 
 //Left as a reference
 // pub(crate) const _WESTWARD_FILE: &[u8] = include_bytes!("../res/westward_05-16.json");
@@ -16,12 +24,18 @@ use tracing::{trace, warn};
 //Struct for menu items. Holds basic details as various fields.
 //There are a number of points I'm not sure how I would like to handle, so I've largely opted for
 //the simplest ways I haven't dismissed.
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+//The examples end up in the OpenAPI document (see openapi.rs).
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, ToSchema)]
 pub struct Item {
+    #[schema(example = "spicy clam dip")]
     item_name: String,
+    #[schema(example = json!(["potato chips", "16"]))]
     ingredients: Vec<String>,
+    #[schema(example = "2024-06-04")]
     updated: String,    //This should be a date, but that complicates a lot of things.
+    #[schema(example = "16")]
     price: String,      //This should probably be an enum, but I'm not sure how to divide options.
+    #[schema(example = "Westward")]
     restaurant: String, //This would probably benefit from being a struct.
 }
 
@@ -38,12 +52,58 @@ impl Item {
         ingredients
     }
 
+    //Splits the name and ingredients into the lowercase words used as search terms.
+    //Only alphanumeric characters are kept; words left empty after that are dropped.
+    //Should numbers be retained?
+    pub(crate) fn terms(&self) -> Vec<String> {
+        let words = self.item_name.split(char::is_whitespace)
+            .chain(self.ingredients.iter().flat_map(|x| x.split(char::is_whitespace)));
+
+        words.map(normalize).filter(|word| !word.is_empty()).collect()
+    }
+
     //Slightly modified from the example at https://doc.rust-lang.org/std/hash/index.html
     pub fn get_hash(&self) -> u64 {
         let mut s = DefaultHasher::new();
         self.hash(&mut s);
         s.finish()
     }
+
+    //Key used by the stores.
+    //Casting the hash to i64 because sqlite can't handle u64.
+    //...that took entirely too long to figure out what was failing here.
+    pub fn id(&self) -> i64 {
+        self.get_hash() as i64
+    }
+
+    pub fn name(&self) -> &str {
+        &self.item_name
+    }
+
+    pub fn ingredients(&self) -> &[String] {
+        &self.ingredients
+    }
+
+    pub fn price(&self) -> &str {
+        &self.price
+    }
+
+    pub fn updated(&self) -> &str {
+        &self.updated
+    }
+
+    pub fn restaurant(&self) -> &str {
+        &self.restaurant
+    }
+}
+
+//A word as it's indexed: only the alphanumeric characters, lowercased. Queries go through this too,
+//so they match what's in the index.
+pub(crate) fn normalize(word: &str) -> String {
+    let mut word = word.to_string();
+    word.retain(|c| c.is_alphanumeric());
+    word.make_ascii_lowercase();
+    word
 }
 
 //Pretty print
@@ -68,87 +128,86 @@ impl Hash for Item {
     }
 }
 
-//Checks if the database exists. If not, creates it.
-//This should probably return something that lets me know if a database needed to be created.
-//That said, what would the response be? Attempt to load every json file in res/?
-pub(crate) fn ensure_db(path: &str) -> Result<(), Box<dyn Error>> {
-    let connection = Connection::open(path)?;
-
-    let mut statement = connection.prepare(
-        "SELECT name FROM sqlite_master WHERE type='table' AND name='menu_db'",
-    )?;
-
-    let mut res = statement.query([])?;
-
-    if (res.next()?).is_some() {
-        //Only(?) does anything if a logger is set up and running
-        //Notably, this means it doesn't show up in current tests.
-        trace!("Database found.")
-    } else {
-        warn!("Database not found.");
-        //Using a hash to determine uniqueness in the database, but otherwise shoving the entire
-        //struct in as a JSON object.
-        //I tried messed around with blobs, but it was a huge hassle dealing with that and serde
-        //Originally had each item field as a column, but I was converting things just to convert
-        //them back. (Which I'm still doing, but this is more straightforward).
-        // I *should* implement it that way for the sake of updating menus and general database
-        // nonsense, but that's not a current priority.
-        connection.execute(
-            "CREATE TABLE menu_db (
-                id  INTEGER PRIMARY KEY,
-                item_data   TEXT
-            )",
-            (),
-        )?;
-    }
-
-    Ok(())
+//What importing one JSON menu file did.
+#[derive(Debug, Default, PartialEq)]
+pub struct MenuImport {
+    pub file: String,
+    //Items that weren't already stored.
+    pub added: usize,
+    //Stored items this menu has a new price for; see reprice.
+    pub repriced: usize,
+    //Restaurants the new items belong to.
+    pub restaurants: BTreeSet<String>,
 }
 
 //Takes a file path to a json file and adds it to the database.
-pub(crate) fn add_json_to_db(path: &str, file: &str) -> Result<(), Box<dyn Error>> {
-    let items = read_from_json(file);
-    let connection = Connection::open(path)?;
-    //Ostensibly it checks for conflict first, so this minimizes operations on existing entries.
-    //However, rather than ignore it may make sense to update. Left as is for current convenience.
-    let mut statement = connection.prepare("INSERT INTO menu_db (id, item_data) \
-    VALUES (?1, ?2) ON CONFLICT(id) DO NOTHING")?;
-
+pub(crate) fn add_json_to_db(store: &dyn MenuStore, file: &str) -> Result<MenuImport, DbError> {
+    let items = read_from_json(file)?;
+    let mut import = MenuImport { file: file.to_string(), ..MenuImport::default() };
+    //Items already in the store are left alone, apart from their price (see reprice).
     for item in items {
-        //Casting the hash to i64 because sqlite can't handle u64.
-        //...that took entirely too long to figure out what was failing here.
-        statement.execute(params![item.get_hash() as i64, serde_json::to_value(item).unwrap()])?;
+        if store.insert(&item)? {
+            import.added += 1;
+            import.restaurants.insert(item.restaurant);
+        } else if reprice(store, &item)? {
+            import.repriced += 1;
+        }
     }
 
-    Ok(())
+    Ok(import)
 }
 
-//Fills a vec with Items from a Json file.
-fn read_from_json(file: &str) -> Vec<Item> {
-    let raw = fs::read_to_string(file).expect("Passed JSON file should exist, double check path.");
-    let json: Vec<Item> = serde_json::from_str(&raw).unwrap();
-    json
+//Replaces the stored item's price with this one's, if it's different and this menu is at least as
+//new as the stored item. Returns whether it did. Dates that don't parse as YYYY-MM-DD can't be
+//compared, so they leave the price alone.
+//Menus are the only way anything gets into the store, so without this an item's price history
+//(GraphQL's priceHistory) could never grow past the price it was first imported at. Only the price
+//is taken; other differences are still ignored. The upsert goes out as an item_updated event like
+//any other, but a menu that only repriced items isn't a menu_imported.
+fn reprice(store: &dyn MenuStore, item: &Item) -> Result<bool, DbError> {
+    let date = |x: &Item| NaiveDate::parse_from_str(x.updated(), "%Y-%m-%d").ok();
+    let stale = store.get(item.id())?.is_some_and(|x| {
+        let newer = matches!((date(&x), date(item)), (Some(old), Some(new)) if old <= new);
+        x.price() != item.price() && newer
+    });
+    if stale {
+        store.upsert(item)?;
+    }
+    Ok(stale)
 }
 
-//Returns a vec of every item in the database.
-fn db_to_vec(path: &str) -> Result<Vec<Item>, Box<dyn Error>> {
-    let connection = Connection::open(path)?;
-    let mut items: Vec<Item> = Vec::new();
-    let mut statement = connection.prepare("SELECT item_data FROM menu_db").unwrap();
-
-    let mut rows = statement.query([]).unwrap();
+//Fills a vec with Items from a Json file.
+fn read_from_json(file: &str) -> Result<Vec<Item>, DbError> {
+    let raw = fs::read_to_string(file)
+        .map_err(|e| format!("Couldn't read {}, double check path: {}", file, e))?;
+    let json: Vec<Item> = serde_json::from_str(&raw)
+        .map_err(|e| format!("{} isn't a JSON menu: {}", file, e))?;
+    Ok(json)
+}
 
-    //Kinda ugly, try using a query_map?
-    while let Some(row) = rows.next().unwrap() {
-        let val: serde_json::Value = row.get(0).unwrap();
-        items.push(serde_json::from_value(val).unwrap());
+//Adds every *.json menu in the directory to the store, in file name order.
+//Returns what each file's import did.
+pub(crate) fn seed_from_dir(store: &dyn MenuStore, dir: &str) -> Result<Vec<MenuImport>, DbError> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .filter(|x| x.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+
+    let mut imports = Vec::with_capacity(files.len());
+    for file in &files {
+        debug!("Seeding from {}.", file.display());
+        imports.push(add_json_to_db(store, &file.to_string_lossy())?);
     }
-    Ok(items)
+    Ok(imports)
 }
 
-//Takes a vector of items (generally taken from the database, via db_to_vec) and creates a map of 
+//Takes a vector of items (generally taken from the database, via MenuStore::list) and creates a map of 
 //words found in the various menus/items, and which items have that word.
-fn make_map(items: Vec<Item>) -> HashMap<String, HashSet<Arc<Item>>> {
+//This was the server's index before index::Index replaced it; it's only kept around for the
+//comparison benchmark in index.rs.
+#[cfg(test)]
+pub(crate) fn make_map(items: Vec<Item>) -> HashMap<String, HashSet<Arc<Item>>> {
     let mut map: HashMap<String, HashSet<Arc<Item>>> = HashMap::new();
     for item in &items {
         //First time using explicit reference counting, one of those little things that took longer
@@ -156,19 +215,7 @@ fn make_map(items: Vec<Item>) -> HashMap<String, HashSet<Arc<Item>>> {
         //Could probably be a Weak reference? Something to learn later.
         let item_copy: Arc<Item> = Arc::new(item.clone());
 
-        let mut all_words: Vec<String> = item.item_name.split(char::is_whitespace).map(|s| s.to_string()).collect();
-
-        for elem in &item.ingredients {
-            all_words.append(&mut elem.split(char::is_whitespace).map(|s| s.to_string()).collect::<Vec<String>>());
-        }
-
-        for word in all_words.iter_mut() {
-            //Should numbers be retained?
-            word.retain(|c| c.is_alphanumeric());
-            word.make_ascii_lowercase();
-        }
-
-        for word in all_words {
+        for word in item.terms() {
             match map.get_mut(&word) {
                 Some(x) => {
                     x.insert(Arc::clone(&item_copy));
@@ -182,40 +229,126 @@ fn make_map(items: Vec<Item>) -> HashMap<String, HashSet<Arc<Item>>> {
     map
 }
 
-pub fn get_map(path: &str) -> HashMap<String, HashSet<Arc<Item>>> {
-    make_map(db_to_vec(path).unwrap())
+//Builds the search index from every item in the store, tagged with the store's id and change counter.
+//The counter is read before the items, so if something is written in between, the index is
+//labelled as older than it is; that only costs a rebuild or a cache miss, never stale data.
+pub fn get_index(store: &dyn MenuStore) -> Result<Index, DbError> {
+    let database = store.database_id()?;
+    let counter = store.change_counter()?;
+    Ok(Index::build(store.list()?).with_version(database, counter))
+}
+
+//Loads the index snapshot saved next to the database if it's still current, otherwise builds the
+//index from the store and saves a new snapshot for next time. Stores without a snapshot path
+//always build.
+//The counter is read before the items, so if something is written in between, the snapshot is
+//labelled as older than it is and simply gets rebuilt on the next start.
+//The id and counter are also the index version responses are tagged with, so we can't do without them.
+pub fn load_index(store: &dyn MenuStore) -> Result<Index, DbError> {
+    let snapshot = match store.snapshot_path() {
+        Some(x) => x,
+        None => return get_index(store),
+    };
+    let snapshot = snapshot.as_path();
+    let database = store.database_id()?;
+    let counter = store.change_counter()?;
+
+    match Index::load(snapshot, database, counter) {
+        Ok(Some(index)) => {
+            debug!("Loaded index snapshot from {}.", snapshot.display());
+            return Ok(index);
+        }
+        Ok(None) => debug!("Index snapshot missing or out of date, rebuilding."),
+        Err(e) => warn!("Couldn't read index snapshot {}, rebuilding: {}", snapshot.display(), e),
+    }
+
+    let index = Index::build(store.list()?).with_version(database, counter);
+    if let Err(e) = index.save(snapshot, database, counter) {
+        warn!("Couldn't save index snapshot {}: {}", snapshot.display(), e);
+    }
+    Ok(index)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::menu::{add_json_to_db, db_to_vec, ensure_db, make_map};
+    use crate::menu::{add_json_to_db, load_index, make_map, seed_from_dir};
+    use crate::store::{MemoryStore, MenuStore, SqliteStore, TempDb};
+
+    fn loaded_store() -> MemoryStore {
+        let store = MemoryStore::new();
+        add_json_to_db(&store, "res/bateau_04-11.json").unwrap();
+        add_json_to_db(&store, "res/canlis_06-03.json").unwrap();
+        add_json_to_db(&store, "res/lark_06-03.json").unwrap();
+        add_json_to_db(&store, "res/westward_05-16.json").unwrap();
+        store
+    }
 
     #[test]
-    fn test_db_setup() {
-        let path = "./test_db.sqlite";
-        ensure_db(path).expect("Failed");
+    fn test_add_json() {
+        let store = loaded_store();
+        let count = store.list().unwrap().len();
+        assert!(count > 0);
+
+        //Adding the same files again doesn't duplicate anything.
+        let import = add_json_to_db(&store, "res/lark_06-03.json").unwrap();
+        assert_eq!(store.list().unwrap().len(), count);
+        assert_eq!(import.added, 0);
+        assert!(import.restaurants.is_empty());
+
+        let import = add_json_to_db(&MemoryStore::new(), "res/lark_06-03.json").unwrap();
+        assert!(import.added > 0);
+        assert_eq!(import.restaurants.len(), 1);
     }
 
+    //A newer menu's price replaces the stored one; an older menu's doesn't.
     #[test]
-    fn test_add_json() {
-        let path = "./test_db.sqlite";
-        ensure_db(path).expect("Failed");
+    fn test_reprice() {
+        let store = MemoryStore::new();
+        let file = std::env::temp_dir().join(format!("menu_manager_reprice_{}.json", std::process::id()));
+        let file = file.to_str().unwrap();
+        let import = |price: &str, updated: &str| {
+            let menu = serde_json::json!([{
+                "item_name": "Oyster stew",
+                "ingredients": ["cream", "leek"],
+                "updated": updated,
+                "price": price,
+                "restaurant": "Lark",
+            }]);
+            std::fs::write(file, menu.to_string()).unwrap();
+            add_json_to_db(&store, file).unwrap()
+        };
 
-        add_json_to_db(path, "res/bateau_04-11.json").unwrap();
-        add_json_to_db(path, "res/canlis_06-03.json").unwrap();
-        add_json_to_db(path, "res/lark_06-03.json").unwrap();
-        add_json_to_db(path, "res/westward_05-16.json").unwrap();
+        assert_eq!(import("18", "2024-06-04").added, 1);
+        let newer = import("21", "2024-07-01");
+        assert_eq!((newer.added, newer.repriced), (0, 1));
+        assert!(newer.restaurants.is_empty());
+        assert_eq!(import("16", "2024-05-01").repriced, 0);
+        assert_eq!(import("21", "2024-08-01").repriced, 0);
+        //Compared as dates, not strings: June 30th is older even though "2024-6" sorts after "2024-0".
+        assert_eq!(import("25", "2024-6-30").repriced, 0);
+        assert_eq!(import("25", "soon").repriced, 0);
+        assert_eq!(import("23", "2024-10-01").repriced, 1);
+        std::fs::remove_file(file).unwrap();
+
+        let item = &store.list().unwrap()[0];
+        assert_eq!(item.price(), "23");
+        let history: Vec<_> = store.price_histories(&[item.id()]).unwrap()[&item.id()].iter().map(|x| x.price.clone()).collect();
+        assert_eq!(history, ["18", "21", "23"]);
     }
 
     #[test]
-    fn test_read_db() {
-        let path = "./test_db.sqlite";
-        ensure_db(path).expect("Failed");
+    fn test_seed_from_dir() {
+        let store = MemoryStore::new();
+        assert_eq!(seed_from_dir(&store, "res/").unwrap().len(), 4);
+        assert_eq!(store.list().unwrap().len(), loaded_store().list().unwrap().len());
 
-        let items = match db_to_vec(path) {
-            Ok(x) => { x }
-            Err(_) => todo!(),
-        };
+        assert!(seed_from_dir(&store, "does/not/exist").is_err());
+        assert!(add_json_to_db(&store, "Cargo.toml").is_err());
+    }
+
+    #[test]
+    fn test_read_db() {
+        let items = loaded_store().list().unwrap();
 
         for item in items {
             println!("{}: {}", item.item_name, item.restaurant)
@@ -224,8 +357,7 @@ mod tests {
 
     #[test]
     fn test_mapper() {
-        let path = "./test_db.sqlite";
-        let map = make_map(db_to_vec(path).unwrap());
+        let map = make_map(loaded_store().list().unwrap());
 
         match map.get("oyster") {
             Some(x) => {
@@ -236,4 +368,28 @@ mod tests {
             None => { println!("Oyster not found!") }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_change_counter_and_snapshot() {
+        let temp = TempDb::new("counter");
+        let store = SqliteStore::open(&temp.path).unwrap();
+        assert_eq!(store.change_counter().unwrap(), 0);
+
+        add_json_to_db(&store, "res/lark_06-03.json").unwrap();
+        let after_add = store.change_counter().unwrap();
+        assert!(after_add > 0);
+
+        //Re-adding the same file conflicts on every row, so nothing actually changes.
+        add_json_to_db(&store, "res/lark_06-03.json").unwrap();
+        assert_eq!(store.change_counter().unwrap(), after_add);
+
+        let built = load_index(&store).unwrap();
+        assert!(store.snapshot_path().unwrap().exists());
+        let loaded = load_index(&store).unwrap();
+        assert_eq!(built.item_count(), loaded.item_count());
+        assert_eq!(built.postings("aioli"), loaded.postings("aioli"));
+        assert_eq!(built.version(), after_add);
+        assert_eq!(loaded.version(), after_add);
+        assert_eq!(loaded.database(), store.database_id().unwrap());
+    }
+}
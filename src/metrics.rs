@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::http;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::db::DbError;
+use crate::error::AppError;
+use crate::index::SharedIndex;
+use crate::menu::Item;
+use crate::store::{MenuStore, PricePoint, SharedStore};
+use crate::sync::{Changes, SyncToken};
+
+//Everything exposed on /metrics.
+//Kept in its own Registry rather than the prometheus crate's global one, so each router (and each
+//test) gets a clean set.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    search_results: Histogram,
+    index_terms: IntGauge,
+    index_items: IntGauge,
+    db_duration: HistogramVec,
+    menu_imports: IntCounter,
+    items_imported: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("menu_manager".to_string()), None)
+            .expect("Metric prefix should be valid.");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by method, matched route and status."),
+            &["method", "route", "status"],
+        ).unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by method and matched route."),
+            &["method", "route"],
+        ).unwrap();
+        let search_results = Histogram::with_opts(
+            HistogramOpts::new("search_results", "Number of items returned per search.")
+                .buckets(vec![0.0, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0]),
+        ).unwrap();
+        let index_terms = IntGauge::new("index_terms", "Distinct words in the current index.").unwrap();
+        let index_items = IntGauge::new("index_items", "Items in the current index.").unwrap();
+        let db_duration = HistogramVec::new(
+            HistogramOpts::new("db_operation_duration_seconds", "Store operation latency by operation.")
+                .buckets(exponential_buckets(0.0001, 4.0, 8).unwrap()),
+            &["op"],
+        ).unwrap();
+        let menu_imports = IntCounter::new("menu_imports_total", "JSON menu files imported.").unwrap();
+        let items_imported = IntCounterVec::new(
+            Opts::new("items_imported_total", "Items inserted, by whether they were new or already stored."),
+            &["result"],
+        ).unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(search_results.clone())).unwrap();
+        registry.register(Box::new(index_terms.clone())).unwrap();
+        registry.register(Box::new(index_items.clone())).unwrap();
+        registry.register(Box::new(db_duration.clone())).unwrap();
+        registry.register(Box::new(menu_imports.clone())).unwrap();
+        registry.register(Box::new(items_imported.clone())).unwrap();
+
+        Metrics {
+            registry,
+            http_requests,
+            http_duration,
+            search_results,
+            index_terms,
+            index_items,
+            db_duration,
+            menu_imports,
+            items_imported,
+        }
+    }
+
+    pub fn observe_search(&self, results: usize) {
+        self.search_results.observe(results as f64);
+    }
+
+    pub fn add_menu_imports(&self, count: usize) {
+        self.menu_imports.inc_by(count as u64);
+    }
+
+    fn observe_db(&self, op: &str, elapsed: Duration) {
+        self.db_duration.with_label_values(&[op]).observe(elapsed.as_secs_f64());
+    }
+
+    //Everything in Prometheus' text format. The index gauges are read from the current snapshot
+    //here rather than kept up to date on every publish.
+    pub fn render(&self, index: &SharedIndex) -> Result<String, AppError> {
+        let snapshot = index.snapshot();
+        self.index_terms.set(snapshot.term_count() as i64);
+        self.index_items.set(snapshot.item_count() as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| AppError::Internal(format!("Couldn't encode metrics: {}", e)))?;
+        String::from_utf8(buffer).map_err(|e| AppError::Internal(e.to_string()))
+    }
+}
+
+//Middleware counting and timing every request by its matched route.
+//Unmatched requests share one "unmatched" label, so scanners can't blow up the number of series.
+pub async fn track_requests(State(metrics): State<Arc<Metrics>>, request: Request, next: Next) -> Response {
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    metrics.http_duration.with_label_values(&[&method, &route]).observe(start.elapsed().as_secs_f64());
+    metrics.http_requests.with_label_values(&[&method, &route, response.status().as_str()]).inc();
+    response
+}
+
+//Handler for /metrics.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "ops",
+    summary = "Prometheus metrics",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+    ),
+)]
+pub async fn serve_metrics(
+    State(metrics): State<Arc<Metrics>>,
+    State(index): State<SharedIndex>,
+) -> Result<Response, AppError> {
+    let body = metrics.render(&index)?;
+    Ok(([(http::header::CONTENT_TYPE, TextEncoder::new().format_type().to_string())], body).into_response())
+}
+
+//Wraps a store to time every operation and count imported items.
+pub struct MeteredStore {
+    inner: SharedStore,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredStore {
+    pub fn new(inner: SharedStore, metrics: Arc<Metrics>) -> Self {
+        MeteredStore { inner, metrics }
+    }
+
+    fn timed<T>(&self, op: &str, f: impl FnOnce(&dyn MenuStore) -> T) -> T {
+        let start = Instant::now();
+        let res = f(self.inner.as_ref());
+        self.metrics.observe_db(op, start.elapsed());
+        res
+    }
+}
+
+impl MenuStore for MeteredStore {
+    fn insert(&self, item: &Item) -> Result<bool, DbError> {
+        let added = self.timed("insert", |x| x.insert(item))?;
+        let result = if added { "added" } else { "existing" };
+        self.metrics.items_imported.with_label_values(&[result]).inc();
+        Ok(added)
+    }
+
+    fn upsert(&self, item: &Item) -> Result<(), DbError> {
+        self.timed("upsert", |x| x.upsert(item))
+    }
+
+    fn delete(&self, id: i64) -> Result<bool, DbError> {
+        self.timed("delete", |x| x.delete(id))
+    }
+
+    fn list(&self) -> Result<Vec<Item>, DbError> {
+        self.timed("list", |x| x.list())
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Item>, DbError> {
+        self.timed("get", |x| x.get(id))
+    }
+
+    fn change_counter(&self) -> Result<i64, DbError> {
+        self.timed("change_counter", |x| x.change_counter())
+    }
+
+    fn database_id(&self) -> Result<i64, DbError> {
+        self.timed("database_id", |x| x.database_id())
+    }
+
+    fn changes_since(&self, since: Option<SyncToken>) -> Result<Changes, DbError> {
+        self.timed("changes_since", |x| x.changes_since(since))
+    }
+
+    fn trim_changes(&self, keep: i64) -> Result<usize, DbError> {
+        self.timed("trim_changes", |x| x.trim_changes(keep))
+    }
+
+    fn price_histories(&self, ids: &[i64]) -> Result<BTreeMap<i64, Vec<PricePoint>>, DbError> {
+        self.timed("price_histories", |x| x.price_histories(ids))
+    }
+
+    fn snapshot_path(&self) -> Option<PathBuf> {
+        self.inner.snapshot_path()
+    }
+
+    fn checkpoint(&self) -> Result<(), DbError> {
+        self.timed("checkpoint", |x| x.checkpoint())
+    }
+
+    fn check(&self) -> Result<(), DbError> {
+        self.timed("check", |x| x.check())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::index::{Index, SharedIndex};
+    use crate::menu::add_json_to_db;
+    use crate::metrics::{MeteredStore, Metrics};
+    use crate::store::{MemoryStore, MenuStore};
+
+    #[test]
+    fn test_metered_store() {
+        let metrics = Arc::new(Metrics::new());
+        let store = MeteredStore::new(Arc::new(MemoryStore::new()), Arc::clone(&metrics));
+
+        add_json_to_db(&store, "res/lark_06-03.json").unwrap();
+        add_json_to_db(&store, "res/lark_06-03.json").unwrap();
+        let count = store.list().unwrap().len();
+        let index = SharedIndex::new(Index::build(store.list().unwrap()));
+
+        let text = metrics.render(&index).unwrap();
+        assert!(text.contains(&format!("menu_manager_items_imported_total{{result=\"added\"}} {}", count)));
+        assert!(text.contains(&format!("menu_manager_items_imported_total{{result=\"existing\"}} {}", count)));
+        assert!(text.contains("menu_manager_db_operation_duration_seconds_count{op=\"list\"} 2"));
+        assert!(text.contains(&format!("menu_manager_index_items {}", count)));
+    }
+}
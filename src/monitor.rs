@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, Path, Request, State};
+use axum::extract::rejection::PathRejection;
+use axum::http::StatusCode;
+use axum::Json;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::auth::Auth;
+use crate::config::Monitor;
+use crate::error::{AppError, ErrorBody};
+use crate::limits::{client_of, UNLIMITED_ROUTES};
+
+//Stop tracking new clients past this many; the quiet ones are dropped first (see prune).
+const MAX_CLIENTS: usize = 10_000;
+//Paths kept per client, for the summary.
+const RECENT_PATHS: usize = 5;
+//Clients listed in the summary, most misses first.
+const SUMMARY_CLIENTS: usize = 100;
+
+//Keeps an eye on requests to routes we don't have. Real clients rarely hit those, while scanners
+//walk lists of well known paths (/wp-admin, /.env, ...) looking for something to exploit.
+//Counts misses per client address and flags the ones matching a known scan pattern. With blocking
+//on, a client with `threshold` misses inside the window is turned away for a while.
+pub struct ScanMonitor {
+    config: Monitor,
+    block: bool,
+    state: Mutex<MonitorState>,
+}
+
+#[derive(Default)]
+struct MonitorState {
+    clients: HashMap<String, ClientRecord>,
+    //How often each scan pattern has matched, since startup.
+    patterns: BTreeMap<String, u64>,
+}
+
+struct ClientRecord {
+    misses: u64,
+    suspicious: u64,
+    //Times of the misses inside the window, oldest first.
+    recent: VecDeque<Instant>,
+    paths: VecDeque<String>,
+    last_seen: Instant,
+    last_seen_at: chrono::DateTime<chrono::Utc>,
+    blocked_until: Option<Instant>,
+}
+
+//What record() found, mostly for logging.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub pattern: Option<String>,
+    pub blocked: bool,
+}
+
+impl ScanMonitor {
+    pub fn new(config: Monitor, block: bool) -> Self {
+        ScanMonitor { config, block, state: Mutex::new(MonitorState::default()) }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.config.window_secs)
+    }
+
+    //The first scan pattern the path matches, ignoring case.
+    pub fn matches(&self, path: &str) -> Option<&str> {
+        let path = path.to_lowercase();
+        self.config.scan_patterns.iter()
+            .find(|x| path.contains(&x.to_lowercase()))
+            .map(|x| x.as_str())
+    }
+
+    //Records a request to an undefined route.
+    pub fn record(&self, client: &str, path: &str, now: Instant) -> Verdict {
+        let pattern = self.matches(path).map(|x| x.to_string());
+        let window = self.window();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(x) = &pattern {
+            *state.patterns.entry(x.clone()).or_default() += 1;
+        }
+        if !state.clients.contains_key(client) && state.clients.len() >= MAX_CLIENTS {
+            prune(&mut state.clients, now, window);
+        }
+
+        let record = state.clients.entry(client.to_string()).or_insert_with(|| ClientRecord {
+            misses: 0,
+            suspicious: 0,
+            recent: VecDeque::new(),
+            paths: VecDeque::new(),
+            last_seen: now,
+            last_seen_at: chrono::Utc::now(),
+            blocked_until: None,
+        });
+        record.misses += 1;
+        record.suspicious += pattern.is_some() as u64;
+        record.last_seen = now;
+        record.last_seen_at = chrono::Utc::now();
+        record.paths.push_back(path.to_string());
+        if record.paths.len() > RECENT_PATHS {
+            record.paths.pop_front();
+        }
+        record.recent.push_back(now);
+        while record.recent.front().is_some_and(|x| now.saturating_duration_since(*x) > window) {
+            record.recent.pop_front();
+        }
+
+        let crossed = record.recent.len() as u64 >= self.config.threshold as u64;
+        let already = record.blocked_until.is_some_and(|x| x > now);
+        let blocked = self.block && crossed && !already;
+        if blocked {
+            record.blocked_until = Some(now + Duration::from_secs(self.config.block_secs));
+            record.recent.clear();
+        }
+        Verdict { pattern, blocked }
+    }
+
+    //How much longer the client is blocked for, if it is.
+    pub fn blocked_for(&self, client: &str, now: Instant) -> Option<Duration> {
+        if !self.block {
+            return None;
+        }
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.clients.get(client)
+            .and_then(|x| x.blocked_until)
+            .filter(|x| *x > now)
+            .map(|x| x - now)
+    }
+
+    //Lifts a block early. Returns whether the client was blocked.
+    pub fn unblock(&self, client: &str, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.clients.get_mut(client) {
+            Some(x) if x.blocked_until.is_some_and(|x| x > now) => {
+                x.blocked_until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn summary(&self, now: Instant) -> Summary {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut clients: Vec<ClientSummary> = state.clients.iter().map(|(client, x)| {
+            let blocked_until = x.blocked_until
+                .filter(|x| *x > now)
+                .and_then(|x| chrono::Duration::from_std(x - now).ok())
+                .map(|x| (chrono::Utc::now() + x).to_rfc3339());
+            ClientSummary {
+                client: client.clone(),
+                misses: x.misses,
+                suspicious: x.suspicious,
+                recent: x.recent.iter().filter(|y| now.saturating_duration_since(**y) <= self.window()).count(),
+                last_seen: x.last_seen_at.to_rfc3339(),
+                recent_paths: x.paths.iter().cloned().collect(),
+                blocked_until,
+            }
+        }).collect();
+        clients.sort_by(|a, b| b.misses.cmp(&a.misses).then_with(|| a.client.cmp(&b.client)));
+        let tracked = clients.len();
+        clients.truncate(SUMMARY_CLIENTS);
+        Summary {
+            blocking: self.block,
+            threshold: self.config.threshold,
+            window_secs: self.config.window_secs,
+            tracked,
+            patterns: state.patterns.clone(),
+            clients,
+        }
+    }
+}
+
+//Makes room by dropping clients that aren't blocked and haven't missed inside the window, or failing
+//that, the one seen longest ago.
+fn prune(clients: &mut HashMap<String, ClientRecord>, now: Instant, window: Duration) {
+    clients.retain(|_, x| x.blocked_until.is_some_and(|x| x > now) || now.saturating_duration_since(x.last_seen) <= window);
+    if clients.len() >= MAX_CLIENTS {
+        let oldest = clients.iter().min_by_key(|(_, x)| x.last_seen).map(|(k, _)| k.clone());
+        if let Some(x) = oldest {
+            clients.remove(&x);
+        }
+    }
+}
+
+//Response for GET /monitor.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Summary {
+    blocking: bool,
+    threshold: u32,
+    window_secs: u64,
+    //Clients being tracked; only the top ones are listed.
+    tracked: usize,
+    patterns: BTreeMap<String, u64>,
+    clients: Vec<ClientSummary>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClientSummary {
+    client: String,
+    misses: u64,
+    suspicious: u64,
+    //Misses inside the current window.
+    recent: usize,
+    last_seen: String,
+    recent_paths: Vec<String>,
+    blocked_until: Option<String>,
+}
+
+//Middleware turning away blocked clients with a 429 until their cool-down is over.
+//The health and metrics routes stay reachable, like with the rate limits, and so does everything
+//for requests with a valid API key, so an admin behind the same address can still lift the block.
+pub async fn reject_blocked(
+    State((monitor, auth)): State<(Arc<ScanMonitor>, Auth)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let exempt = request.extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|x| UNLIMITED_ROUTES.contains(&x.as_str()));
+    let client = client_of(request.extensions());
+    match monitor.blocked_for(&client, Instant::now()) {
+        Some(x) if !exempt && !matches!(auth.authenticate(request.headers()).await, Ok(Some(_))) => {
+            Err(AppError::TooManyRequests {
+                message: "Blocked for requesting too many undefined routes.".to_string(),
+                retry_after_secs: x.as_secs().max(1),
+            })
+        }
+        _ => Ok(next.run(request).await),
+    }
+}
+
+//Handler for GET /monitor.
+#[utoipa::path(
+    get,
+    path = "/monitor",
+    tag = "monitor",
+    summary = "Clients requesting undefined routes",
+    responses(
+        (status = 200, description = "The clients with the most misses, and how often each scan pattern matched", body = Summary),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn monitor_summary(State(monitor): State<Arc<ScanMonitor>>) -> Json<Summary> {
+    Json(monitor.summary(Instant::now()))
+}
+
+//Handler for DELETE /monitor/blocks/:client.
+#[utoipa::path(
+    delete,
+    path = "/monitor/blocks/{client}",
+    tag = "monitor",
+    summary = "Lift a block early",
+    params(("client" = String, Path, description = "The client's address, as listed by /monitor")),
+    responses(
+        (status = 204, description = "Unblocked"),
+        (status = 400, description = "Bad client", body = ErrorBody),
+        (status = 404, description = "The client isn't blocked", body = ErrorBody),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn unblock_client(
+    client: Result<Path<String>, PathRejection>,
+    State(monitor): State<Arc<ScanMonitor>>,
+) -> Result<StatusCode, AppError> {
+    let Path(client) = client?;
+    if monitor.unblock(&client, Instant::now()) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("{} isn't blocked", client)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::Monitor;
+    use crate::monitor::ScanMonitor;
+
+    fn monitor(block: bool) -> ScanMonitor {
+        ScanMonitor::new(Monitor { threshold: 3, window_secs: 60, block_secs: 600, ..Monitor::default() }, block)
+    }
+
+    #[test]
+    fn test_patterns() {
+        let monitor = monitor(false);
+        assert_eq!(monitor.matches("/WP-Admin/setup.php"), Some("wp-admin"));
+        assert_eq!(monitor.matches("/app/.env"), Some(".env"));
+        assert_eq!(monitor.matches("/query"), None);
+    }
+
+    #[test]
+    fn test_blocking() {
+        let monitor = monitor(true);
+        let start = Instant::now();
+        assert!(!monitor.record("a", "/nope", start).blocked);
+        //Misses that fall out of the window don't count.
+        assert!(!monitor.record("a", "/.env", start + Duration::from_secs(61)).blocked);
+        assert!(!monitor.record("a", "/.git/config", start + Duration::from_secs(62)).blocked);
+        assert!(monitor.blocked_for("a", start + Duration::from_secs(62)).is_none());
+        assert!(monitor.record("a", "/wp-login.php", start + Duration::from_secs(63)).blocked);
+
+        let blocked = start + Duration::from_secs(63);
+        assert_eq!(monitor.blocked_for("a", blocked), Some(Duration::from_secs(600)));
+        assert!(monitor.blocked_for("b", blocked).is_none());
+        assert!(monitor.blocked_for("a", blocked + Duration::from_secs(600)).is_none());
+
+        let summary = serde_json::to_value(monitor.summary(blocked)).unwrap();
+        assert_eq!(summary["clients"][0]["client"], "a");
+        assert_eq!(summary["clients"][0]["misses"], 4);
+        assert_eq!(summary["clients"][0]["suspicious"], 3);
+        assert!(summary["clients"][0]["blocked_until"].is_string());
+        assert_eq!(summary["patterns"][".env"], 1);
+
+        assert!(monitor.unblock("a", blocked));
+        assert!(monitor.blocked_for("a", blocked).is_none());
+        assert!(!monitor.unblock("a", blocked));
+    }
+
+    #[test]
+    fn test_counts_without_blocking() {
+        let monitor = monitor(false);
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(!monitor.record("a", "/.env", now).blocked);
+        }
+        assert!(monitor.blocked_for("a", now).is_none());
+        assert_eq!(serde_json::to_value(monitor.summary(now)).unwrap()["clients"][0]["suspicious"], 10);
+    }
+}
@@ -0,0 +1,388 @@
+use std::sync::Arc;
+
+use axum::extract::Path;
+use axum::http;
+use axum::Json;
+use axum::response::{Html, IntoResponse, Response};
+use utoipa_swagger_ui::Config;
+use utoipa::{Modify, OpenApi};
+use utoipa::openapi::{Content, Deprecated, PathItem, Ref, ResponseBuilder};
+use utoipa::openapi::path::Operation;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+
+use crate::auth::API_KEY_HEADER;
+use crate::error::{AppError, ErrorBody};
+use crate::limits::UNLIMITED_ROUTES;
+use crate::{auth, events, graphql, health, metrics, monitor, search, stream, sync, versions, webhooks};
+
+//The OpenAPI document for the whole API, built from the #[utoipa::path] attributes on the handlers
+//and the ToSchema derives on the types they return, so it can't describe a field we don't send.
+//Every route in router() has to be listed here; test_spec_matches_router fails otherwise.
+//The versioned routes go in V1, which is nested under /v1 and copied to the deprecated unversioned
+//paths (see LegacyAliases). The rest go in ApiDoc.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        crate::query,
+        stream::export,
+        events::events,
+        sync::sync,
+        webhooks::list_webhooks,
+        webhooks::create_webhook,
+        webhooks::delete_webhook,
+        webhooks::webhook_deliveries,
+        auth::list_keys,
+        auth::issue_key,
+        auth::revoke_key,
+        monitor::monitor_summary,
+        monitor::unblock_client,
+        search::search,
+        graphql::graphql,
+        graphql::explorer,
+    ),
+    //Types only used in query strings aren't picked up from the paths.
+    components(schemas(stream::Format)),
+)]
+struct V1;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Menu Manager",
+        description = "Search and sync restaurant menu items. Ids and sync tokens are strings, since \
+            they don't fit in a JavaScript number.",
+    ),
+    paths(
+        metrics::serve_metrics,
+        health::healthz,
+        health::readyz,
+        openapi_json,
+        docs,
+        docs_asset,
+    ),
+    nest((path = "/v1", api = V1)),
+    modifiers(&Security, &LegacyAliases, &CommonErrors),
+    tags(
+        (name = "search", description = "Finding items. Open to anonymous clients unless features.anonymous_read is off."),
+        (name = "sync", description = "Keeping a local copy of the catalog up to date."),
+        (name = "webhooks", description = "Catalog events pushed to other services. Needs an admin key."),
+        (name = "keys", description = "API keys. Needs an admin key."),
+        (name = "monitor", description = "Clients requesting routes that don't exist. Needs an admin key."),
+        (name = "ops", description = "Health checks, metrics and these docs."),
+    ),
+)]
+pub struct ApiDoc;
+
+//The two ways of passing an API key (see auth.rs).
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+    }
+}
+
+//The unversioned paths the API was at before /v1, each marked deprecated. Routes added since don't
+//have one.
+struct LegacyAliases;
+
+impl Modify for LegacyAliases {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let aliases: Vec<(String, PathItem)> = openapi.paths.paths.iter()
+            .filter_map(|(path, item)| {
+                let alias = path.strip_prefix(versions::CURRENT)
+                    .filter(|x| !versions::ADDED_IN_V1.contains(x))?
+                    .to_string();
+                let mut item = item.clone();
+                for (_, operation) in operations(&mut item) {
+                    operation.deprecated = Some(Deprecated::True);
+                    operation.operation_id = operation.operation_id.as_ref().map(|x| format!("{}_unversioned", x));
+                    operation.description = Some(format!(
+                        "Deprecated alias of {}{}. Responses carry a `Deprecation` header and a `Link` to it.",
+                        versions::CURRENT, alias,
+                    ));
+                }
+                Some((alias, item))
+            })
+            .collect();
+        openapi.paths.paths.extend(aliases);
+    }
+}
+
+//Errors any route can give that come from the middleware rather than the handler, so they'd
+//otherwise have to be repeated on every one: auth failures, the rate limits and the load shedding
+//in limits.rs. The routes in UNLIMITED_ROUTES skip the limits.
+struct CommonErrors;
+
+impl Modify for CommonErrors {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            let limited = !UNLIMITED_ROUTES.contains(&path.as_str());
+            for (_, operation) in operations(item) {
+                let responses = &mut operation.responses.responses;
+                let mut add = |status: &str, description: &str| {
+                    responses.entry(status.to_string()).or_insert_with(|| error_response(description).into());
+                };
+                if let Some(security) = &operation.security {
+                    add("401", "Missing or unknown API key");
+                    if !security.contains(&SecurityRequirement::default()) {
+                        add("403", "The key's scope doesn't allow this");
+                    }
+                }
+                if limited {
+                    add("429", "Rate limited, or blocked for scanning; see Retry-After");
+                    add("503", "Too busy; see Retry-After");
+                    add("504", "The request took too long");
+                }
+            }
+        }
+    }
+}
+
+fn error_response(description: &str) -> utoipa::openapi::Response {
+    ResponseBuilder::new()
+        .description(description)
+        .content("application/json", Content::new(Some(Ref::from_schema_name("ErrorBody"))))
+        .build()
+}
+
+//Every operation on a path, with its method.
+fn operations(item: &mut PathItem) -> impl Iterator<Item = (&'static str, &mut Operation)> {
+    [
+        ("GET", &mut item.get),
+        ("PUT", &mut item.put),
+        ("POST", &mut item.post),
+        ("DELETE", &mut item.delete),
+        ("OPTIONS", &mut item.options),
+        ("HEAD", &mut item.head),
+        ("PATCH", &mut item.patch),
+        ("TRACE", &mut item.trace),
+    ].into_iter().filter_map(|(method, x)| x.as_mut().map(|x| (method, x)))
+}
+
+//Handler for GET /openapi.json.
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "ops",
+    summary = "This document",
+    responses(
+        (status = 200, description = "The OpenAPI document", body = Object),
+    ),
+)]
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+//Swagger UI over /openapi.json. Swagger UI itself is built into the binary (a pinned release,
+//vendored by utoipa-swagger-ui) and served from /docs/<file>, so the docs work offline and nothing
+//on the page comes from anywhere but us.
+const DOCS_PAGE: &str = r##"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>Menu Manager API</title>
+  <link rel="stylesheet" href="docs/swagger-ui.css">
+</head>
+<body>
+  <div id="docs"></div>
+  <script src="docs/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = () => SwaggerUIBundle({ url: "openapi.json", dom_id: "#docs" });
+  </script>
+</body>
+</html>
+"##;
+
+//Handler for GET /docs.
+#[utoipa::path(
+    get,
+    path = "/docs",
+    tag = "ops",
+    summary = "Browsable docs for this API",
+    responses(
+        (status = 200, description = "Swagger UI", body = String, content_type = "text/html"),
+    ),
+)]
+pub async fn docs() -> Html<&'static str> {
+    Html(DOCS_PAGE)
+}
+
+//Handler for GET /docs/<file>, Swagger UI's scripts and styles.
+#[utoipa::path(
+    get,
+    path = "/docs/{file}",
+    tag = "ops",
+    summary = "Swagger UI's own files, for the docs page",
+    params(("file" = String, Path, description = "e.g. swagger-ui-bundle.js")),
+    responses(
+        (status = 200, description = "The file", body = String),
+        (status = 404, description = "No such file", body = ErrorBody),
+    ),
+)]
+pub async fn docs_asset(Path(file): Path<String>) -> Result<Response, AppError> {
+    //Only swagger-initializer.js is filled in from the config, and our page doesn't load it.
+    let asset = utoipa_swagger_ui::serve(&file, Arc::new(Config::default()))
+        .map_err(|e| AppError::Internal(format!("Couldn't serve {}: {}", file, e)))?
+        .ok_or_else(|| AppError::NotFound(format!("No docs file named {}", file)))?;
+    Ok(([(http::header::CONTENT_TYPE, asset.content_type)], asset.bytes.into_owned()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use axum::{body::Body, http};
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+    use utoipa::OpenApi;
+    use utoipa::openapi::Deprecated;
+
+    use crate::auth::Scope;
+    use crate::menu::Item;
+    use crate::openapi::{operations, ApiDoc};
+    use crate::{admin_routes, open_routes, read_routes, read_v1_routes, router};
+    use crate::versions::{self, DEPRECATION_HEADER};
+    use crate::tests::{send, test_key, test_router, test_state};
+
+    //Every path router() serves, in OpenAPI's {param} form and without the /v1 the API routes are
+    //nested under. Taken from the same lists router() adds its routes from, since a Router can't list
+    //its routes itself.
+    fn router_paths() -> BTreeSet<String> {
+        [read_routes(), read_v1_routes(), admin_routes(), open_routes()].into_iter()
+            .flatten()
+            .map(|(x, _)| {
+                x.split('/')
+                    .map(|x| match x.strip_prefix(':') {
+                        Some(x) => format!("{{{}}}", x),
+                        None => x.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .collect()
+    }
+
+    //The status of a request with an admin key, whether it fell through to the fallback, and whether
+    //it was answered as deprecated. Bodies are only read for 404s; /events never finishes.
+    async fn probe(app: &axum::Router, key: &str, method: &str, path: &str) -> (StatusCode, bool, bool) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", key))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let deprecated = response.headers().contains_key(DEPRECATION_HEADER);
+        if status != StatusCode::NOT_FOUND {
+            return (status, false, deprecated);
+        }
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["message"].as_str().unwrap_or_default().starts_with("No route"), deprecated)
+    }
+
+    //Fails when a route is added, removed or given another method without the document following.
+    #[tokio::test]
+    async fn test_spec_matches_router() {
+        let mut spec = ApiDoc::openapi();
+        let spec_paths: BTreeSet<String> = spec.paths.paths.keys()
+            .map(|x| x.strip_prefix(versions::CURRENT).unwrap_or(x).to_string())
+            .collect();
+        assert_eq!(spec_paths, router_paths(), "Paths in the OpenAPI document and router() differ");
+
+        let state = test_state();
+        let key = test_key(&state, Scope::Admin);
+        let app = router(state);
+        for (path, item) in spec.paths.paths.iter_mut() {
+            //Ids that won't match anything, so nothing gets deleted.
+            let uri = path.split('/')
+                .map(|x| if x.starts_with('{') { "0" } else { x })
+                .collect::<Vec<_>>()
+                .join("/");
+            let documented: Vec<(&str, bool)> = operations(item)
+                .map(|(x, operation)| (x, operation.deprecated == Some(Deprecated::True)))
+                .collect();
+            for method in ["GET", "POST", "PUT", "PATCH", "DELETE"] {
+                let (status, fell_through, deprecated) = probe(&app, &key, method, &uri).await;
+                if let Some((_, documented_deprecated)) = documented.iter().find(|(x, _)| *x == method) {
+                    assert!(!fell_through && status != StatusCode::METHOD_NOT_ALLOWED, "{} {} is documented but not routed", method, path);
+                    assert_eq!(deprecated, *documented_deprecated, "{} {} is deprecated in only one of them", method, path);
+                } else {
+                    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED, "{} {} is routed but not documented", method, path);
+                }
+            }
+        }
+    }
+
+    //Collects every "$ref" in the document.
+    fn refs<'a>(value: &'a serde_json::Value, found: &mut Vec<&'a str>) {
+        match value {
+            serde_json::Value::Object(x) => {
+                if let Some(x) = x.get("$ref").and_then(|x| x.as_str()) {
+                    found.push(x);
+                }
+                x.values().for_each(|x| refs(x, found));
+            }
+            serde_json::Value::Array(x) => x.iter().for_each(|x| refs(x, found)),
+            _ => {}
+        }
+    }
+
+    //The document's idea of an item has the same fields we actually send, and everything it refers to
+    //is in it.
+    #[test]
+    fn test_schemas() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut found = Vec::new();
+        refs(&spec, &mut found);
+        for x in found {
+            let name = x.strip_prefix("#/components/schemas/").unwrap();
+            assert!(spec["components"]["schemas"][name].is_object(), "{} is referred to but missing", x);
+        }
+
+        let documented: BTreeSet<String> = spec["components"]["schemas"]["Item"]["properties"]
+            .as_object().unwrap().keys().cloned().collect();
+        let sent: BTreeSet<String> = serde_json::to_value(Item::default()).unwrap()
+            .as_object().unwrap().keys().cloned().collect();
+        assert_eq!(documented, sent);
+    }
+
+    #[tokio::test]
+    async fn test_served() {
+        let app = test_router();
+        let (status, json) = send(&app, "GET", "/openapi.json", None, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(json["openapi"].as_str().unwrap().starts_with("3."));
+        assert_eq!(json["paths"]["/v1/query/{input}"]["get"]["security"][0], serde_json::json!({}));
+        assert!(json["paths"]["/v1/query/{input}"]["get"].get("deprecated").is_none());
+        assert_eq!(json["paths"]["/query/{input}"]["get"]["deprecated"], true);
+        assert!(json["paths"]["/v1/keys"]["post"]["responses"]["403"].is_object());
+        assert!(json["components"]["securitySchemes"]["bearer"].is_object());
+
+        let response = app.clone().oneshot(Request::get("/docs").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[http::header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+        let page = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8_lossy(&page).contains("https://"), "The docs page shouldn't load anything from elsewhere");
+
+        //Everything the page loads is served from the binary.
+        for file in ["swagger-ui.css", "swagger-ui-bundle.js"] {
+            let response = app.clone().oneshot(Request::get(format!("/docs/{}", file)).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", file);
+        }
+        let (status, _) = send(&app, "GET", "/docs/nope.js", None, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,22 @@
+use axum::http::{HeaderMap, HeaderName};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+
+//Every request gets an id in this header: the client's own if it sent one, otherwise a generated
+//UUID. It goes on the request's tracing span, in error bodies (see error::render_errors) and back
+//on the response, so a client can quote it and we can find the matching log lines.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+//Keeps the client's id, or generates one. Has to sit outside everything that reads it.
+pub fn set() -> SetRequestIdLayer<MakeRequestUuid> {
+    SetRequestIdLayer::new(HeaderName::from_static(REQUEST_ID_HEADER), MakeRequestUuid)
+}
+
+//Copies the id onto the response.
+pub fn propagate() -> PropagateRequestIdLayer {
+    PropagateRequestIdLayer::new(HeaderName::from_static(REQUEST_ID_HEADER))
+}
+
+//The request's id, once `set` has run.
+pub fn of(headers: &HeaderMap) -> Option<&str> {
+    headers.get(REQUEST_ID_HEADER).and_then(|x| x.to_str().ok())
+}
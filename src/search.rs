@@ -0,0 +1,665 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use async_graphql::{Enum, InputObject, SimpleObject};
+use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+use utoipa::ToSchema;
+
+use crate::error::{AppError, ErrorBody};
+use crate::index::{Index, SharedIndex};
+use crate::menu::{normalize, Item};
+use crate::metrics::Metrics;
+
+//Structured search, for what doesn't fit in /query's path segment: free text, filters, sorting,
+//paging, and looser matching. Ranked by a simple tf-idf-ish score: every query word adds the rarity
+//(idf) of the best indexed word it matched in the item, scaled by how close the match was and
+//doubled if it's in the item's name.
+
+const MAX_TEXT_CHARS: usize = 500;
+const MAX_TERMS: usize = 32;
+const MAX_FILTER_VALUES: usize = 50;
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+//Shorter words are only matched exactly, even with fuzzy on; one typo away from a three letter word
+//is usually just a different word.
+const FUZZY_MIN_LEN: usize = 4;
+//Words at least this long can be two typos away.
+const FUZZY_TWO_EDITS_LEN: usize = 8;
+
+//How much a match counts for, by how close it is to what was typed.
+const EXACT: f64 = 1.0;
+const STEMMED: f64 = 0.8;
+const ONE_EDIT: f64 = 0.6;
+const TWO_EDITS: f64 = 0.4;
+const NAME_BOOST: f64 = 2.0;
+
+//Body of POST /search. Everything is optional: an empty body pages through every item.
+//The parts are Serialize so the defaults can go in the OpenAPI document.
+//Also the argument to the GraphQL search field (see graphql.rs), where the fields are camelCase.
+#[derive(Debug, Default, Deserialize, ToSchema, InputObject)]
+#[serde(default, deny_unknown_fields)]
+#[graphql(name = "SearchInput")]
+pub struct SearchRequest {
+    //Words separated by whitespace, matched against item names and ingredients.
+    #[schema(example = "clam dip")]
+    #[graphql(default)]
+    text: String,
+    #[graphql(default)]
+    filters: Filters,
+    #[graphql(default)]
+    sort: SortBy,
+    //Defaults to descending for relevance and ascending for everything else.
+    order: Option<Order>,
+    #[graphql(default)]
+    offset: usize,
+    //1 to 100, 20 if left out.
+    #[schema(example = 20)]
+    limit: Option<usize>,
+    #[graphql(default)]
+    options: SearchOptions,
+}
+
+//Every filter given has to match. Text comparisons ignore case.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, InputObject)]
+#[serde(default, deny_unknown_fields)]
+#[graphql(name = "SearchFilters")]
+pub struct Filters {
+    //Any of these restaurants.
+    #[graphql(default)]
+    restaurants: Vec<String>,
+    //Every one of these has to be part of some ingredient, e.g. "chip" matches "potato chips".
+    #[graphql(default)]
+    ingredients: Vec<String>,
+    //None of these can be part of any ingredient.
+    #[graphql(default)]
+    exclude_ingredients: Vec<String>,
+    //Items whose price isn't a number are left out when either is set.
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+    //YYYY-MM-DD, both inclusive.
+    #[schema(example = "2024-06-01")]
+    updated_from: Option<String>,
+    updated_to: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Relevance,
+    Name,
+    Price,
+    Restaurant,
+    Updated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema, InputObject)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchOptions {
+    //Only items matching every word, rather than any of them.
+    #[graphql(default)]
+    all: bool,
+    //Also match words a typo or two away ("oystr" finds "oyster").
+    #[graphql(default)]
+    fuzzy: bool,
+    //Also match other forms of the same word ("oysters" finds "oyster").
+    #[graphql(default)]
+    stemming: bool,
+    //Add each item's name and ingredients with the matched words wrapped in <mark></mark>.
+    #[graphql(default)]
+    highlight: bool,
+}
+
+impl SearchRequest {
+    pub(crate) fn validate(&self) -> Result<(), AppError> {
+        let invalid = |x: String| Err(AppError::Unprocessable(x));
+        if self.text.chars().count() > MAX_TEXT_CHARS {
+            return invalid(format!("text can be at most {} characters", MAX_TEXT_CHARS));
+        }
+        if query_terms(&self.text).len() > MAX_TERMS {
+            return invalid(format!("text can have at most {} words", MAX_TERMS));
+        }
+        if self.limit.is_some_and(|x| x == 0 || x > MAX_LIMIT) {
+            return invalid(format!("limit has to be between 1 and {}", MAX_LIMIT));
+        }
+        self.filters.validate()
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+}
+
+impl Filters {
+    fn validate(&self) -> Result<(), AppError> {
+        let invalid = |x: String| Err(AppError::Unprocessable(x));
+        let lists = [
+            ("restaurants", &self.restaurants),
+            ("ingredients", &self.ingredients),
+            ("exclude_ingredients", &self.exclude_ingredients),
+        ];
+        for (name, list) in lists {
+            if list.len() > MAX_FILTER_VALUES {
+                return invalid(format!("filters.{} can have at most {} values", name, MAX_FILTER_VALUES));
+            }
+            if list.iter().any(|x| x.trim().is_empty()) {
+                return invalid(format!("filters.{} can't have empty values", name));
+            }
+        }
+        for (name, price) in [("min_price", self.min_price), ("max_price", self.max_price)] {
+            if price.is_some_and(|x| !x.is_finite() || x < 0.0) {
+                return invalid(format!("filters.{} has to be a number of at least 0", name));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_price, self.max_price) {
+            if min > max {
+                return invalid("filters.min_price is more than filters.max_price".to_string());
+            }
+        }
+        let mut dates = Vec::new();
+        for (name, date) in [("updated_from", &self.updated_from), ("updated_to", &self.updated_to)] {
+            if let Some(x) = date {
+                match parse_date(x) {
+                    Some(x) => dates.push(x),
+                    None => return invalid(format!("filters.{} \"{}\" isn't a YYYY-MM-DD date", name, x)),
+                }
+            }
+        }
+        if dates.len() == 2 && dates[0] > dates[1] {
+            return invalid("filters.updated_from is after filters.updated_to".to_string());
+        }
+        Ok(())
+    }
+
+    fn matches(&self, item: &Item) -> bool {
+        let ingredients: Vec<String> = item.ingredients().iter().map(|x| x.to_lowercase()).collect();
+        let has_ingredient = |x: &String| {
+            let x = x.to_lowercase();
+            ingredients.iter().any(|y| y.contains(&x))
+        };
+        let restaurant_matches = self.restaurants.is_empty()
+            || self.restaurants.iter().any(|x| x.eq_ignore_ascii_case(item.restaurant()));
+        let price_matches = (self.min_price.is_none() && self.max_price.is_none())
+            || parse_price(item.price()).is_some_and(|x| {
+                self.min_price.is_none_or(|min| x >= min) && self.max_price.is_none_or(|max| x <= max)
+            });
+        let updated = parse_date(item.updated());
+        let date_matches = (self.updated_from.is_none() && self.updated_to.is_none())
+            || updated.is_some_and(|x| {
+                self.updated_from.as_deref().and_then(parse_date).is_none_or(|from| x >= from)
+                    && self.updated_to.as_deref().and_then(parse_date).is_none_or(|to| x <= to)
+            });
+
+        restaurant_matches
+            && price_matches
+            && date_matches
+            && self.ingredients.iter().all(has_ingredient)
+            && !self.exclude_ingredients.iter().any(has_ingredient)
+    }
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()
+}
+
+//Prices are free text; "16" and "$16.50" are numbers, "mp" isn't.
+pub(crate) fn parse_price(price: &str) -> Option<f64> {
+    price.trim().trim_start_matches('$').parse::<f64>().ok().filter(|x| x.is_finite())
+}
+
+//The distinct words of the text, normalized like the index's.
+fn query_terms(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    text.split_whitespace()
+        .map(normalize)
+        .filter(|x| !x.is_empty() && seen.insert(x.clone()))
+        .collect()
+}
+
+//Cuts common English suffixes so different forms of a word end up the same: "oysters", "tomatoes",
+//"pickled" and "roasting" become "oyster", "tomato", "pickl" and "roast". Not a real stemmer, and the
+//results aren't always words, but both sides of a comparison go through it, so that's fine.
+pub fn stem(word: &str) -> String {
+    if word.len() <= 3 || !word.is_ascii() {
+        return word.to_string();
+    }
+    let mut stem = if let Some(x) = word.strip_suffix("ies").or_else(|| word.strip_suffix("ied")) {
+        format!("{}y", x)
+    } else if let Some(x) = word.strip_suffix("ing").or_else(|| word.strip_suffix("ed")).filter(|x| x.len() >= 3) {
+        undouble(x)
+    } else if let Some(x) = word.strip_suffix("es").filter(|x| ["s", "x", "z", "ch", "sh", "o"].iter().any(|y| x.ends_with(y))) {
+        x.to_string()
+    } else if word.ends_with('s') && !["ss", "us", "is"].iter().any(|x| word.ends_with(x)) {
+        word[..word.len() - 1].to_string()
+    } else {
+        word.to_string()
+    };
+    if stem.len() > 3 && stem.ends_with('e') {
+        stem.pop();
+    }
+    stem
+}
+
+//"chopp" (from "chopping") back to "chop". Doubled l, s and z are usually part of the word.
+fn undouble(word: &str) -> String {
+    let bytes = word.as_bytes();
+    let n = bytes.len();
+    let doubled = n >= 2
+        && bytes[n - 1] == bytes[n - 2]
+        && !b"aeioulsz".contains(&bytes[n - 1]);
+    if doubled { word[..n - 1].to_string() } else { word.to_string() }
+}
+
+//The Levenshtein distance between the words, if it's at most `max`. Gives up on a row as soon as
+//nothing in it is close enough.
+fn edits_within(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let substitution = previous[j - 1] + (a[i - 1] != b[j - 1]) as usize;
+            current[j] = substitution.min(previous[j] + 1).min(current[j - 1] + 1);
+        }
+        if current.iter().min().is_some_and(|x| *x > max) {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    Some(previous[b.len()]).filter(|x| *x <= max)
+}
+
+//The indexed words a query word matches, with how much each match is worth. Fuzzy and stemmed
+//matching have to look at every word in the index; there are only a few thousand.
+fn expand(index: &Index, term: &str, options: &SearchOptions) -> Vec<(String, f64)> {
+    let mut matches = Vec::new();
+    if !index.postings(term).is_empty() {
+        matches.push((term.to_string(), EXACT));
+    }
+    if options.stemming || options.fuzzy {
+        let stemmed = stem(term);
+        let length = term.chars().count();
+        let max_edits = if length >= FUZZY_TWO_EDITS_LEN { 2 } else { 1 };
+        let fuzzy = options.fuzzy && length >= FUZZY_MIN_LEN;
+        for word in index.terms().filter(|x| *x != term) {
+            let weight = if options.stemming && stem(word) == stemmed {
+                STEMMED
+            } else if fuzzy {
+                match edits_within(term, word, max_edits) {
+                    Some(1) => ONE_EDIT,
+                    Some(2) => TWO_EDITS,
+                    _ => continue,
+                }
+            } else {
+                continue;
+            };
+            matches.push((word.to_string(), weight));
+        }
+    }
+    matches.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    matches
+}
+
+//Response for POST /search.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchResults<'a> {
+    //Items matching the search and filters; `results` is one page of them.
+    pub(crate) total: usize,
+    pub(crate) offset: usize,
+    pub(crate) limit: usize,
+    //Each word searched for, with the indexed words it matched. More than itself with fuzzy or
+    //stemming on, nothing if it's in no item.
+    #[schema(example = json!({ "clam": ["clam", "clams"] }))]
+    pub(crate) terms: BTreeMap<String, Vec<String>>,
+    pub(crate) results: Vec<Hit<'a>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Hit<'a> {
+    //The same id /sync and /events use.
+    #[schema(example = "7140986328187424601")]
+    id: String,
+    //Only comparable within one response. 0 without search text.
+    pub(crate) score: f64,
+    pub(crate) item: &'a Item,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) highlights: Option<Highlights>,
+}
+
+//The item's text, HTML escaped, with the matched words in <mark></mark>.
+#[derive(Debug, Serialize, ToSchema, SimpleObject)]
+pub struct Highlights {
+    #[schema(example = "spicy <mark>clam</mark> dip")]
+    item_name: String,
+    ingredients: Vec<String>,
+}
+
+//Runs a validated search against a snapshot of the index.
+pub(crate) fn run<'a>(index: &'a Index, request: &SearchRequest) -> SearchResults<'a> {
+    let terms = query_terms(&request.text);
+    let expansions: Vec<Vec<(String, f64)>> = terms.iter().map(|x| expand(index, x, &request.options)).collect();
+
+    //Best score of each query word, for every item matching at least one.
+    let mut matched: Vec<(u32, f64)> = if terms.is_empty() {
+        (0..index.item_count() as u32).map(|x| (x, 0.0)).collect()
+    } else {
+        let items = index.item_count() as f64;
+        let mut scores: HashMap<u32, Vec<f64>> = HashMap::new();
+        for (i, matches) in expansions.iter().enumerate() {
+            for (word, weight) in matches {
+                let postings = index.postings(word);
+                let idf = (1.0 + items / postings.len() as f64).ln();
+                for &id in postings {
+                    let in_name = index.item(id).name().split_whitespace().any(|x| normalize(x) == *word);
+                    let score = weight * idf * if in_name { NAME_BOOST } else { 1.0 };
+                    let best = &mut scores.entry(id).or_insert_with(|| vec![0.0; terms.len()])[i];
+                    *best = best.max(score);
+                }
+            }
+        }
+        scores.into_iter()
+            .filter(|(_, x)| !request.options.all || x.iter().all(|y| *y > 0.0))
+            .map(|(id, x)| (id, x.iter().sum()))
+            .collect()
+    };
+    matched.retain(|(id, _)| request.filters.matches(index.item(*id)));
+    sort(index, &mut matched, request.sort, request.order);
+
+    let highlighted: HashSet<&str> = expansions.iter().flatten().map(|(x, _)| x.as_str()).collect();
+    let results = matched.iter()
+        .skip(request.offset)
+        .take(request.limit())
+        .map(|&(id, score)| {
+            let item = index.item(id);
+            Hit {
+                id: item.id().to_string(),
+                //Rounded, since nobody needs the full float and it keeps responses stable.
+                score: (score * 1000.0).round() / 1000.0,
+                item,
+                highlights: request.options.highlight.then(|| Highlights {
+                    item_name: highlight(item.name(), &highlighted),
+                    ingredients: item.ingredients().iter().map(|x| highlight(x, &highlighted)).collect(),
+                }),
+            }
+        })
+        .collect();
+
+    SearchResults {
+        total: matched.len(),
+        offset: request.offset,
+        limit: request.limit(),
+        terms: terms.into_iter()
+            .zip(expansions)
+            .map(|(term, matches)| (term, matches.into_iter().map(|(x, _)| x).collect()))
+            .collect(),
+        results,
+    }
+}
+
+//Ties go by name and then position in the index, so paging through a sort is stable.
+fn sort(index: &Index, matched: &mut [(u32, f64)], by: SortBy, order: Option<Order>) {
+    let descending = order.unwrap_or(if by == SortBy::Relevance { Order::Desc } else { Order::Asc }) == Order::Desc;
+    let directed = |x: Ordering| if descending { x.reverse() } else { x };
+    let name = |id: u32| index.item(id).name().to_lowercase();
+    matched.sort_by(|(a, a_score), (b, b_score)| {
+        let (x, y) = (index.item(*a), index.item(*b));
+        let ordering = match by {
+            SortBy::Relevance => directed(a_score.total_cmp(b_score)),
+            SortBy::Name => directed(name(*a).cmp(&name(*b))),
+            SortBy::Restaurant => directed(x.restaurant().to_lowercase().cmp(&y.restaurant().to_lowercase())),
+            SortBy::Updated => directed(x.updated().cmp(y.updated())),
+            //Items without a number for a price go last either way.
+            SortBy::Price => match (parse_price(x.price()), parse_price(y.price())) {
+                (Some(x), Some(y)) => directed(x.total_cmp(&y)),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+        };
+        ordering.then_with(|| name(*a).cmp(&name(*b))).then_with(|| a.cmp(b))
+    });
+}
+
+//Wraps the whitespace separated words of the text that normalize to one of `words` in <mark>.
+//Everything is HTML escaped, since menus are scraped text and the result is meant to be rendered.
+fn highlight(text: &str, words: &HashSet<&str>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let start = rest.find(|x: char| !x.is_whitespace()).unwrap_or(rest.len());
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..end];
+        if !word.is_empty() && words.contains(normalize(word).as_str()) {
+            out.push_str("<mark>");
+            escape_into(&mut out, word);
+            out.push_str("</mark>");
+        } else {
+            escape_into(&mut out, word);
+        }
+        rest = &rest[end..];
+    }
+    out
+}
+
+fn escape_into(out: &mut String, text: &str) {
+    for x in text.chars() {
+        match x {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            x => out.push(x),
+        }
+    }
+}
+
+//Handler for POST /search.
+//Not cached like /query; the body isn't part of the URL, so nothing between us and the client could
+//key on it anyway.
+#[utoipa::path(
+    post,
+    path = "/search",
+    tag = "search",
+    summary = "Search with filters, sorting and paging",
+    description = "Everything in the body is optional. Results are ranked by relevance unless `sort` says otherwise.",
+    request_body = SearchRequest,
+    responses(
+        (status = 200, description = "One page of the matching items", body = SearchResults),
+        (status = 400, description = "Malformed body", body = ErrorBody),
+        (status = 413, description = "Body over the size limit", body = ErrorBody),
+        (status = 422, description = "A field is out of range or has the wrong type", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = [])),
+)]
+pub async fn search(
+    State(index): State<SharedIndex>,
+    State(metrics): State<Arc<Metrics>>,
+    body: Result<Json<SearchRequest>, JsonRejection>,
+) -> Result<Response, AppError> {
+    let Json(request) = body?;
+    request.validate()?;
+    let index = index.snapshot();
+    let results = run(&index, &request);
+
+    //Recorded on the http_request span from the TraceLayer, like for /query.
+    let span = Span::current();
+    span.record("terms", results.terms.len());
+    span.record("results", results.total);
+    metrics.observe_search(results.total);
+
+    Ok(Json(results).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::Index;
+    use crate::menu::Item;
+    use crate::search::{edits_within, run, stem, SearchRequest};
+
+    fn item(name: &str, ingredients: &[&str], price: &str, updated: &str, restaurant: &str) -> Item {
+        serde_json::from_value(serde_json::json!({
+            "item_name": name,
+            "ingredients": ingredients,
+            "updated": updated,
+            "price": price,
+            "restaurant": restaurant,
+        })).unwrap()
+    }
+
+    fn sample() -> Index {
+        Index::build(vec![
+            item("spicy clam dip", &["potato chips"], "16", "2024-06-04", "Westward"),
+            item("raw oysters", &["mignonette"], "mp", "2024-06-04", "Westward"),
+            item("oyster po boy", &["fried oysters", "slaw"], "18", "2024-04-11", "Bateau"),
+            item("chowder", &["clams", "potatoes", "bacon"], "14", "2024-06-03", "Lark"),
+            item("green salad", &["lettuce", "\"ranch\" & herbs"], "12", "2024-06-03", "Canlis"),
+        ])
+    }
+
+    fn request(body: serde_json::Value) -> SearchRequest {
+        let request: SearchRequest = serde_json::from_value(body).unwrap();
+        request.validate().unwrap();
+        request
+    }
+
+    fn names(index: &Index, body: serde_json::Value) -> Vec<String> {
+        let request = request(body);
+        let results = serde_json::to_value(run(index, &request)).unwrap();
+        results["results"].as_array().unwrap().iter()
+            .map(|x| x["item"]["item_name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_stem() {
+        for (a, b) in [("oysters", "oyster"), ("tomatoes", "tomato"), ("pickled", "pickle"), ("berries", "berry"), ("roasting", "roasted"), ("chopping", "chop")] {
+            assert_eq!(stem(a), stem(b), "{} and {}", a, b);
+        }
+        assert_ne!(stem("glass"), stem("glas"));
+        assert_eq!(stem("king"), "king");
+        assert_eq!(stem("hummus"), "hummus");
+    }
+
+    #[test]
+    fn test_edits_within() {
+        assert_eq!(edits_within("oyster", "oyster", 1), Some(0));
+        assert_eq!(edits_within("oystr", "oyster", 1), Some(1));
+        assert_eq!(edits_within("oytser", "oyster", 1), None);
+        assert_eq!(edits_within("oytser", "oyster", 2), Some(2));
+        assert_eq!(edits_within("clam", "chowder", 2), None);
+    }
+
+    #[test]
+    fn test_ranking_and_matching() {
+        let index = sample();
+        //In the name beats in the ingredients; plain matching doesn't find "oysters" for "oyster".
+        assert_eq!(names(&index, serde_json::json!({ "text": "oyster" })), ["oyster po boy"]);
+        assert_eq!(
+            names(&index, serde_json::json!({ "text": "oyster", "options": { "stemming": true } })),
+            ["oyster po boy", "raw oysters"],
+        );
+        assert_eq!(names(&index, serde_json::json!({ "text": "oystr", "options": { "fuzzy": true } })), ["oyster po boy"]);
+        assert!(names(&index, serde_json::json!({ "text": "oystr" })).is_empty());
+
+        assert_eq!(names(&index, serde_json::json!({ "text": "clam bacon" })).len(), 2);
+        assert!(names(&index, serde_json::json!({ "text": "clam bacon", "options": { "all": true } })).is_empty());
+        assert_eq!(
+            names(&index, serde_json::json!({ "text": "clam bacon", "options": { "all": true, "stemming": true } })),
+            ["chowder"],
+        );
+
+        let results = serde_json::to_value(run(&index, &request(serde_json::json!({
+            "text": "clam", "options": { "stemming": true },
+        })))).unwrap();
+        assert_eq!(results["terms"]["clam"], serde_json::json!(["clam", "clams"]));
+    }
+
+    #[test]
+    fn test_filters_sort_and_paging() {
+        let index = sample();
+        assert_eq!(
+            names(&index, serde_json::json!({ "filters": { "restaurants": ["westward", "LARK"] }, "sort": "name" })),
+            ["chowder", "raw oysters", "spicy clam dip"],
+        );
+        assert_eq!(
+            names(&index, serde_json::json!({ "filters": { "min_price": 13, "max_price": 16 }, "sort": "price", "order": "desc" })),
+            ["spicy clam dip", "chowder"],
+        );
+        assert_eq!(
+            names(&index, serde_json::json!({ "filters": { "ingredients": ["POTATO"], "exclude_ingredients": ["bacon"] } })),
+            ["spicy clam dip"],
+        );
+        assert_eq!(
+            names(&index, serde_json::json!({ "filters": { "updated_from": "2024-06-01", "updated_to": "2024-06-03" }, "sort": "name" })),
+            ["chowder", "green salad"],
+        );
+        //Unparseable prices sort last.
+        assert_eq!(names(&index, serde_json::json!({ "sort": "price" })).last().unwrap(), "raw oysters");
+
+        let results = serde_json::to_value(run(&index, &request(serde_json::json!({ "sort": "name", "offset": 1, "limit": 2 })))).unwrap();
+        assert_eq!(results["total"], 5);
+        assert_eq!(results["limit"], 2);
+        assert_eq!(results["results"][0]["item"]["item_name"], "green salad");
+        assert_eq!(results["results"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_highlight() {
+        let index = sample();
+        let results = serde_json::to_value(run(&index, &request(serde_json::json!({
+            "text": "ranch clam",
+            "options": { "highlight": true, "stemming": true },
+        })))).unwrap();
+        let hits = results["results"].as_array().unwrap();
+        let salad = hits.iter().find(|x| x["item"]["item_name"] == "green salad").unwrap();
+        assert_eq!(salad["highlights"]["ingredients"][1], "<mark>&quot;ranch&quot;</mark> &amp; herbs");
+        let chowder = hits.iter().find(|x| x["item"]["item_name"] == "chowder").unwrap();
+        assert_eq!(chowder["highlights"]["ingredients"][0], "<mark>clams</mark>");
+        assert_eq!(chowder["highlights"]["item_name"], "chowder");
+
+        let results = serde_json::to_value(run(&index, &request(serde_json::json!({ "text": "clam" })))).unwrap();
+        assert!(results["results"][0].get("highlights").is_none());
+    }
+
+    #[test]
+    fn test_validation() {
+        let invalid = [
+            serde_json::json!({ "limit": 0 }),
+            serde_json::json!({ "limit": 101 }),
+            serde_json::json!({ "text": (0..33).map(|x| format!("w{}", x)).collect::<Vec<_>>().join(" ") }),
+            serde_json::json!({ "filters": { "min_price": 10, "max_price": 5 } }),
+            serde_json::json!({ "filters": { "min_price": -1 } }),
+            serde_json::json!({ "filters": { "updated_from": "June 4th" } }),
+            serde_json::json!({ "filters": { "updated_from": "2024-06-05", "updated_to": "2024-06-04" } }),
+            serde_json::json!({ "filters": { "restaurants": [" "] } }),
+        ];
+        for x in invalid {
+            let request: SearchRequest = serde_json::from_value(x.clone()).unwrap();
+            assert!(request.validate().is_err(), "{} should be rejected", x);
+        }
+        assert!(serde_json::from_value::<SearchRequest>(serde_json::json!({ "sort": "price", "limt": 5 })).is_err());
+        assert!(serde_json::from_value::<SearchRequest>(serde_json::json!({ "sort": "popularity" })).is_err());
+    }
+}
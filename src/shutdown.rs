@@ -0,0 +1,169 @@
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+//Resolves on the first SIGINT (ctrl-c) or, on unix, SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Ctrl-c handler should have been installed.");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler should have been installed.")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received SIGINT, shutting down."),
+        _ = terminate => info!("Received SIGTERM, shutting down."),
+    }
+}
+
+//Serves the app until `shutdown` resolves, then stops accepting connections and waits for in-flight
+//requests to finish. If they take longer than `deadline`, they're dropped.
+//Returns whether everything drained in time.
+//Handlers and middleware can see the client's address through ConnectInfo<SocketAddr>.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+    deadline: Duration,
+) -> std::io::Result<bool> {
+    let (started_tx, mut started_rx) = watch::channel(false);
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            let _ = started_tx.send(true);
+        })
+        .into_future();
+    tokio::pin!(server);
+
+    let drain_timer = async {
+        //Only starts counting once shutdown begins. If the server finishes first the sender is
+        //dropped, but then the other branch has already won.
+        let _ = started_rx.wait_for(|x| *x).await;
+        info!("Draining in-flight requests, waiting up to {:?}.", deadline);
+        tokio::time::sleep(deadline).await;
+    };
+
+    tokio::select! {
+        res = &mut server => {
+            res?;
+            info!("All connections drained.");
+            Ok(true)
+        }
+        _ = drain_timer => {
+            warn!("Drain deadline passed, dropping the remaining connections.");
+            Ok(false)
+        }
+    }
+}
+
+//serve, over HTTPS. axum::serve only takes plain TCP, so this goes through axum-server instead,
+//with the same shutdown behaviour.
+pub async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    tls: RustlsConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+    deadline: Duration,
+) -> std::io::Result<bool> {
+    let handle = axum_server::Handle::new();
+    let server = axum_server::from_tcp_rustls(listener.into_std()?, tls)
+        .handle(handle.clone())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    tokio::pin!(server);
+
+    tokio::select! {
+        res = &mut server => return res.map(|_| true),
+        _ = shutdown => {}
+    }
+    //No new connections from here on, and idle ones are closed.
+    handle.graceful_shutdown(None);
+    info!("Draining in-flight requests, waiting up to {:?}.", deadline);
+
+    tokio::select! {
+        res = &mut server => {
+            res?;
+            info!("All connections drained.");
+            Ok(true)
+        }
+        _ = tokio::time::sleep(deadline) => {
+            warn!("Drain deadline passed, dropping the remaining connections.");
+            handle.shutdown();
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{Router, routing::get};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+
+    use crate::shutdown::serve;
+
+    //Sends a bare HTTP/1.1 request and returns the whole response.
+    async fn request(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n", path).as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    fn app(delay: Duration) -> Router {
+        Router::new().route("/slow", get(move || async move {
+            tokio::time::sleep(delay).await;
+            "done"
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_drains_in_flight_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app(Duration::from_millis(200)), async { let _ = rx.await; }, Duration::from_secs(5)));
+
+        let in_flight = tokio::spawn(request(addr, "/slow"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(()).unwrap();
+
+        assert!(in_flight.await.unwrap().ends_with("done"));
+        assert!(server.await.unwrap().unwrap());
+        //No longer accepting.
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app(Duration::from_secs(30)), async { let _ = rx.await; }, Duration::from_millis(100)));
+
+        let _in_flight = tokio::spawn(request(addr, "/slow"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(()).unwrap();
+
+        let drained = tokio::time::timeout(Duration::from_secs(5), server).await
+            .expect("Server should have stopped at the deadline.");
+        assert!(!drained.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,862 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{Connection, OptionalExtension, params, TransactionBehavior};
+use tracing::{trace, warn};
+
+use crate::db::{Db, DbError};
+use crate::index::snapshot_path;
+use crate::menu::Item;
+use crate::sync::{ChangeOp, Changes, SyncToken};
+
+//Everything the server needs from wherever the menu items are kept.
+//Items are keyed by Item::id. Methods are synchronous; from async code go through with_store so
+//they run on the blocking thread pool.
+pub trait MenuStore: Send + Sync {
+    //Adds the item unless one with the same id exists. Returns whether it was added.
+    fn insert(&self, item: &Item) -> Result<bool, DbError>;
+    //Adds the item, or replaces the stored one with the same id.
+    fn upsert(&self, item: &Item) -> Result<(), DbError>;
+    //Removes the item with this id. Returns whether there was one.
+    fn delete(&self, id: i64) -> Result<bool, DbError>;
+    fn list(&self) -> Result<Vec<Item>, DbError>;
+    fn get(&self, id: i64) -> Result<Option<Item>, DbError>;
+    //Increases with every change to the stored items, so anything derived from them can tell
+    //whether it's out of date.
+    fn change_counter(&self) -> Result<i64, DbError>;
+    //Random, picked when the store was created. Goes into sync tokens, ETags and the index
+    //snapshot, so nothing keyed on the change counter mixes up two databases.
+    fn database_id(&self) -> Result<i64, DbError>;
+    //What changed after the given sync token, up to the current one.
+    //No token, or one the store can't answer for, gets every item with `reset` set.
+    fn changes_since(&self, since: Option<SyncToken>) -> Result<Changes, DbError>;
+    //Drops all but the last `keep` changes from the change log. Tokens from before them get a
+    //reset from then on. Returns how many were dropped.
+    fn trim_changes(&self, keep: i64) -> Result<usize, DbError>;
+    //Every price each of the items has had, oldest first, ending with its current one. Kept after
+    //an item is deleted, and picked up again if it comes back. Ids without any are left out.
+    //Takes many ids so a page of items costs one call, not one each.
+    fn price_histories(&self, ids: &[i64]) -> Result<BTreeMap<i64, Vec<PricePoint>>, DbError>;
+
+    //Where the built index for this store should be saved, if anywhere.
+    fn snapshot_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    //Called once on shutdown, after the last request, to leave storage in a tidy state.
+    fn checkpoint(&self) -> Result<(), DbError> {
+        Ok(())
+    }
+
+    //Checks the store is reachable and in the shape this build expects, for /readyz.
+    fn check(&self) -> Result<(), DbError> {
+        Ok(())
+    }
+}
+
+pub type SharedStore = Arc<dyn MenuStore>;
+
+//A price an item had, with the `updated` date of the item when it was set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PricePoint {
+    pub price: String,
+    pub updated: String,
+}
+
+impl PricePoint {
+    fn of(item: &Item) -> Self {
+        PricePoint { price: item.price().to_string(), updated: item.updated().to_string() }
+    }
+}
+
+//Runs a closure against the store on Tokio's blocking thread pool, so handlers can use it without
+//stalling the async runtime (the SQLite store blocks on file I/O and the pool).
+pub async fn with_store<F, T>(store: &SharedStore, f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&dyn MenuStore) -> Result<T, DbError> + Send + 'static,
+    T: Send + 'static,
+{
+    blocking(store, move |x: &(dyn MenuStore + 'static)| f(x)).await
+}
+
+//Same thing for any of the other stores (webhooks, API keys).
+pub async fn blocking<S, F, T>(store: &Arc<S>, f: F) -> Result<T, DbError>
+where
+    S: ?Sized + Send + Sync + 'static,
+    F: FnOnce(&S) -> Result<T, DbError> + Send + 'static,
+    T: Send + 'static,
+{
+    let store = Arc::clone(store);
+    tokio::task::spawn_blocking(move || f(store.as_ref())).await?
+}
+
+//Bumped with every new entry in MIGRATIONS. Stored in SQLite's user_version.
+//  1: db_meta and the change counter
+//  2: change_log, for delta sync
+//  3: webhooks and webhook_deliveries
+//  4: api_keys
+//  5: price_history
+//  6: database_id
+pub const SCHEMA_VERSION: i64 = 6;
+
+//The items as JSON in a single SQLite table, through the connection pool.
+pub struct SqliteStore {
+    db: Db,
+    snapshot: bool,
+}
+
+impl SqliteStore {
+    //Opens (creating if needed) the database at the path.
+    pub fn open(path: &str) -> Result<SqliteStore, DbError> {
+        let db = Db::open(path)?;
+        ensure_db(&mut *db.get()?)?;
+        Ok(SqliteStore { db, snapshot: true })
+    }
+
+    //For the other things kept in the same database (see webhooks.rs).
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    //Turns off the saved index snapshot, so the index is always rebuilt from the table.
+    pub fn without_snapshot(mut self) -> Self {
+        self.snapshot = false;
+        self
+    }
+}
+
+impl MenuStore for SqliteStore {
+    fn insert(&self, item: &Item) -> Result<bool, DbError> {
+        //Ostensibly it checks for conflict first, so this minimizes operations on existing entries.
+        let changed = self.db.get()?.execute(
+            "INSERT INTO menu_db (id, item_data) VALUES (?1, ?2) ON CONFLICT(id) DO NOTHING",
+            params![item.id(), serde_json::to_value(item)?],
+        )?;
+        Ok(changed > 0)
+    }
+
+    fn upsert(&self, item: &Item) -> Result<(), DbError> {
+        self.db.get()?.execute(
+            "INSERT INTO menu_db (id, item_data) VALUES (?1, ?2) \
+            ON CONFLICT(id) DO UPDATE SET item_data = excluded.item_data",
+            params![item.id(), serde_json::to_value(item)?],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self, id: i64) -> Result<bool, DbError> {
+        let changed = self.db.get()?.execute("DELETE FROM menu_db WHERE id = ?1", [id])?;
+        Ok(changed > 0)
+    }
+
+    fn list(&self) -> Result<Vec<Item>, DbError> {
+        list_items(&*self.db.get()?)
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Item>, DbError> {
+        get_item(&*self.db.get()?, id)
+    }
+
+    fn change_counter(&self) -> Result<i64, DbError> {
+        read_meta(&*self.db.get()?, "change_counter")
+    }
+
+    fn database_id(&self) -> Result<i64, DbError> {
+        read_meta(&*self.db.get()?, "database_id")
+    }
+
+    //Everything is read in one transaction, so the token always matches the items sent with it.
+    fn changes_since(&self, since: Option<SyncToken>) -> Result<Changes, DbError> {
+        let mut connection = self.db.get()?;
+        let transaction = connection.transaction()?;
+        let token = SyncToken {
+            database: read_meta(&transaction, "database_id")?,
+            counter: read_meta(&transaction, "change_counter")?,
+        };
+        let log_start = read_meta(&transaction, "change_log_start")?;
+
+        let since = match since {
+            Some(x) if x.database == token.database && x.counter >= log_start && x.counter <= token.counter => x.counter,
+            _ => return Ok(Changes::everything(token, list_items(&transaction)?)),
+        };
+
+        let mut first_ops = BTreeMap::new();
+        {
+            let mut statement = transaction.prepare(
+                "SELECT item_id, op FROM change_log WHERE seq > ?1 ORDER BY seq",
+            )?;
+            let mut rows = statement.query([since])?;
+            while let Some(row) = rows.next()? {
+                let op = ChangeOp::parse(row.get_ref(1)?.as_str()?)?;
+                first_ops.entry(row.get::<_, i64>(0)?).or_insert(op);
+            }
+        }
+        Changes::from_log(token, first_ops, |id| get_item(&transaction, id))
+    }
+
+    //The log covers changes after change_log_start, so moving it up to the oldest change kept
+    //is what makes older tokens get a reset.
+    fn trim_changes(&self, keep: i64) -> Result<usize, DbError> {
+        let mut connection = self.db.get()?;
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let cutoff = read_meta(&transaction, "change_counter")?.saturating_sub(keep.max(0));
+        if cutoff <= read_meta(&transaction, "change_log_start")? {
+            return Ok(0);
+        }
+        let removed = transaction.execute("DELETE FROM change_log WHERE seq <= ?1", [cutoff])?;
+        transaction.execute("UPDATE db_meta SET value = ?1 WHERE key = 'change_log_start'", [cutoff])?;
+        transaction.commit()?;
+        Ok(removed)
+    }
+
+    //The ids go in as one JSON array, so any number of them is still a single statement.
+    fn price_histories(&self, ids: &[i64]) -> Result<BTreeMap<i64, Vec<PricePoint>>, DbError> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(
+            "SELECT item_id, price, updated FROM price_history
+            WHERE item_id IN (SELECT value FROM json_each(?1))
+            ORDER BY item_id, seq",
+        )?;
+        let ids = serde_json::Value::from(ids);
+        let mut rows = statement.query([ids])?;
+        let mut res: BTreeMap<i64, Vec<PricePoint>> = BTreeMap::new();
+        while let Some(row) = rows.next()? {
+            res.entry(row.get(0)?).or_default().push(PricePoint { price: row.get(1)?, updated: row.get(2)? });
+        }
+        Ok(res)
+    }
+
+    fn snapshot_path(&self) -> Option<PathBuf> {
+        self.snapshot.then(|| snapshot_path(self.db.path()))
+    }
+
+    //Copies everything in the WAL back into the database file and truncates the WAL, so the .sqlite
+    //file is complete on its own once we've exited.
+    fn checkpoint(&self) -> Result<(), DbError> {
+        let (busy, log, checkpointed): (i64, i64, i64) = self.db.get()?.query_row(
+            "PRAGMA wal_checkpoint(TRUNCATE)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        if busy != 0 {
+            return Err(format!("WAL checkpoint was blocked ({} of {} frames copied)", checkpointed, log).into());
+        }
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), DbError> {
+        let version = schema_version(&*self.db.get()?)?;
+        if version != SCHEMA_VERSION {
+            return Err(format!("Schema version is {}, expected {}", version, SCHEMA_VERSION).into());
+        }
+        Ok(())
+    }
+}
+
+fn list_items(connection: &Connection) -> Result<Vec<Item>, DbError> {
+    let mut statement = connection.prepare("SELECT item_data FROM menu_db")?;
+    let rows = statement.query_map([], |row| row.get::<_, serde_json::Value>(0))?;
+
+    let mut items: Vec<Item> = Vec::new();
+    for row in rows {
+        items.push(serde_json::from_value(row?)?);
+    }
+    Ok(items)
+}
+
+fn get_item(connection: &Connection, id: i64) -> Result<Option<Item>, DbError> {
+    let val: Option<serde_json::Value> = connection.query_row(
+        "SELECT item_data FROM menu_db WHERE id = ?1",
+        [id],
+        |row| row.get(0),
+    ).optional()?;
+
+    match val {
+        Some(x) => Ok(Some(serde_json::from_value(x)?)),
+        None => Ok(None),
+    }
+}
+
+fn read_meta(connection: &Connection, key: &str) -> Result<i64, DbError> {
+    let value = connection.query_row(
+        "SELECT value FROM db_meta WHERE key = ?1",
+        [key],
+        |row| row.get(0),
+    )?;
+    Ok(value)
+}
+
+//Brings the database up to SCHEMA_VERSION, one migration at a time. Each runs in its own
+//transaction together with the user_version bump, so a failed step leaves the database at the last
+//version that fully applied, and the next start picks up from there.
+//A database from a newer build is refused rather than used (and stamped) as if it were ours.
+fn ensure_db(connection: &mut Connection) -> Result<(), DbError> {
+    let mut version = schema_version(connection)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "Database is at schema version {}, but this build only knows up to {}. Refusing to open it.",
+            version, SCHEMA_VERSION,
+        ).into());
+    }
+    if version == SCHEMA_VERSION {
+        //Only(?) does anything if a logger is set up and running
+        //Notably, this means it doesn't show up in current tests.
+        trace!("Database found.");
+        return Ok(());
+    }
+
+    while version < SCHEMA_VERSION {
+        //Immediate, so two processes opening the same file can't both apply the same step. The
+        //version is read again once we hold the lock in case the other one got there first.
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        version = schema_version(&transaction)?;
+        if version >= SCHEMA_VERSION {
+            break;
+        }
+        warn!("Migrating the database schema from version {} to {}.", version, version + 1);
+        transaction.execute_batch(MIGRATIONS[version as usize])?;
+        transaction.pragma_update(None, "user_version", version + 1)?;
+        transaction.commit()?;
+        version += 1;
+    }
+    Ok(())
+}
+
+fn schema_version(connection: &Connection) -> Result<i64, DbError> {
+    Ok(connection.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+//MIGRATIONS[n] takes the schema from version n to n + 1.
+//Version 0 is a new file, or one from before the schema was versioned, which may already have
+//menu_db; the first step copes with both.
+const MIGRATIONS: [&str; SCHEMA_VERSION as usize] = [
+    //1: the items, db_meta and the change counter.
+    //Using a hash to determine uniqueness in the database, but otherwise shoving the entire
+    //struct in as a JSON object.
+    //I tried messed around with blobs, but it was a huge hassle dealing with that and serde
+    //Originally had each item field as a column, but I was converting things just to convert
+    //them back. (Which I'm still doing, but this is more straightforward).
+    // I *should* implement it that way for the sake of updating menus and general database
+    // nonsense, but that's not a current priority.
+    //The counter goes up with every change to menu_db, so anything built from it (like the saved
+    //index snapshot) can tell whether it's out of date.
+    "CREATE TABLE IF NOT EXISTS menu_db (
+        id  INTEGER PRIMARY KEY,
+        item_data   TEXT
+    );
+    CREATE TABLE IF NOT EXISTS db_meta (
+        key     TEXT PRIMARY KEY,
+        value   INTEGER NOT NULL
+    );
+    INSERT OR IGNORE INTO db_meta (key, value) VALUES ('change_counter', 0);
+    DROP TRIGGER IF EXISTS menu_db_insert;
+    CREATE TRIGGER menu_db_insert AFTER INSERT ON menu_db BEGIN
+        UPDATE db_meta SET value = value + 1 WHERE key = 'change_counter';
+    END;
+    DROP TRIGGER IF EXISTS menu_db_update;
+    CREATE TRIGGER menu_db_update AFTER UPDATE ON menu_db BEGIN
+        UPDATE db_meta SET value = value + 1 WHERE key = 'change_counter';
+    END;
+    DROP TRIGGER IF EXISTS menu_db_delete;
+    CREATE TRIGGER menu_db_delete AFTER DELETE ON menu_db BEGIN
+        UPDATE db_meta SET value = value + 1 WHERE key = 'change_counter';
+    END;",
+    //2: change_log, for delta sync.
+    //Each change is logged under the counter value it produced. The log only covers changes from
+    //change_log_start on, which for a database from before the log existed is wherever its counter
+    //was when it got one.
+    "CREATE TABLE IF NOT EXISTS change_log (
+        seq     INTEGER PRIMARY KEY,
+        item_id INTEGER NOT NULL,
+        op      TEXT NOT NULL
+    );
+    INSERT OR IGNORE INTO db_meta (key, value)
+        SELECT 'change_log_start', value FROM db_meta WHERE key = 'change_counter';
+    DROP TRIGGER IF EXISTS menu_db_insert;
+    CREATE TRIGGER menu_db_insert AFTER INSERT ON menu_db BEGIN
+        UPDATE db_meta SET value = value + 1 WHERE key = 'change_counter';
+        INSERT INTO change_log (seq, item_id, op)
+            SELECT value, NEW.id, 'insert' FROM db_meta WHERE key = 'change_counter';
+    END;
+    DROP TRIGGER IF EXISTS menu_db_update;
+    CREATE TRIGGER menu_db_update AFTER UPDATE ON menu_db BEGIN
+        UPDATE db_meta SET value = value + 1 WHERE key = 'change_counter';
+        INSERT INTO change_log (seq, item_id, op)
+            SELECT value, NEW.id, 'update' FROM db_meta WHERE key = 'change_counter';
+    END;
+    DROP TRIGGER IF EXISTS menu_db_delete;
+    CREATE TRIGGER menu_db_delete AFTER DELETE ON menu_db BEGIN
+        UPDATE db_meta SET value = value + 1 WHERE key = 'change_counter';
+        INSERT INTO change_log (seq, item_id, op)
+            SELECT value, OLD.id, 'delete' FROM db_meta WHERE key = 'change_counter';
+    END;",
+    //3: registered webhooks and the log of every attempt to deliver to them (see webhooks.rs).
+    //`events` and `restaurants` are JSON arrays of filters; empty means everything.
+    "CREATE TABLE IF NOT EXISTS webhooks (
+        id          INTEGER PRIMARY KEY,
+        url         TEXT NOT NULL,
+        secret      TEXT NOT NULL,
+        events      TEXT NOT NULL,
+        restaurants TEXT NOT NULL,
+        created     TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS webhook_deliveries (
+        id          INTEGER PRIMARY KEY,
+        webhook_id  INTEGER NOT NULL,
+        event       TEXT NOT NULL,
+        attempt     INTEGER NOT NULL,
+        status      INTEGER,
+        error       TEXT,
+        succeeded   INTEGER NOT NULL,
+        at          TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS webhook_deliveries_by_webhook ON webhook_deliveries (webhook_id, id);",
+    //4: API keys, by the SHA-256 of the key (see auth.rs). Revoked keys are kept for the record.
+    "CREATE TABLE IF NOT EXISTS api_keys (
+        id          INTEGER PRIMARY KEY,
+        name        TEXT NOT NULL,
+        scope       TEXT NOT NULL,
+        prefix      TEXT NOT NULL,
+        hash        TEXT NOT NULL UNIQUE,
+        created     TEXT NOT NULL,
+        revoked     TEXT
+    );",
+    //5: every price each item has had, written by triggers so nothing can change a price without
+    //it being recorded. Items already stored start with their current price.
+    //Prices are only logged when they change (or the item is new), not on every update.
+    "CREATE TABLE IF NOT EXISTS price_history (
+        seq     INTEGER PRIMARY KEY,
+        item_id INTEGER NOT NULL,
+        price   TEXT NOT NULL,
+        updated TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS price_history_by_item ON price_history (item_id, seq);
+    INSERT INTO price_history (item_id, price, updated)
+        SELECT id, json_extract(item_data, '$.price'), json_extract(item_data, '$.updated') FROM menu_db
+        WHERE id NOT IN (SELECT item_id FROM price_history);
+    DROP TRIGGER IF EXISTS menu_db_price_insert;
+    CREATE TRIGGER menu_db_price_insert AFTER INSERT ON menu_db
+    WHEN json_extract(NEW.item_data, '$.price') IS NOT (
+        SELECT price FROM price_history WHERE item_id = NEW.id ORDER BY seq DESC LIMIT 1
+    ) BEGIN
+        INSERT INTO price_history (item_id, price, updated)
+            VALUES (NEW.id, json_extract(NEW.item_data, '$.price'), json_extract(NEW.item_data, '$.updated'));
+    END;
+    DROP TRIGGER IF EXISTS menu_db_price_update;
+    CREATE TRIGGER menu_db_price_update AFTER UPDATE ON menu_db
+    WHEN json_extract(NEW.item_data, '$.price') IS NOT json_extract(OLD.item_data, '$.price') BEGIN
+        INSERT INTO price_history (item_id, price, updated)
+            VALUES (NEW.id, json_extract(NEW.item_data, '$.price'), json_extract(NEW.item_data, '$.updated'));
+    END;",
+    //6: a random id for the database (see MenuStore::database_id).
+    "INSERT OR IGNORE INTO db_meta (key, value) VALUES ('database_id', random());",
+];
+
+//Keeps everything in a map, for tests and throwaway demo servers.
+//Nothing is saved, and there's no index snapshot.
+pub struct MemoryStore {
+    inner: Mutex<MemoryInner>,
+}
+
+#[derive(Default)]
+struct MemoryInner {
+    items: BTreeMap<i64, Item>,
+    database_id: i64,
+    change_counter: i64,
+    //Same as the SQLite change_log: (counter after the change, item id, op), covering the changes
+    //after log_start.
+    log: Vec<(i64, i64, ChangeOp)>,
+    log_start: i64,
+    //Same as the SQLite price_history.
+    prices: BTreeMap<i64, Vec<PricePoint>>,
+}
+
+impl MemoryInner {
+    fn changed(&mut self, id: i64, op: ChangeOp) {
+        self.change_counter += 1;
+        let seq = self.change_counter;
+        self.log.push((seq, id, op));
+    }
+
+    fn priced(&mut self, item: &Item) {
+        let history = self.prices.entry(item.id()).or_default();
+        if history.last().map(|x| x.price.as_str()) != Some(item.price()) {
+            history.push(PricePoint::of(item));
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        let inner = MemoryInner { database_id: rand::random(), ..MemoryInner::default() };
+        MemoryStore { inner: Mutex::new(inner) }
+    }
+
+    //A poisoned lock only means another thread panicked mid-call; the map itself is never left
+    //half updated, so it's fine to keep using it.
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MenuStore for MemoryStore {
+    fn insert(&self, item: &Item) -> Result<bool, DbError> {
+        let mut inner = self.lock();
+        if inner.items.contains_key(&item.id()) {
+            return Ok(false);
+        }
+        inner.items.insert(item.id(), item.clone());
+        inner.changed(item.id(), ChangeOp::Insert);
+        inner.priced(item);
+        Ok(true)
+    }
+
+    fn upsert(&self, item: &Item) -> Result<(), DbError> {
+        let mut inner = self.lock();
+        let op = match inner.items.entry(item.id()) {
+            Entry::Occupied(mut x) => {
+                x.insert(item.clone());
+                ChangeOp::Update
+            }
+            Entry::Vacant(x) => {
+                x.insert(item.clone());
+                ChangeOp::Insert
+            }
+        };
+        inner.changed(item.id(), op);
+        inner.priced(item);
+        Ok(())
+    }
+
+    fn delete(&self, id: i64) -> Result<bool, DbError> {
+        let mut inner = self.lock();
+        let removed = inner.items.remove(&id).is_some();
+        if removed {
+            inner.changed(id, ChangeOp::Delete);
+        }
+        Ok(removed)
+    }
+
+    fn list(&self) -> Result<Vec<Item>, DbError> {
+        Ok(self.lock().items.values().cloned().collect())
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Item>, DbError> {
+        Ok(self.lock().items.get(&id).cloned())
+    }
+
+    fn change_counter(&self) -> Result<i64, DbError> {
+        Ok(self.lock().change_counter)
+    }
+
+    fn database_id(&self) -> Result<i64, DbError> {
+        Ok(self.lock().database_id)
+    }
+
+    fn changes_since(&self, since: Option<SyncToken>) -> Result<Changes, DbError> {
+        let inner = self.lock();
+        let token = SyncToken { database: inner.database_id, counter: inner.change_counter };
+        let since = match since {
+            Some(x) if x.database == token.database && (inner.log_start..=token.counter).contains(&x.counter) => x.counter,
+            _ => return Ok(Changes::everything(token, inner.items.values().cloned().collect())),
+        };
+
+        let mut first_ops = BTreeMap::new();
+        //The log is in counter order, so everything after `since` is one contiguous tail.
+        let start = inner.log.partition_point(|x| x.0 <= since);
+        for &(_, id, op) in &inner.log[start..] {
+            first_ops.entry(id).or_insert(op);
+        }
+        Changes::from_log(token, first_ops, |id| Ok(inner.items.get(&id).cloned()))
+    }
+
+    fn trim_changes(&self, keep: i64) -> Result<usize, DbError> {
+        let mut inner = self.lock();
+        let cutoff = inner.change_counter.saturating_sub(keep.max(0));
+        if cutoff <= inner.log_start {
+            return Ok(0);
+        }
+        let removed = inner.log.partition_point(|x| x.0 <= cutoff);
+        inner.log.drain(..removed);
+        inner.log_start = cutoff;
+        Ok(removed)
+    }
+
+    fn price_histories(&self, ids: &[i64]) -> Result<BTreeMap<i64, Vec<PricePoint>>, DbError> {
+        let inner = self.lock();
+        Ok(ids.iter().filter_map(|x| Some((*x, inner.prices.get(x)?.clone()))).collect())
+    }
+}
+
+//A SQLite database in the temp directory, deleted (along with its WAL files and index snapshot)
+//when dropped. Each test gets its own, so tests can run in parallel without stepping on each other.
+#[cfg(test)]
+pub(crate) struct TempDb {
+    pub path: String,
+}
+
+#[cfg(test)]
+impl TempDb {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("menu_manager_{}_{}.sqlite", name, std::process::id()));
+        let temp = TempDb { path: path.to_str().unwrap().to_string() };
+        temp.remove_files();
+        temp
+    }
+
+    fn remove_files(&self) {
+        for suffix in ["", "-wal", "-shm", ".index"] {
+            let _ = std::fs::remove_file(format!("{}{}", self.path, suffix));
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        self.remove_files();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::menu::Item;
+    use crate::store::{MemoryStore, MenuStore, SqliteStore, TempDb, SCHEMA_VERSION};
+    use crate::sync::SyncToken;
+
+    fn item(name: &str, price: &str) -> Item {
+        serde_json::from_value(serde_json::json!({
+            "item_name": name,
+            "ingredients": ["cream", "leek"],
+            "updated": "2024-06-04",
+            "price": price,
+            "restaurant": "Lark",
+        })).unwrap()
+    }
+
+    //Runs the same checks against any store.
+    fn exercise(store: &dyn MenuStore) {
+        let stew = item("Oyster stew", "18");
+        let start = store.change_counter().unwrap();
+
+        assert!(store.insert(&stew).unwrap());
+        assert!(!store.insert(&stew).unwrap());
+        assert_eq!(store.get(stew.id()).unwrap(), Some(stew.clone()));
+        let after_insert = store.change_counter().unwrap();
+        assert_eq!(after_insert, start + 1);
+
+        //Price isn't part of the id, so this replaces the stored item.
+        let pricier = item("Oyster stew", "21");
+        assert_eq!(pricier.id(), stew.id());
+        store.upsert(&pricier).unwrap();
+        assert_eq!(store.get(stew.id()).unwrap(), Some(pricier.clone()));
+        assert!(store.change_counter().unwrap() > after_insert);
+
+        store.upsert(&item("Leek soup", "12")).unwrap();
+        assert_eq!(store.list().unwrap().len(), 2);
+
+        let before_delete = store.change_counter().unwrap();
+        assert!(store.delete(stew.id()).unwrap());
+        assert!(!store.delete(stew.id()).unwrap());
+        assert_eq!(store.get(stew.id()).unwrap(), None);
+        assert_eq!(store.list().unwrap(), vec![item("Leek soup", "12")]);
+        assert_eq!(store.change_counter().unwrap(), before_delete + 1);
+
+        //The history outlives the item, and only grows when the price changes.
+        let prices = |id| store.price_histories(&[id]).unwrap().remove(&id).unwrap_or_default().into_iter().map(|x| x.price).collect::<Vec<_>>();
+        assert_eq!(prices(stew.id()), ["18", "21"]);
+        store.insert(&pricier).unwrap();
+        store.upsert(&pricier).unwrap();
+        assert_eq!(prices(stew.id()), ["18", "21"]);
+        store.upsert(&stew).unwrap();
+        assert_eq!(prices(stew.id()), ["18", "21", "18"]);
+        assert!(store.price_histories(&[0]).unwrap().is_empty());
+        let soup = item("Leek soup", "12").id();
+        let both = store.price_histories(&[stew.id(), 0, soup]).unwrap();
+        assert_eq!(both.len(), 2);
+        assert_eq!(both[&stew.id()].len(), 3);
+        assert_eq!(both[&soup].len(), 1);
+    }
+
+    //Runs the same delta sync checks against any store.
+    fn exercise_sync(store: &dyn MenuStore) {
+        let stew = item("Oyster stew", "18");
+        let soup = item("Leek soup", "12");
+        let tart = item("Leek tart", "14");
+        store.insert(&stew).unwrap();
+        store.insert(&soup).unwrap();
+
+        let full = store.changes_since(None).unwrap();
+        assert!(full.reset);
+        assert_eq!(full.added.len(), 2);
+        let token = full.token;
+        assert_eq!(token, SyncToken { database: store.database_id().unwrap(), counter: store.change_counter().unwrap() });
+
+        let none = store.changes_since(Some(token)).unwrap();
+        assert!(!none.reset);
+        assert_eq!((none.added.len(), none.changed.len(), none.deleted.len()), (0, 0, 0));
+
+        store.upsert(&item("Oyster stew", "21")).unwrap();
+        store.delete(soup.id()).unwrap();
+        store.insert(&tart).unwrap();
+        //Added and gone again before the client looked, so it never hears about it.
+        let special = item("Special", "30");
+        store.insert(&special).unwrap();
+        store.delete(special.id()).unwrap();
+
+        let delta = store.changes_since(Some(token)).unwrap();
+        assert!(!delta.reset);
+        assert_eq!(delta.token.counter, store.change_counter().unwrap());
+        assert_eq!(delta.added, vec![(tart.id(), tart.clone())]);
+        assert_eq!(delta.changed, vec![(stew.id(), item("Oyster stew", "21"))]);
+        assert_eq!(delta.deleted, vec![soup.id()]);
+
+        //A token from the future, or from another database at a counter this one has been at,
+        //starts the client over.
+        let future = SyncToken { counter: delta.token.counter + 100, ..delta.token };
+        assert!(store.changes_since(Some(future)).unwrap().reset);
+        let elsewhere = SyncToken { database: token.database.wrapping_add(1), ..token };
+        assert!(store.changes_since(Some(elsewhere)).unwrap().reset);
+
+        //Trimming keeps the last changes answerable, and resets anything older.
+        let before = store.changes_since(Some(token)).unwrap();
+        assert!(store.trim_changes(2).unwrap() > 0);
+        assert_eq!(store.trim_changes(2).unwrap(), 0);
+        assert!(store.changes_since(Some(token)).unwrap().reset);
+        let recent = SyncToken { counter: delta.token.counter - 2, ..delta.token };
+        let after = store.changes_since(Some(recent)).unwrap();
+        assert!(!after.reset);
+        assert_ne!(after, before);
+        assert!(!store.changes_since(Some(delta.token)).unwrap().reset);
+    }
+
+    #[test]
+    fn test_db_setup() {
+        let temp = TempDb::new("setup");
+        SqliteStore::open(&temp.path).expect("Failed");
+        //Opening an existing database shouldn't try to create anything twice.
+        SqliteStore::open(&temp.path).expect("Failed");
+    }
+
+    //A database from before the change log only gets deltas from when it was upgraded.
+    #[test]
+    fn test_change_log_upgrade() {
+        let temp = TempDb::new("change_log_upgrade");
+        let store = SqliteStore::open(&temp.path).unwrap();
+        store.insert(&item("Oyster stew", "18")).unwrap();
+        store.db.get().unwrap().execute_batch(
+            "DROP TABLE change_log; DELETE FROM db_meta WHERE key = 'change_log_start'; PRAGMA user_version = 1;",
+        ).unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&temp.path).unwrap();
+        let token = |counter| Some(SyncToken { database: store.database_id().unwrap(), counter });
+        let counter = store.change_counter().unwrap();
+        assert!(store.changes_since(token(counter - 1)).unwrap().reset);
+        store.insert(&item("Leek soup", "12")).unwrap();
+        let delta = store.changes_since(token(counter)).unwrap();
+        assert!(!delta.reset);
+        assert_eq!(delta.added.len(), 1);
+    }
+
+    //Items from before the price history start with their current price.
+    #[test]
+    fn test_price_history_upgrade() {
+        let temp = TempDb::new("price_history_upgrade");
+        let store = SqliteStore::open(&temp.path).unwrap();
+        let stew = item("Oyster stew", "18");
+        store.insert(&stew).unwrap();
+        store.db.get().unwrap().execute_batch("DROP TABLE price_history; PRAGMA user_version = 4;").unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&temp.path).unwrap();
+        let history = store.price_histories(&[stew.id()]).unwrap().remove(&stew.id()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].price.as_str(), history[0].updated.as_str()), ("18", "2024-06-04"));
+    }
+
+    //A file from before the schema was versioned has only menu_db, and is brought all the way up.
+    #[test]
+    fn test_migrate_unversioned() {
+        let temp = TempDb::new("migrate_unversioned");
+        let stew = item("Oyster stew", "18");
+        let connection = rusqlite::Connection::open(&temp.path).unwrap();
+        connection.execute_batch("CREATE TABLE menu_db (id INTEGER PRIMARY KEY, item_data TEXT);").unwrap();
+        connection.execute(
+            "INSERT INTO menu_db (id, item_data) VALUES (?1, ?2)",
+            rusqlite::params![stew.id(), serde_json::to_value(&stew).unwrap()],
+        ).unwrap();
+        drop(connection);
+
+        let store = SqliteStore::open(&temp.path).unwrap();
+        store.check().unwrap();
+        assert_eq!(store.list().unwrap(), vec![stew.clone()]);
+        assert_eq!(store.price_histories(&[stew.id()]).unwrap()[&stew.id()].len(), 1);
+        store.insert(&item("Leek soup", "12")).unwrap();
+        assert_eq!(store.change_counter().unwrap(), 1);
+    }
+
+    //A database a newer build has migrated isn't ours to touch.
+    #[test]
+    fn test_newer_schema_refused() {
+        let temp = TempDb::new("newer_schema");
+        let store = SqliteStore::open(&temp.path).unwrap();
+        store.db.get().unwrap().pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        assert!(store.check().is_err());
+        drop(store);
+
+        assert!(SqliteStore::open(&temp.path).is_err());
+        let connection = rusqlite::Connection::open(&temp.path).unwrap();
+        let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION + 1);
+    }
+
+    //A step that fails leaves the database at the last version that applied.
+    #[test]
+    fn test_failed_migration() {
+        let temp = TempDb::new("failed_migration");
+        let store = SqliteStore::open(&temp.path).unwrap();
+        //Step 5 can't index or fill price_history when it's a view.
+        store.db.get().unwrap().execute_batch(
+            "DROP TABLE price_history; CREATE VIEW price_history AS SELECT 1 AS item_id; PRAGMA user_version = 4;",
+        ).unwrap();
+        drop(store);
+
+        assert!(SqliteStore::open(&temp.path).is_err());
+        let connection = rusqlite::Connection::open(&temp.path).unwrap();
+        let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, 4);
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let temp = TempDb::new("sqlite_store");
+        let store = SqliteStore::open(&temp.path).unwrap();
+        exercise(&store);
+        exercise_sync(&store);
+        store.checkpoint().unwrap();
+        assert_eq!(std::fs::metadata(format!("{}-wal", temp.path)).unwrap().len(), 0);
+        store.check().unwrap();
+        store.db.get().unwrap().pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        assert!(store.check().is_err());
+        assert!(store.snapshot_path().is_some());
+        assert!(store.without_snapshot().snapshot_path().is_none());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::new();
+        exercise(&store);
+        exercise_sync(&store);
+        assert!(store.snapshot_path().is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Query, State};
+use axum::extract::rejection::QueryRejection;
+use axum::http::{self, HeaderMap, HeaderValue};
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::cache::CachePolicy;
+use crate::error::{AppError, ErrorBody};
+use crate::index::{Index, SharedIndex};
+use crate::menu::Item;
+
+pub const NDJSON: &str = "application/x-ndjson";
+
+//Items serialized per chunk of a streamed response. Big enough that chunks aren't mostly framing,
+//small enough that memory stays flat however many items there are.
+const CHUNK_ITEMS: usize = 256;
+
+//How a list of items goes out: one JSON array, or newline-delimited JSON with an item per line.
+//NDJSON is streamed straight from the index snapshot, so nothing the size of the whole result is
+//ever built, and clients can start on the first items before the last are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Ndjson,
+}
+
+impl Format {
+    //`?format=` wins, then an Accept header asking for NDJSON. JSON otherwise.
+    pub fn negotiate(explicit: Option<Format>, headers: &HeaderMap) -> Format {
+        if let Some(x) = explicit {
+            return x;
+        }
+        let accepts_ndjson = headers.get_all(http::header::ACCEPT)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .any(|x| x.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(NDJSON));
+        if accepts_ndjson { Format::Ndjson } else { Format::Json }
+    }
+
+    //Goes into the ETag, so the JSON and NDJSON versions of a response are told apart.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+        }
+    }
+
+    //The items with these ids, in this format.
+    pub fn respond(self, index: Arc<Index>, ids: Vec<u32>) -> Response {
+        match self {
+            Format::Json => {
+                let items: Vec<&Item> = ids.iter().map(|&x| index.item(x)).collect();
+                Json(items).into_response()
+            }
+            Format::Ndjson => ndjson(index, ids),
+        }
+    }
+}
+
+//Streams the items as NDJSON, serializing a chunk at a time as the client reads.
+//Holds on to the snapshot until it's done, so a rebuild mid-stream doesn't change what's sent.
+fn ndjson(index: Arc<Index>, ids: Vec<u32>) -> Response {
+    let chunks = futures_util::stream::unfold(0, move |start| {
+        let index = Arc::clone(&index);
+        let end = (start + CHUNK_ITEMS).min(ids.len());
+        let chunk = (start < ids.len()).then(|| {
+            let mut buf = Vec::new();
+            for &id in &ids[start..end] {
+                //Items are plain data, serializing them can't fail.
+                serde_json::to_writer(&mut buf, index.item(id)).expect("Items should always serialize.");
+                buf.push(b'\n');
+            }
+            (Ok::<_, Infallible>(Bytes::from(buf)), end)
+        });
+        async move { chunk }
+    });
+    let mut response = Body::from_stream(chunks).into_response();
+    response.headers_mut().insert(http::header::CONTENT_TYPE, HeaderValue::from_static(NDJSON));
+    response
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportOptions {
+    format: Option<Format>,
+}
+
+//Handler for GET /export, every item in the catalog. NDJSON unless asked for JSON, since this is
+//the one response guaranteed to be big.
+//Comes from the index like search, so it's as current as search is, and gets the same ETag.
+#[utoipa::path(
+    get,
+    path = "/export",
+    tag = "search",
+    summary = "Every item in the catalog",
+    description = "NDJSON (an item per line) unless `format=json` asks for a single array.",
+    params(ExportOptions),
+    responses(
+        (status = 200, description = "Every item", content(
+            (Item = "application/x-ndjson"),
+            (Vec<Item> = "application/json"),
+        ), headers(("etag" = String))),
+        (status = 304, description = "Unchanged since the ETag in If-None-Match"),
+        (status = 400, description = "Bad query string", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = [])),
+)]
+pub async fn export(
+    options: Result<Query<ExportOptions>, QueryRejection>,
+    headers: HeaderMap,
+    State(index): State<SharedIndex>,
+    State(cache): State<CachePolicy>,
+) -> Result<Response, AppError> {
+    let Query(options) = options?;
+    let index = index.snapshot();
+    let format = options.format.unwrap_or(Format::Ndjson);
+    cache.respond(&headers, CachePolicy::etag(index.database(), index.version(), format.as_str()), || {
+        let ids = (0..index.item_count() as u32).collect();
+        Ok(format.respond(Arc::clone(&index), ids))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::{self, HeaderMap, HeaderValue};
+
+    use crate::index::Index;
+    use crate::menu::Item;
+    use crate::stream::{Format, CHUNK_ITEMS, NDJSON};
+
+    #[test]
+    fn test_negotiate() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Format::negotiate(None, &headers), Format::Json);
+        headers.insert(http::header::ACCEPT, HeaderValue::from_static("application/json, application/x-ndjson;q=0.9"));
+        assert_eq!(Format::negotiate(None, &headers), Format::Ndjson);
+        assert_eq!(Format::negotiate(Some(Format::Json), &headers), Format::Json);
+    }
+
+    #[tokio::test]
+    async fn test_ndjson() {
+        let items: Vec<Item> = (0..CHUNK_ITEMS * 2 + 3)
+            .map(|x| serde_json::from_value(serde_json::json!({
+                "item_name": format!("item {}", x),
+                "ingredients": [],
+                "updated": "2024-06-04",
+                "price": "1",
+                "restaurant": "Lark",
+            })).unwrap())
+            .collect();
+        let index = Arc::new(Index::build(items));
+        let ids: Vec<u32> = (0..index.item_count() as u32).collect();
+
+        let response = Format::Ndjson.respond(Arc::clone(&index), ids.clone());
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], NDJSON);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let lines: Vec<Item> = String::from_utf8(bytes.to_vec()).unwrap()
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        let expected: Vec<Item> = ids.iter().map(|&x| index.item(x).clone()).collect();
+        assert_eq!(lines, expected);
+
+        let response = Format::Ndjson.respond(index, Vec::new());
+        assert!(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::extract::rejection::QueryRejection;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::db::DbError;
+use crate::error::{AppError, ErrorBody};
+use crate::menu::Item;
+use crate::store::{with_store, SharedStore};
+
+//Delta sync for clients that keep their own copy of the catalog.
+//Stores log every change to an item, numbered by the change counter it bumped the store to. A sync
+//token is that counter plus the id of the database it came from, so "changes since a token" is every
+//logged change with a higher number, as long as it's the same database.
+//A client without a token, or with one we can't answer for (older than the log, or from some
+//other database), gets everything with `reset` set and should replace its copy.
+//The log is trimmed to the last `sync_log_retention` changes now and then, so clients that haven't
+//synced in a long while start over too.
+
+//How often the change log is trimmed.
+const TRIM_EVERY: Duration = Duration::from_secs(60 * 60);
+
+//Where a client is up to. Sent as `<database id in hex>-<change counter>`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyncToken {
+    //Random, picked when the database is created, so a token can't be mistaken for one from a
+    //recreated or swapped database that happens to be at a similar counter.
+    pub database: i64,
+    pub counter: i64,
+}
+
+impl fmt::Display for SyncToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}-{}", self.database as u64, self.counter)
+    }
+}
+
+impl FromStr for SyncToken {
+    type Err = AppError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let invalid = || AppError::BadRequest(format!("\"{}\" isn't a sync token", token));
+        let (database, counter) = token.split_once('-').ok_or_else(invalid)?;
+        Ok(SyncToken {
+            database: u64::from_str_radix(database, 16).map_err(|_| invalid())? as i64,
+            counter: counter.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl ChangeOp {
+    //As written by the SQLite triggers.
+    pub fn parse(op: &str) -> Result<ChangeOp, DbError> {
+        match op {
+            "insert" => Ok(ChangeOp::Insert),
+            "update" => Ok(ChangeOp::Update),
+            "delete" => Ok(ChangeOp::Delete),
+            x => Err(format!("Unknown change log op \"{}\"", x).into()),
+        }
+    }
+}
+
+//What changed between a client's token and `token`. Items are paired with their ids.
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    pub token: SyncToken,
+    pub reset: bool,
+    pub added: Vec<(i64, Item)>,
+    pub changed: Vec<(i64, Item)>,
+    pub deleted: Vec<i64>,
+}
+
+impl Changes {
+    //Every item, for clients starting over.
+    pub fn everything(token: SyncToken, items: Vec<Item>) -> Changes {
+        Changes {
+            token,
+            reset: true,
+            added: items.into_iter().map(|x| (x.id(), x)).collect(),
+            ..Changes::default()
+        }
+    }
+
+    //Works out the changes from the first logged op for each item after the client's token, and what
+    //(if anything) is stored for it now. Only the first op matters for whether the client has the
+    //item, and only the current state matters for what it should end up with. Items added and
+    //removed again in between never reach the client at all.
+    pub fn from_log(
+        token: SyncToken,
+        first_ops: BTreeMap<i64, ChangeOp>,
+        mut current: impl FnMut(i64) -> Result<Option<Item>, DbError>,
+    ) -> Result<Changes, DbError> {
+        let mut changes = Changes { token, ..Changes::default() };
+        for (id, op) in first_ops {
+            let existed = op != ChangeOp::Insert;
+            match (existed, current(id)?) {
+                (false, Some(item)) => changes.added.push((id, item)),
+                (true, Some(item)) => changes.changed.push((id, item)),
+                (true, None) => changes.deleted.push(id),
+                (false, None) => {}
+            }
+        }
+        Ok(changes)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SyncOptions {
+    //The token from the last sync. Left out to get everything.
+    #[param(example = "5f1d3c0a9b8e7d6c-42")]
+    since: Option<String>,
+}
+
+//Ids and tokens go out as strings: ids use the full 64 bits, which a JavaScript number can't hold.
+#[derive(Debug, Serialize, ToSchema)]
+struct SyncResponse<'a> {
+    #[schema(example = "5f1d3c0a9b8e7d6c-42")]
+    token: String,
+    reset: bool,
+    added: Vec<SyncItem<'a>>,
+    changed: Vec<SyncItem<'a>>,
+    deleted: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct SyncItem<'a> {
+    #[schema(example = "7140986328187424601")]
+    id: String,
+    item: &'a Item,
+}
+
+impl<'a> SyncResponse<'a> {
+    fn new(changes: &'a Changes) -> Self {
+        let items = |list: &'a [(i64, Item)]| {
+            list.iter().map(|(id, item)| SyncItem { id: id.to_string(), item }).collect()
+        };
+        SyncResponse {
+            token: changes.token.to_string(),
+            reset: changes.reset,
+            added: items(&changes.added),
+            changed: items(&changes.changed),
+            deleted: changes.deleted.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+//Handler for GET /sync?since=<token>.
+//Read straight from the store rather than the index, so a client is never handed a token newer than
+//the data it got.
+#[utoipa::path(
+    get,
+    path = "/sync",
+    tag = "sync",
+    summary = "Changes since a sync token",
+    description = "With `reset` set, the client should replace its copy with `added`.",
+    params(SyncOptions),
+    responses(
+        (status = 200, description = "What changed, and the token to pass next time", body = SyncResponse),
+        (status = 400, description = "Bad query string, or a token that isn't one", body = ErrorBody),
+    ),
+    security((), ("bearer" = []), ("api_key" = [])),
+)]
+pub async fn sync(
+    options: Result<Query<SyncOptions>, QueryRejection>,
+    State(store): State<SharedStore>,
+) -> Result<Response, AppError> {
+    let Query(options) = options?;
+    let since = options.since.map(|x| x.parse::<SyncToken>()).transpose()?;
+    let changes = with_store(&store, move |store| store.changes_since(since)).await?;
+    Ok(Json(SyncResponse::new(&changes)).into_response())
+}
+
+//Trims the change log to the last `keep` changes now, and then every TRIM_EVERY. 0 keeps everything.
+pub fn spawn_log_trimmer(store: SharedStore, keep: u64) {
+    if keep == 0 {
+        return;
+    }
+    let keep = keep.min(i64::MAX as u64) as i64;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TRIM_EVERY);
+        loop {
+            interval.tick().await;
+            match with_store(&store, move |store| store.trim_changes(keep)).await {
+                Ok(0) => {}
+                Ok(x) => tracing::info!("Trimmed {} old changes from the sync log.", x),
+                Err(e) => tracing::error!("Couldn't trim the sync log: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::menu::Item;
+    use crate::sync::{ChangeOp, Changes, SyncToken};
+
+    #[test]
+    fn test_from_log() {
+        let kept = Item::default();
+        let first_ops = BTreeMap::from([
+            (1, ChangeOp::Insert),
+            (2, ChangeOp::Update),
+            (3, ChangeOp::Delete),
+            (4, ChangeOp::Insert),
+            (5, ChangeOp::Delete),
+        ]);
+        //1 and 2 are still stored, 3 and 4 aren't, and 5 was deleted then added back.
+        let token = SyncToken { database: 7, counter: 9 };
+        let changes = Changes::from_log(token, first_ops, |id| {
+            Ok([1, 2, 5].contains(&id).then(|| kept.clone()))
+        }).unwrap();
+
+        assert_eq!(changes, Changes {
+            token,
+            reset: false,
+            added: vec![(1, kept.clone())],
+            changed: vec![(2, kept.clone()), (5, kept.clone())],
+            deleted: vec![3],
+        });
+    }
+
+    #[test]
+    fn test_token() {
+        let token = SyncToken { database: -2, counter: 42 };
+        assert_eq!(token.to_string(), "fffffffffffffffe-42");
+        assert_eq!("fffffffffffffffe-42".parse::<SyncToken>().unwrap(), token);
+        for bad in ["42", "-42", "xyz-42", "00-", "00-4.2"] {
+            assert!(bad.parse::<SyncToken>().is_err(), "{}", bad);
+        }
+    }
+}
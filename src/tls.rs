@@ -0,0 +1,131 @@
+use std::io;
+
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{error, info};
+
+//HTTPS for small deployments that don't have a proxy in front to terminate TLS.
+//The certificate and key are PEM files (e.g. from certbot: fullchain.pem and privkey.pem).
+//Connections already open keep the certificate they started with; new ones get whatever was loaded
+//last, so a renewed certificate can be swapped in with a SIGHUP instead of a restart.
+
+pub async fn load(cert_path: &str, key_path: &str) -> io::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(cert_path, key_path).await
+}
+
+//Reads the files again. On error the certificate already loaded stays in use.
+pub async fn reload(tls: &RustlsConfig, cert_path: &str, key_path: &str) -> io::Result<()> {
+    tls.reload_from_pem_file(cert_path, key_path).await
+}
+
+//Reloads the certificate on every SIGHUP, for as long as the server runs.
+#[cfg(unix)]
+pub fn reload_on_sighup(tls: RustlsConfig, cert_path: String, key_path: String) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("SIGHUP handler should have been installed.");
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match reload(&tls, &cert_path, &key_path).await {
+                Ok(()) => info!("Reloaded the TLS certificate from {}.", cert_path),
+                Err(e) => error!("Couldn't reload the TLS certificate, keeping the old one: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_tls: RustlsConfig, _cert_path: String, _key_path: String) {}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use axum::{Router, routing::get};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    use crate::shutdown::serve_tls;
+    use crate::tls::{load, reload};
+
+    //A self-signed certificate for localhost, written to PEM files.
+    struct TempCert {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    }
+
+    impl TempCert {
+        //Also returns the certificate, DER encoded.
+        fn new(name: &str) -> (Self, Vec<u8>) {
+            let dir = std::env::temp_dir();
+            let cert = TempCert {
+                cert_path: dir.join(format!("menu_manager_{}_{}.crt", name, std::process::id())),
+                key_path: dir.join(format!("menu_manager_{}_{}.key", name, std::process::id())),
+            };
+            let der = cert.write();
+            (cert, der)
+        }
+
+        //Each call makes a new key pair, so the served certificate can be told apart.
+        fn write(&self) -> Vec<u8> {
+            let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            std::fs::write(&self.cert_path, generated.cert.pem()).unwrap();
+            std::fs::write(&self.key_path, generated.key_pair.serialize_pem()).unwrap();
+            generated.cert.der().to_vec()
+        }
+
+        fn paths(&self) -> (&str, &str) {
+            (self.cert_path.to_str().unwrap(), self.key_path.to_str().unwrap())
+        }
+    }
+
+    impl Drop for TempCert {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.cert_path);
+            let _ = std::fs::remove_file(&self.key_path);
+        }
+    }
+
+    //Fetches / over HTTPS, trusting only `cert`, and returns the certificate the server presented.
+    async fn presented(port: u16, cert: &PathBuf) -> Vec<u8> {
+        let root = reqwest::Certificate::from_pem(&std::fs::read(cert).unwrap()).unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(root)
+            .tls_info(true)
+            .build()
+            .unwrap();
+        let response = client.get(format!("https://localhost:{}/", port)).send().await.unwrap();
+        let info = response.extensions().get::<reqwest::tls::TlsInfo>().unwrap().clone();
+        assert_eq!(response.text().await.unwrap(), "secure");
+        info.peer_certificate().unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_serve_and_reload() {
+        let (cert, first_der) = TempCert::new("tls");
+        let (cert_path, key_path) = cert.paths();
+        let tls = load(cert_path, key_path).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = Router::new().route("/", get(|| async { "secure" }));
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_tls(listener, app, tls.clone(), async { let _ = rx.await; }, Duration::from_secs(5)));
+
+        assert_eq!(presented(port, &cert.cert_path).await, first_der);
+
+        //A renewed certificate is picked up by new connections.
+        let second_der = cert.write();
+        reload(&tls, cert_path, key_path).await.unwrap();
+        assert_eq!(presented(port, &cert.cert_path).await, second_der);
+
+        //A broken one is refused, and the last good one stays.
+        std::fs::write(&cert.key_path, "not a key").unwrap();
+        assert!(reload(&tls, cert_path, key_path).await.is_err());
+        assert_eq!(presented(port, &cert.cert_path).await, second_der);
+        assert!(load(cert_path, key_path).await.is_err());
+
+        tx.send(()).unwrap();
+        assert!(server.await.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,35 @@
+use axum::extract::Request;
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+
+//The API is mounted under /v1 (see router in main). The unversioned paths it used to live at still
+//work for clients that haven't moved yet, but every response on them says so, so a /v2 with
+//different models can be added next to /v1 without breaking anyone.
+pub const CURRENT: &str = "/v1";
+
+//Routes that only exist under /v1, and so have no unversioned alias.
+pub const ADDED_IN_V1: [&str; 2] = ["/search", "/graphql"];
+
+//RFC 9745: when the unversioned paths were deprecated, as a structured field date.
+//2026-10-18T00:00:00Z.
+pub const DEPRECATED_SINCE: &str = "@1792281600";
+
+pub const DEPRECATION_HEADER: &str = "deprecation";
+
+//Middleware for the unversioned aliases. Adds the Deprecation header, and a Link to the same
+//request under the current version.
+pub async fn deprecated(request: Request, next: Next) -> Response {
+    let successor = match request.uri().path_and_query() {
+        Some(x) => format!("<{}{}>; rel=\"successor-version\"", CURRENT, x),
+        None => format!("<{}>; rel=\"successor-version\"", CURRENT),
+    };
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(HeaderName::from_static(DEPRECATION_HEADER), HeaderValue::from_static(DEPRECATED_SINCE));
+    //Paths and query strings are already valid header text; the check is just to be safe.
+    if let Ok(x) = HeaderValue::from_str(&successor) {
+        headers.append(axum::http::header::LINK, x);
+    }
+    response
+}
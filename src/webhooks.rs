@@ -0,0 +1,946 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::http::StatusCode;
+use axum::Json;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::config;
+use crate::db::DbError;
+use crate::error::{AppError, ErrorBody};
+use crate::events::{CatalogEvent, EventBus};
+use crate::store::{blocking, SqliteStore};
+
+//Headers sent with every delivery. The signature is
+//  sha256=<hex HMAC-SHA256 of "<timestamp>.<body>", keyed with the webhook's secret>
+//so receivers can check the request came from us, and reject old timestamps to stop replays.
+pub const EVENT_HEADER: &str = "x-menu-manager-event";
+pub const TIMESTAMP_HEADER: &str = "x-menu-manager-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-menu-manager-signature";
+
+//How long one delivery attempt may take before it counts as failed.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_DELIVERY_LIMIT: usize = 50;
+const MAX_DELIVERY_LIMIT: usize = 500;
+//Delivery attempts kept per webhook; older ones are dropped as new ones are recorded. As many as
+//GET /webhooks/:id/deliveries can show, so nothing it could list is ever missing.
+const KEPT_DELIVERIES: usize = MAX_DELIVERY_LIMIT;
+
+//A registered webhook target. Empty filters match everything; otherwise an event has to be one of
+//`events` and be about one of `restaurants` (compared ignoring case).
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    //Only ever shown once, when the webhook is created.
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<String>,
+    pub restaurants: Vec<String>,
+    pub created: String,
+}
+
+impl Webhook {
+    pub fn wants(&self, event: &CatalogEvent) -> bool {
+        let event_matches = self.events.is_empty() || self.events.iter().any(|x| x == event.name());
+        let restaurant_matches = self.restaurants.is_empty() || event.restaurants().iter()
+            .any(|x| self.restaurants.iter().any(|y| y.eq_ignore_ascii_case(x)));
+        event_matches && restaurant_matches
+    }
+}
+
+//Body of POST /webhooks. A secret is generated if none is given.
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NewWebhook {
+    #[schema(example = "https://example.com/hooks/menus")]
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub restaurants: Vec<String>,
+    pub secret: Option<String>,
+}
+
+impl NewWebhook {
+    fn validate(&self) -> Result<(), AppError> {
+        match reqwest::Url::parse(&self.url) {
+            Ok(x) if x.scheme() == "http" || x.scheme() == "https" => {}
+            _ => return Err(AppError::Unprocessable(format!("\"{}\" isn't an http(s) URL", self.url))),
+        }
+        if let Some(x) = self.events.iter().find(|x| !CatalogEvent::NAMES.contains(&x.as_str())) {
+            return Err(AppError::Unprocessable(format!(
+                "Unknown event \"{}\", expected one of {}", x, CatalogEvent::NAMES.join(", ")
+            )));
+        }
+        if self.secret.as_ref().is_some_and(|x| x.is_empty()) {
+            return Err(AppError::Unprocessable("The secret can't be empty".to_string()));
+        }
+        Ok(())
+    }
+}
+
+//Which addresses webhooks may be delivered to. Unless private targets are allowed, only public
+//ones: a webhook can't be used to reach the server's own loopback, the local network, or a cloud
+//metadata endpoint. Checked when a webhook is registered, and again on every connection (see
+//PublicOnly), since a name can resolve somewhere else later.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TargetPolicy {
+    pub allow_private: bool,
+}
+
+impl TargetPolicy {
+    pub fn new(config: &config::Webhooks) -> Self {
+        TargetPolicy { allow_private: config.allow_private_targets }
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        self.allow_private || is_public(ip)
+    }
+
+    //Only looks at IP literals; names are left to the resolver.
+    fn allows_url(&self, url: &reqwest::Url) -> bool {
+        host(url).parse().map_or(true, |x| self.allows(x))
+    }
+
+    //Resolves the URL's host and refuses it if any of its addresses isn't allowed.
+    async fn check(&self, url: &str) -> Result<(), AppError> {
+        if self.allow_private {
+            return Ok(());
+        }
+        let url = reqwest::Url::parse(url).map_err(|e| AppError::Unprocessable(e.to_string()))?;
+        let host = host(&url);
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await
+            .map_err(|e| AppError::Unprocessable(format!("Couldn't resolve \"{}\": {}", host, e)))?
+            .collect();
+        match addrs.iter().find(|x| !self.allows(x.ip())) {
+            Some(x) => Err(AppError::Unprocessable(format!(
+                "\"{}\" is a private or local address ({}); webhooks.allow_private_targets allows those",
+                host, x.ip()
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+//The URL's host, without the brackets around IPv6 addresses.
+fn host(url: &reqwest::Url) -> &str {
+    url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']')
+}
+
+//Whether an address is reachable from the internet at large, as opposed to loopback, private,
+//link-local, carrier-grade NAT, multicast and the like.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(x) => {
+            let [a, b, ..] = x.octets();
+            !(x.is_private() || x.is_loopback() || x.is_link_local() || x.is_unspecified()
+                || x.is_broadcast() || x.is_multicast() || a == 0 || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(x) => match x.to_ipv4_mapped() {
+            Some(x) => is_public(x.into()),
+            None => {
+                let first = x.segments()[0];
+                //fc00::/7 is unique local, fe80::/10 link-local.
+                !(x.is_loopback() || x.is_unspecified() || x.is_multicast()
+                    || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+//Resolver for the delivery client that drops addresses the policy doesn't allow, so a name that
+//passed the check at registration can't be pointed somewhere private afterwards.
+struct PublicOnly(TargetPolicy);
+
+impl reqwest::dns::Resolve for PublicOnly {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let policy = self.0;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?
+                .filter(|x| policy.allows(x.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public addresses", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+//One attempt at delivering one event to one webhook.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Delivery {
+    pub webhook_id: i64,
+    pub event: String,
+    pub attempt: u32,
+    //None if there was no response at all; see `error`.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub succeeded: bool,
+    pub at: String,
+}
+
+//Where webhooks and their delivery log are kept. SQLite keeps them next to the menu items,
+//MemoryWebhooks goes with the in-memory store.
+pub trait WebhookStore: Send + Sync {
+    fn add(&self, new: &NewWebhook, secret: &str, created: &str) -> Result<Webhook, DbError>;
+    fn list(&self) -> Result<Vec<Webhook>, DbError>;
+    fn get(&self, id: i64) -> Result<Option<Webhook>, DbError>;
+    //Also drops its delivery log. Returns whether there was one.
+    fn remove(&self, id: i64) -> Result<bool, DbError>;
+    //Also drops the webhook's oldest deliveries past KEPT_DELIVERIES.
+    fn record(&self, delivery: &Delivery) -> Result<(), DbError>;
+    //Newest first.
+    fn deliveries(&self, id: i64, limit: usize) -> Result<Vec<Delivery>, DbError>;
+}
+
+pub type SharedWebhooks = Arc<dyn WebhookStore>;
+
+fn webhook_from_row(row: &Row) -> Result<Webhook, DbError> {
+    Ok(Webhook {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        secret: row.get(2)?,
+        events: serde_json::from_str(&row.get::<_, String>(3)?)?,
+        restaurants: serde_json::from_str(&row.get::<_, String>(4)?)?,
+        created: row.get(5)?,
+    })
+}
+
+impl WebhookStore for SqliteStore {
+    fn add(&self, new: &NewWebhook, secret: &str, created: &str) -> Result<Webhook, DbError> {
+        let connection = self.db().get()?;
+        connection.execute(
+            "INSERT INTO webhooks (url, secret, events, restaurants, created) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![new.url, secret, serde_json::to_string(&new.events)?, serde_json::to_string(&new.restaurants)?, created],
+        )?;
+        Ok(Webhook {
+            id: connection.last_insert_rowid(),
+            url: new.url.clone(),
+            secret: secret.to_string(),
+            events: new.events.clone(),
+            restaurants: new.restaurants.clone(),
+            created: created.to_string(),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<Webhook>, DbError> {
+        let connection = self.db().get()?;
+        let mut statement = connection.prepare(
+            "SELECT id, url, secret, events, restaurants, created FROM webhooks ORDER BY id",
+        )?;
+        let mut rows = statement.query([])?;
+        let mut webhooks = Vec::new();
+        while let Some(row) = rows.next()? {
+            webhooks.push(webhook_from_row(row)?);
+        }
+        Ok(webhooks)
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Webhook>, DbError> {
+        let connection = self.db().get()?;
+        let mut statement = connection.prepare(
+            "SELECT id, url, secret, events, restaurants, created FROM webhooks WHERE id = ?1",
+        )?;
+        let row = statement.query_row([id], |row| Ok(webhook_from_row(row))).optional()?;
+        row.transpose()
+    }
+
+    fn remove(&self, id: i64) -> Result<bool, DbError> {
+        let mut connection = self.db().get()?;
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM webhook_deliveries WHERE webhook_id = ?1", [id])?;
+        let removed = transaction.execute("DELETE FROM webhooks WHERE id = ?1", [id])?;
+        transaction.commit()?;
+        Ok(removed > 0)
+    }
+
+    fn record(&self, delivery: &Delivery) -> Result<(), DbError> {
+        let mut connection = self.db().get()?;
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO webhook_deliveries (webhook_id, event, attempt, status, error, succeeded, at) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                delivery.webhook_id,
+                delivery.event,
+                delivery.attempt,
+                delivery.status,
+                delivery.error,
+                delivery.succeeded,
+                delivery.at,
+            ],
+        )?;
+        //Everything up to the newest delivery that's past the cap, if there is one.
+        transaction.execute(
+            "DELETE FROM webhook_deliveries WHERE webhook_id = ?1 AND id <= (\
+                SELECT id FROM webhook_deliveries WHERE webhook_id = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2\
+            )",
+            params![delivery.webhook_id, KEPT_DELIVERIES as i64],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn deliveries(&self, id: i64, limit: usize) -> Result<Vec<Delivery>, DbError> {
+        let connection = self.db().get()?;
+        let mut statement = connection.prepare(
+            "SELECT webhook_id, event, attempt, status, error, succeeded, at FROM webhook_deliveries \
+            WHERE webhook_id = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let rows = statement.query_map(params![id, limit as i64], |row| {
+            Ok(Delivery {
+                webhook_id: row.get(0)?,
+                event: row.get(1)?,
+                attempt: row.get(2)?,
+                status: row.get(3)?,
+                error: row.get(4)?,
+                succeeded: row.get(5)?,
+                at: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+//Webhooks for the in-memory store. Gone on restart, like everything else in it.
+#[derive(Default)]
+pub struct MemoryWebhooks {
+    inner: Mutex<MemoryWebhooksInner>,
+}
+
+#[derive(Default)]
+struct MemoryWebhooksInner {
+    last_id: i64,
+    webhooks: BTreeMap<i64, Webhook>,
+    deliveries: Vec<Delivery>,
+}
+
+impl MemoryWebhooks {
+    pub fn new() -> Self {
+        MemoryWebhooks::default()
+    }
+
+    //See MemoryStore::lock.
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryWebhooksInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl WebhookStore for MemoryWebhooks {
+    fn add(&self, new: &NewWebhook, secret: &str, created: &str) -> Result<Webhook, DbError> {
+        let mut inner = self.lock();
+        inner.last_id += 1;
+        let webhook = Webhook {
+            id: inner.last_id,
+            url: new.url.clone(),
+            secret: secret.to_string(),
+            events: new.events.clone(),
+            restaurants: new.restaurants.clone(),
+            created: created.to_string(),
+        };
+        inner.webhooks.insert(webhook.id, webhook.clone());
+        Ok(webhook)
+    }
+
+    fn list(&self) -> Result<Vec<Webhook>, DbError> {
+        Ok(self.lock().webhooks.values().cloned().collect())
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Webhook>, DbError> {
+        Ok(self.lock().webhooks.get(&id).cloned())
+    }
+
+    fn remove(&self, id: i64) -> Result<bool, DbError> {
+        let mut inner = self.lock();
+        inner.deliveries.retain(|x| x.webhook_id != id);
+        Ok(inner.webhooks.remove(&id).is_some())
+    }
+
+    fn record(&self, delivery: &Delivery) -> Result<(), DbError> {
+        let mut inner = self.lock();
+        inner.deliveries.push(delivery.clone());
+        let kept = inner.deliveries.iter().filter(|x| x.webhook_id == delivery.webhook_id).count();
+        if kept > KEPT_DELIVERIES {
+            let oldest = inner.deliveries.iter().position(|x| x.webhook_id == delivery.webhook_id);
+            inner.deliveries.remove(oldest.unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    fn deliveries(&self, id: i64, limit: usize) -> Result<Vec<Delivery>, DbError> {
+        Ok(self.lock().deliveries.iter().rev().filter(|x| x.webhook_id == id).take(limit).cloned().collect())
+    }
+}
+
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length.");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn generate_secret() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+//How often, and how far apart, a failed delivery is tried again. The wait doubles after each
+//attempt, up to `max_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    //Wait after the given (1 based) attempt fails.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay.saturating_mul(2u32.saturating_pow(attempt - 1)).min(self.max_delay)
+    }
+}
+
+//Sends catalog events from the event bus to every webhook that wants them.
+//Each delivery runs in its own task, so a slow or failing target never holds up the others, but
+//only `max_concurrent_deliveries` at once; past that the dispatcher waits for one to finish.
+#[derive(Clone)]
+pub struct Dispatcher {
+    webhooks: SharedWebhooks,
+    client: reqwest::Client,
+    retry: RetryPolicy,
+    targets: TargetPolicy,
+    slots: Arc<Semaphore>,
+}
+
+impl Dispatcher {
+    pub fn new(webhooks: SharedWebhooks, retry: RetryPolicy, config: &config::Webhooks) -> Self {
+        let targets = TargetPolicy::new(config);
+        //Redirects aren't followed: they could lead anywhere, and receivers have no reason to send them.
+        let mut client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none());
+        if !targets.allow_private {
+            client = client.dns_resolver(Arc::new(PublicOnly(targets)));
+        }
+        let client = client.build().expect("HTTP client should have been built.");
+        let slots = match config.max_concurrent_deliveries {
+            0 => Semaphore::MAX_PERMITS,
+            x => x,
+        };
+        Dispatcher { webhooks, client, retry, targets, slots: Arc::new(Semaphore::new(slots)) }
+    }
+
+    //Subscribes straight away (so nothing published after this returns is missed), then dispatches
+    //until the bus is closed.
+    pub fn spawn(self, events: &EventBus) -> JoinHandle<()> {
+        let mut receiver = events.subscribe();
+        let closed = events.closed();
+        tokio::spawn(async move {
+            tokio::pin!(closed);
+            loop {
+                let event = tokio::select! {
+                    _ = &mut closed => break,
+                    x = receiver.recv() => x,
+                };
+                match event {
+                    Ok(event) => self.dispatch(event).await,
+                    Err(RecvError::Lagged(missed)) => warn!("Webhooks fell behind and missed {} events.", missed),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    async fn dispatch(&self, event: CatalogEvent) {
+        let webhooks = match blocking(&self.webhooks, |x| x.list()).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Couldn't read webhooks, dropping a {} event: {}", event.name(), e);
+                return;
+            }
+        };
+        for webhook in webhooks.into_iter().filter(|x| x.wants(&event)) {
+            //Held until the delivery is done, retries included. The semaphore is never closed.
+            let Ok(slot) = Arc::clone(&self.slots).acquire_owned().await else { return };
+            let delivery = self.clone().deliver(webhook, event.clone());
+            tokio::spawn(async move {
+                delivery.await;
+                drop(slot);
+            });
+        }
+    }
+
+    //Posts the event to the webhook, retrying server errors, timeouts and connection failures.
+    //Every attempt goes in the delivery log. Returns whether it was delivered.
+    pub async fn deliver(self, webhook: Webhook, event: CatalogEvent) -> bool {
+        let body = match serde_json::to_vec(&event) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Couldn't serialize a {} event: {}", event.name(), e);
+                return false;
+            }
+        };
+        //The resolver covers names; an address written into the URL never gets that far.
+        if !reqwest::Url::parse(&webhook.url).is_ok_and(|x| self.targets.allows_url(&x)) {
+            let delivery = Delivery {
+                webhook_id: webhook.id,
+                event: event.name().to_string(),
+                attempt: 1,
+                status: None,
+                error: Some("The URL points at a private or local address".to_string()),
+                succeeded: false,
+                at: chrono::Utc::now().to_rfc3339(),
+            };
+            if let Err(e) = blocking(&self.webhooks, move |x| x.record(&delivery)).await {
+                warn!("Couldn't record a webhook delivery: {}", e);
+            }
+            warn!("Not delivering {} to webhook {} ({}), it's a private address.", event.name(), webhook.id, webhook.url);
+            return false;
+        }
+
+        for attempt in 1..=self.retry.attempts {
+            let timestamp = chrono::Utc::now().timestamp();
+            let res = self.client.post(&webhook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, event.name())
+                .header(TIMESTAMP_HEADER, timestamp.to_string())
+                .header(SIGNATURE_HEADER, sign(&webhook.secret, timestamp, &body))
+                .body(body.clone())
+                .send()
+                .await;
+
+            let (status, error) = match res {
+                Ok(x) => (Some(x.status().as_u16()), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            let succeeded = status.is_some_and(|x| (200..300).contains(&x));
+            //Anything else from the target (say a 404) won't get better by asking again.
+            let retryable = !succeeded && status.is_none_or(|x| x >= 500 || x == 408 || x == 429);
+
+            let delivery = Delivery {
+                webhook_id: webhook.id,
+                event: event.name().to_string(),
+                attempt,
+                status,
+                error,
+                succeeded,
+                at: chrono::Utc::now().to_rfc3339(),
+            };
+            if let Err(e) = blocking(&self.webhooks, move |x| x.record(&delivery)).await {
+                warn!("Couldn't record a webhook delivery: {}", e);
+            }
+
+            if succeeded {
+                debug!("Delivered {} to webhook {} on attempt {}.", event.name(), webhook.id, attempt);
+                return true;
+            }
+            if !retryable || attempt == self.retry.attempts {
+                break;
+            }
+            tokio::time::sleep(self.retry.delay(attempt)).await;
+        }
+        warn!("Gave up delivering {} to webhook {} ({}).", event.name(), webhook.id, webhook.url);
+        false
+    }
+}
+
+//Registered webhook with its secret, only returned by POST /webhooks.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    secret: String,
+}
+
+//Handler for POST /webhooks.
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    summary = "Register a webhook",
+    description = "The secret deliveries are signed with is only ever returned here.",
+    request_body = NewWebhook,
+    responses(
+        (status = 201, description = "Registered", body = CreatedWebhook),
+        (status = 400, description = "Malformed body", body = ErrorBody),
+        (status = 422, description = "Bad or private URL, unknown event or empty secret", body = ErrorBody),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn create_webhook(
+    State(webhooks): State<SharedWebhooks>,
+    State(targets): State<TargetPolicy>,
+    body: Result<Json<NewWebhook>, JsonRejection>,
+) -> Result<(StatusCode, Json<CreatedWebhook>), AppError> {
+    let Json(new) = body?;
+    new.validate()?;
+    targets.check(&new.url).await?;
+    let secret = new.secret.clone().unwrap_or_else(generate_secret);
+    let created = chrono::Utc::now().to_rfc3339();
+    let webhook = blocking(&webhooks, move |x| x.add(&new, &secret, &created)).await?;
+    let secret = webhook.secret.clone();
+    Ok((StatusCode::CREATED, Json(CreatedWebhook { webhook, secret })))
+}
+
+//Handler for GET /webhooks. Secrets are left out.
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    summary = "List webhooks",
+    responses(
+        (status = 200, description = "Every registered webhook, without secrets", body = Vec<Webhook>),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn list_webhooks(State(webhooks): State<SharedWebhooks>) -> Result<Json<Vec<Webhook>>, AppError> {
+    Ok(Json(blocking(&webhooks, |x| x.list()).await?))
+}
+
+//Handler for DELETE /webhooks/:id.
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    summary = "Remove a webhook and its delivery log",
+    params(("id" = i64, Path)),
+    responses(
+        (status = 204, description = "Removed"),
+        (status = 400, description = "Bad id", body = ErrorBody),
+        (status = 404, description = "No such webhook", body = ErrorBody),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn delete_webhook(
+    id: Result<Path<i64>, PathRejection>,
+    State(webhooks): State<SharedWebhooks>,
+) -> Result<StatusCode, AppError> {
+    let Path(id) = id?;
+    if blocking(&webhooks, move |x| x.remove(id)).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("No webhook {}", id)))
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveryOptions {
+    limit: Option<usize>,
+}
+
+//Handler for GET /webhooks/:id/deliveries, the most recent delivery attempts first.
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    summary = "Recent delivery attempts, newest first",
+    params(("id" = i64, Path), DeliveryOptions),
+    responses(
+        (status = 200, description = "Delivery attempts", body = Vec<Delivery>),
+        (status = 400, description = "Bad id or query string", body = ErrorBody),
+        (status = 404, description = "No such webhook", body = ErrorBody),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+pub async fn webhook_deliveries(
+    id: Result<Path<i64>, PathRejection>,
+    options: Result<Query<DeliveryOptions>, QueryRejection>,
+    State(webhooks): State<SharedWebhooks>,
+) -> Result<Json<Vec<Delivery>>, AppError> {
+    let Path(id) = id?;
+    let Query(options) = options?;
+    let limit = options.limit.unwrap_or(DEFAULT_DELIVERY_LIMIT).min(MAX_DELIVERY_LIMIT);
+    let deliveries = blocking(&webhooks, move |x| {
+        match x.get(id)? {
+            Some(_) => Ok(Some(x.deliveries(id, limit)?)),
+            None => Ok(None),
+        }
+    }).await?;
+    deliveries.map(Json).ok_or_else(|| AppError::NotFound(format!("No webhook {}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use axum::{Router, routing::post};
+    use axum::http::{HeaderMap, StatusCode};
+
+    use crate::config;
+    use crate::events::{CatalogEvent, EventBus};
+    use crate::menu::Item;
+    use crate::store::{SqliteStore, TempDb};
+    use crate::webhooks::{
+        is_public, sign, Delivery, Dispatcher, MemoryWebhooks, NewWebhook, RetryPolicy, Webhook, WebhookStore,
+        KEPT_DELIVERIES, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+
+    //The stand-in receivers listen on loopback.
+    fn local() -> config::Webhooks {
+        config::Webhooks { allow_private_targets: true, ..config::Webhooks::default() }
+    }
+
+    fn restaurant_changed(restaurant: &str) -> CatalogEvent {
+        CatalogEvent::RestaurantChanged { restaurant: restaurant.to_string() }
+    }
+
+    fn new_webhook(url: &str) -> NewWebhook {
+        NewWebhook { url: url.to_string(), ..NewWebhook::default() }
+    }
+
+    //Runs the same checks against any webhook store.
+    fn exercise(store: &dyn WebhookStore) {
+        let new = NewWebhook {
+            events: vec!["item_added".to_string()],
+            restaurants: vec!["Lark".to_string()],
+            ..new_webhook("http://localhost/hook")
+        };
+        let first = store.add(&new, "secret", "2024-06-04T00:00:00Z").unwrap();
+        let second = store.add(&new_webhook("http://localhost/other"), "other", "2024-06-04T00:00:00Z").unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(store.get(first.id).unwrap(), Some(first.clone()));
+        assert_eq!(store.list().unwrap(), vec![first.clone(), second.clone()]);
+
+        let delivery = |webhook_id: i64, attempt: u32| Delivery {
+            webhook_id,
+            event: "item_added".to_string(),
+            attempt,
+            status: Some(500),
+            error: None,
+            succeeded: false,
+            at: "2024-06-04T00:00:00Z".to_string(),
+        };
+        for attempt in 1..=3 {
+            store.record(&delivery(first.id, attempt)).unwrap();
+        }
+        let deliveries = store.deliveries(first.id, 2).unwrap();
+        assert_eq!(deliveries.iter().map(|x| x.attempt).collect::<Vec<_>>(), vec![3, 2]);
+        assert!(store.deliveries(second.id, 10).unwrap().is_empty());
+
+        //Only the newest are kept, and only the webhook's own are dropped for it.
+        let past_cap = KEPT_DELIVERIES as u32 + 2;
+        for attempt in 1..=past_cap {
+            store.record(&delivery(second.id, attempt)).unwrap();
+        }
+        let deliveries = store.deliveries(second.id, usize::MAX).unwrap();
+        assert_eq!(deliveries.len(), KEPT_DELIVERIES);
+        assert_eq!((deliveries[0].attempt, deliveries[KEPT_DELIVERIES - 1].attempt), (past_cap, 3));
+        assert_eq!(store.deliveries(first.id, 10).unwrap().len(), 3);
+
+        assert!(store.remove(first.id).unwrap());
+        assert!(!store.remove(first.id).unwrap());
+        assert!(store.deliveries(first.id, 10).unwrap().is_empty());
+        assert_eq!(store.list().unwrap(), vec![second]);
+    }
+
+    #[test]
+    fn test_sqlite_webhooks() {
+        let temp = TempDb::new("webhooks");
+        exercise(&SqliteStore::open(&temp.path).unwrap());
+    }
+
+    #[test]
+    fn test_memory_webhooks() {
+        exercise(&MemoryWebhooks::new());
+    }
+
+    #[test]
+    fn test_filters() {
+        let webhook = |events: &[&str], restaurants: &[&str]| Webhook {
+            id: 1,
+            url: String::new(),
+            secret: String::new(),
+            events: events.iter().map(|x| x.to_string()).collect(),
+            restaurants: restaurants.iter().map(|x| x.to_string()).collect(),
+            created: String::new(),
+        };
+        let removed = CatalogEvent::ItemRemoved { id: "1".to_string(), restaurant: None };
+
+        assert!(webhook(&[], &[]).wants(&restaurant_changed("Lark")));
+        assert!(webhook(&["restaurant_changed"], &["lark"]).wants(&restaurant_changed("Lark")));
+        assert!(!webhook(&["item_added"], &[]).wants(&restaurant_changed("Lark")));
+        assert!(!webhook(&[], &["Canlis"]).wants(&restaurant_changed("Lark")));
+        //Nothing to match a restaurant filter against.
+        assert!(webhook(&[], &[]).wants(&removed));
+        assert!(!webhook(&[], &["Lark"]).wants(&removed));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = RetryPolicy {
+            attempts: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+        let delays: Vec<_> = (1..=4).map(|x| retry.delay(x).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5]);
+    }
+
+    //Stands in for a receiver: fails the first request with a 500, accepts the rest, and keeps
+    //every request it got.
+    async fn stand_in() -> (String, Arc<Mutex<Vec<(HeaderMap, String)>>>) {
+        let received: Arc<Mutex<Vec<(HeaderMap, String)>>> = Arc::default();
+        let log = Arc::clone(&received);
+        let app = Router::new().route("/hook", post(move |headers: HeaderMap, body: String| async move {
+            let mut log = log.lock().unwrap();
+            log.push((headers, body));
+            if log.len() == 1 { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn test_delivery_with_retry() {
+        let (url, received) = stand_in().await;
+        let webhooks = Arc::new(MemoryWebhooks::new());
+        let webhook = webhooks.add(&new_webhook(&url), "shh", "2024-06-04T00:00:00Z").unwrap();
+        let retry = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+        };
+
+        let bus = EventBus::new();
+        let dispatcher = Dispatcher::new(webhooks.clone(), retry, &local()).spawn(&bus);
+        bus.publish(CatalogEvent::ItemAdded { id: "7".to_string(), item: Item::default() });
+
+        let mut deliveries = Vec::new();
+        for _ in 0..500 {
+            deliveries = webhooks.deliveries(webhook.id, 10).unwrap();
+            if deliveries.iter().any(|x| x.succeeded) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let attempts: Vec<_> = deliveries.iter().map(|x| (x.attempt, x.status, x.succeeded)).collect();
+        assert_eq!(attempts, vec![(2, Some(200), true), (1, Some(500), false)]);
+
+        let (headers, body) = received.lock().unwrap()[1].clone();
+        assert!(body.contains("\"type\":\"item_added\""));
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert_eq!(headers[SIGNATURE_HEADER], sign("shh", timestamp, body.as_bytes()));
+        assert_ne!(headers[SIGNATURE_HEADER], sign("wrong", timestamp, body.as_bytes()));
+
+        bus.close();
+        tokio::time::timeout(Duration::from_secs(5), dispatcher).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_on_client_errors() {
+        let webhooks = Arc::new(MemoryWebhooks::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/missing", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, Router::new()).await.unwrap() });
+        let webhook = webhooks.add(&new_webhook(&url), "shh", "2024-06-04T00:00:00Z").unwrap();
+
+        let dispatcher = Dispatcher::new(webhooks.clone(), RetryPolicy::default(), &local());
+        assert!(!dispatcher.deliver(webhook.clone(), restaurant_changed("Lark")).await);
+        let deliveries = webhooks.deliveries(webhook.id, 10).unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].status, Some(404));
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in ["93.184.215.14", "8.8.8.8", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "255.255.255.255", "224.0.0.1", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refuses_private_targets() {
+        let (url, received) = stand_in().await;
+        let by_name = url.replace("127.0.0.1", "localhost");
+        let webhooks = Arc::new(MemoryWebhooks::new());
+        let dispatcher = Dispatcher::new(webhooks.clone(), RetryPolicy { attempts: 1, ..RetryPolicy::default() }, &config::Webhooks::default());
+
+        //Written as an address it's never tried; as a name the resolver has nothing left to connect to.
+        for url in [url, by_name] {
+            let webhook = webhooks.add(&new_webhook(&url), "shh", "2024-06-04T00:00:00Z").unwrap();
+            assert!(!dispatcher.clone().deliver(webhook.clone(), restaurant_changed("Lark")).await);
+            let deliveries = webhooks.deliveries(webhook.id, 10).unwrap();
+            assert_eq!(deliveries.len(), 1);
+            assert_eq!(deliveries[0].status, None);
+        }
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bounded_deliveries() {
+        //Counts how many requests it's holding at once.
+        let counts: Arc<Mutex<(usize, usize)>> = Arc::default();
+        let log = Arc::clone(&counts);
+        let app = Router::new().route("/hook", post(move || async move {
+            {
+                let mut counts = log.lock().unwrap();
+                counts.0 += 1;
+                counts.1 = counts.1.max(counts.0);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            log.lock().unwrap().0 -= 1;
+            StatusCode::OK
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let webhooks = Arc::new(MemoryWebhooks::new());
+        let ids: Vec<_> = (0..4)
+            .map(|_| webhooks.add(&new_webhook(&url), "shh", "2024-06-04T00:00:00Z").unwrap().id)
+            .collect();
+        let config = config::Webhooks { max_concurrent_deliveries: 2, ..local() };
+        let bus = EventBus::new();
+        let dispatcher = Dispatcher::new(webhooks.clone(), RetryPolicy::default(), &config).spawn(&bus);
+        bus.publish(restaurant_changed("Lark"));
+        bus.publish(restaurant_changed("Canlis"));
+
+        for _ in 0..500 {
+            if ids.iter().all(|x| webhooks.deliveries(*x, 10).unwrap().len() == 2) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(ids.iter().all(|x| webhooks.deliveries(*x, 10).unwrap().iter().all(|x| x.succeeded)));
+        assert_eq!(counts.lock().unwrap().1, 2);
+
+        bus.close();
+        tokio::time::timeout(Duration::from_secs(5), dispatcher).await.unwrap().unwrap();
+    }
+}